@@ -1,11 +1,15 @@
+mod openapi;
 mod proxy;
 pub mod responses;
+pub mod self_test;
 
+use crate::config::SamplingDefaults;
 use crate::model::Model;
 use crate::state::SharedState;
 use axum::{
+    body::Bytes,
     extract::{Path, Query, Request, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
@@ -17,13 +21,103 @@ use serde::{Deserialize, Serialize};
 pub enum Tier {
     Free,
     Stealth,
+    /// Meta-routers like `openrouter/auto`, which pick a model per request.
+    Router,
+    /// The `CUSTOM_TIERS` entry at this index.
+    Custom(usize),
 }
 
+/// Meta-routers get the request exactly as the client sent it.
+const NO_DEFAULTS: SamplingDefaults = SamplingDefaults {
+    temperature: None,
+    top_p: None,
+};
+
 impl Tier {
+    pub(crate) fn name(self, config: &crate::config::Config) -> &str {
+        match self {
+            Self::Free => "free",
+            Self::Stealth => "stealth",
+            Self::Router => "routers",
+            Self::Custom(i) => &config.custom_tiers[i].name,
+        }
+    }
+
+    /// The tier's own OpenRouter key if configured, else the shared one.
+    pub(crate) fn api_key(self, config: &crate::config::Config) -> Option<&str> {
+        let own = match self {
+            Self::Free => &config.free_api_key,
+            Self::Stealth => &config.stealth_api_key,
+            Self::Router | Self::Custom(_) => &None,
+        };
+        own.as_deref().or(config.health_check_key.as_deref())
+    }
+
+    /// The key to health-check this tier with, unless health checks are off.
+    /// Meta-routers are never pinged: each ping would run on a routed model.
+    pub(crate) fn health_check_key(self, config: &crate::config::Config) -> Option<&str> {
+        match self {
+            Self::Router => return None,
+            Self::Custom(i) if !config.custom_tiers[i].health_check => return None,
+            _ => {}
+        }
+        self.api_key(config).filter(|_| config.health_check_enabled)
+    }
+
+    /// Parallel pings for this tier: its own override, else the global value.
+    pub(crate) fn health_check_concurrency(self, config: &crate::config::Config) -> usize {
+        let own = match self {
+            Self::Free => config.free_health_check_concurrency,
+            Self::Stealth => config.stealth_health_check_concurrency,
+            Self::Router | Self::Custom(_) => None,
+        };
+        own.unwrap_or(config.health_check_concurrency)
+    }
+
+    /// The model for requests to this tier that don't name one.
+    pub(crate) fn default_model(self, config: &crate::config::Config) -> Option<&str> {
+        match self {
+            Self::Free => config.free_default_model.as_deref(),
+            Self::Stealth => config.stealth_default_model.as_deref(),
+            Self::Router => None,
+            Self::Custom(i) => config.custom_tiers[i].default_model.as_deref(),
+        }
+    }
+
     pub(crate) fn models(self, cache: &crate::state::ModelCache) -> std::sync::Arc<Vec<Model>> {
         match self {
             Self::Free => cache.free_models.clone(),
             Self::Stealth => cache.stealth_models.clone(),
+            Self::Router => cache.router_models.clone(),
+            Self::Custom(i) => cache.custom_models.get(i).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Image-generation models belonging to this tier.
+    pub(crate) fn image_models(self, cache: &crate::state::ModelCache) -> Vec<Model> {
+        let stealth = match self {
+            Self::Free => false,
+            Self::Stealth => true,
+            Self::Router => return Vec::new(),
+            // Custom tiers hold image generators alongside their chat models.
+            Self::Custom(_) => {
+                let models = self.models(cache);
+                return models.iter().filter(|m| m.generates_images()).cloned().collect();
+            }
+        };
+        cache
+            .image_models
+            .iter()
+            .filter(|m| m.is_stealth() == stealth)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn sampling_defaults(self, config: &crate::config::Config) -> &SamplingDefaults {
+        match self {
+            Self::Free => &config.free_defaults,
+            Self::Stealth => &config.stealth_defaults,
+            Self::Router | Self::Custom(_) => &NO_DEFAULTS,
         }
     }
 }
@@ -32,85 +126,275 @@ impl Tier {
 pub(crate) struct ModelFilter {
     #[serde(default)]
     supports: Option<String>,
+    #[serde(default)]
+    sort: Option<String>,
+    #[serde(default)]
+    pub(crate) available_only: bool,
+    /// Unix timestamp; models with an unknown (`0`) `created` never match.
+    #[serde(default)]
+    created_after: Option<i64>,
+    /// `json` (the default) or `csv`.
+    #[serde(default)]
+    pub(crate) format: Option<String>,
 }
 
 impl ModelFilter {
     pub(crate) fn matches(&self, model: &Model) -> bool {
+        if let Some(after) = self.created_after {
+            if model.created == 0 || model.created <= after {
+                return false;
+            }
+        }
         let Some(ref caps) = self.supports else {
             return true;
         };
-        caps.split(',').all(|c| match c.trim() {
-            "tools" => model.has_param("tools"),
-            "tool_choice" => model.has_param("tool_choice"),
-            "json_mode" => model.has_param("response_format"),
-            "streaming" => model.has_param("stream"),
-            "vision" => model.supports_vision(),
-            _ => true,
-        })
-    }
-}
-
-macro_rules! tier_handlers {
-    ($tier:expr, $list:ident, $get:ident, $fwd:ident, $resp:ident) => {
-        async fn $list(
-            State(s): State<SharedState>,
-            Query(f): Query<ModelFilter>,
-        ) -> impl IntoResponse {
-            Proxy::list_models($tier, &s, &f).await
-        }
+        let have = model.capabilities();
+        caps.split(',').all(|c| have.get(c.trim()).unwrap_or(true))
+    }
 
-        async fn $get(State(s): State<SharedState>, Path(id): Path<String>) -> Response {
-            Proxy::get_model($tier, &s, &id).await
+    pub(crate) fn sort(&self, models: &mut [Model]) {
+        use std::cmp::Reverse;
+        // Unknown `created` (0) sorts last in both directions.
+        match self.sort.as_deref() {
+            // Most recently verified first; never-checked models last.
+            Some("health") => models.sort_by_key(|m| Reverse(m.last_success)),
+            Some("created_desc") => models.sort_by_key(|m| (m.created == 0, Reverse(m.created))),
+            Some("created_asc") => models.sort_by_key(|m| (m.created == 0, m.created)),
+            _ => {}
         }
+    }
+}
 
-        async fn $fwd(State(s): State<SharedState>, req: Request) -> Response {
-            Proxy::forward($tier, &s, req).await
-        }
+/// Query for `/models/watch`, alongside the usual `ModelFilter` fields.
+#[derive(Deserialize)]
+pub(crate) struct WatchQuery {
+    /// The `x-models-version` the client last saw; a newer one answers at once.
+    #[serde(default)]
+    pub(crate) version: Option<u64>,
+    /// How long to wait for a change, capped at `MAX_WATCH_SECS`.
+    #[serde(default)]
+    pub(crate) timeout_secs: Option<u64>,
+}
 
-        async fn $resp(State(s): State<SharedState>, req: Request) -> Response {
-            Proxy::handle_responses($tier, &s, req).await
-        }
-    };
+async fn list_routers(
+    State(s): State<SharedState>,
+    Query(f): Query<ModelFilter>,
+) -> impl IntoResponse {
+    Proxy::list_models(Tier::Router, &s, &f).await
+}
+
+async fn get_router(State(s): State<SharedState>, Path(id): Path<String>) -> Response {
+    Proxy::get_model(Tier::Router, &s, &id).await
+}
+
+async fn fwd_router(State(s): State<SharedState>, req: Request) -> Response {
+    if !s.config.allow_meta_routers {
+        return Proxy::error(
+            StatusCode::FORBIDDEN,
+            "Forwarding to meta-routers is disabled (set ALLOW_META_ROUTERS)".into(),
+            Some("meta_routers_disabled"),
+        );
+    }
+    let tier = Tier::Router;
+    Proxy::traced(tier, &s, Proxy::forward(tier, &s, req)).await
+}
+
+async fn get_response(
+    State(s): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    Proxy::get_response(&s, &headers, &id)
 }
 
-tier_handlers!(Tier::Free, list_free, get_free, fwd_free, resp_free);
-tier_handlers!(Tier::Stealth, list_stealth, get_stealth, fwd_stealth, resp_stealth);
+async fn delete_response(
+    State(s): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    Proxy::delete_response(&s, &headers, &id)
+}
 
+/// Routes for one tier. Chat tiers (built-in and `CUSTOM_TIERS`) share the
+/// same handlers, parameterized by `tier`.
 pub fn tier_router(tier: Tier) -> Router<SharedState> {
-    match tier {
-        Tier::Free => Router::new()
-            .route("/models", get(list_free))
-            .route("/models/*id", get(get_free))
-            .route("/chat/completions", post(fwd_free))
-            .route("/responses", post(resp_free)),
-        Tier::Stealth => Router::new()
-            .route("/models", get(list_stealth))
-            .route("/models/*id", get(get_stealth))
-            .route("/chat/completions", post(fwd_stealth))
-            .route("/responses", post(resp_stealth)),
+    // Meta-routers can always be listed; using them needs ALLOW_META_ROUTERS.
+    if matches!(tier, Tier::Router) {
+        return Router::new()
+            .route("/models", get(list_routers))
+            .route("/models/*id", get(get_router))
+            .route("/chat/completions", post(fwd_router));
     }
+    let list = move |State(s): State<SharedState>, Query(f): Query<ModelFilter>| async move {
+        Proxy::list_models(tier, &s, &f).await
+    };
+    let watch = move |State(s): State<SharedState>,
+                      Query(f): Query<ModelFilter>,
+                      Query(w): Query<WatchQuery>| async move {
+        Proxy::watch_models(tier, &s, &f, &w).await
+    };
+    let caps = move |State(s): State<SharedState>| async move {
+        Proxy::capabilities(tier, &s).await
+    };
+    let get_model = move |State(s): State<SharedState>, Path(id): Path<String>| async move {
+        Proxy::get_model(tier, &s, &id).await
+    };
+    let ping = move |State(s): State<SharedState>, headers: HeaderMap, Path(id): Path<String>| {
+        async move { Proxy::ping_model(tier, &s, &headers, &id).await }
+    };
+    let tokenize = move |State(s): State<SharedState>, body: Bytes| async move {
+        Proxy::tokenize(tier, &s, &body).await
+    };
+    let forward = move |State(s): State<SharedState>, req: Request| async move {
+        Proxy::traced(tier, &s, Proxy::forward(tier, &s, req)).await
+    };
+    let images = move |State(s): State<SharedState>, req: Request| async move {
+        Proxy::traced(tier, &s, Proxy::forward_images(tier, &s, req)).await
+    };
+    let responses = move |State(s): State<SharedState>, req: Request| async move {
+        Proxy::traced(tier, &s, Proxy::handle_responses(tier, &s, req)).await
+    };
+    let batch = move |State(s): State<SharedState>, req: Request| async move {
+        Proxy::traced(tier, &s, Proxy::batch(tier, &s, req)).await
+    };
+    Router::new()
+        .route("/models", get(list))
+        .route("/models/capabilities", get(caps))
+        .route("/models/watch", get(watch))
+        .route("/models/ping/*id", post(ping))
+        .route("/models/*id", get(get_model))
+        .route("/tokenize", post(tokenize))
+        .route("/chat/completions", post(forward))
+        .route("/images/generations", post(images))
+        .route("/responses", post(responses))
+        .route("/responses/:id", get(get_response).delete(delete_response))
+        .route("/batch", post(batch))
 }
 
 pub async fn health() -> &'static str {
     "OK"
 }
 
+pub async fn openapi_spec(State(s): State<SharedState>) -> impl IntoResponse {
+    Json(openapi::spec(&s.config.custom_tiers))
+}
+
 #[derive(Serialize)]
 struct StatusBody {
     free_models: usize,
     stealth_models: usize,
+    image_models: usize,
+    router_models: usize,
+    /// Model count per `CUSTOM_TIERS` name.
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    custom_models: std::collections::BTreeMap<String, usize>,
     last_refreshed: String,
+    last_refresh_error: Option<String>,
+    last_refresh_error_at: Option<String>,
+    refresh_in_progress: bool,
+    next_refresh_at: Option<String>,
+    refresh_interval_secs: u64,
+    consecutive_refresh_failures: u32,
+    maintenance: bool,
+    /// Models the last refresh's health checks dropped, by tier, and why.
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    health_failures: crate::state::HealthFailures,
+    /// Health checks each model has failed in a row, by tier; a served model
+    /// is dropped once its count reaches `HEALTH_FAIL_THRESHOLD`.
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    health_fail_counts: crate::state::HealthStrikes,
+    /// Only with `?history=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<Vec<crate::state::RefreshEvent>>,
 }
 
-pub async fn status(State(s): State<SharedState>) -> impl IntoResponse {
+#[derive(Deserialize)]
+pub struct StatusQuery {
+    #[serde(default)]
+    history: bool,
+}
+
+pub async fn status(
+    State(s): State<SharedState>,
+    Query(q): Query<StatusQuery>,
+) -> impl IntoResponse {
     let c = s.cache.read().await;
+    let (error_at, error) = match c.last_refresh_error {
+        Some((at, ref msg)) => (Some(at.to_rfc3339()), Some(msg.clone())),
+        None => (None, None),
+    };
     Json(StatusBody {
         free_models: c.free_models.len(),
         stealth_models: c.stealth_models.len(),
+        image_models: c.image_models.len(),
+        router_models: c.router_models.len(),
+        custom_models: crate::state::custom_counts(&s.config.custom_tiers, &c.custom_models),
         last_refreshed: c.last_refreshed.to_rfc3339(),
+        last_refresh_error: error,
+        last_refresh_error_at: error_at,
+        refresh_in_progress: c.refresh_in_progress,
+        next_refresh_at: c.next_refresh.map(|t| t.to_rfc3339()),
+        refresh_interval_secs: s.config.refresh_interval_secs,
+        consecutive_refresh_failures: c.consecutive_failures,
+        maintenance: s.maintenance.load(std::sync::atomic::Ordering::Relaxed),
+        health_failures: c.health_failures.clone(),
+        health_fail_counts: s.health_strikes.lock().unwrap().clone(),
+        history: q.history.then(|| c.history.iter().cloned().collect()),
     })
 }
 
+pub async fn metrics(State(s): State<SharedState>) -> impl IntoResponse {
+    ([("content-type", "text/plain; version=0.0.4")], s.metrics.render())
+}
+
+pub async fn classify(
+    State(s): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    Proxy::classify_model(&s, &headers, &id).await
+}
+
+pub async fn maintenance(
+    State(s): State<SharedState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    Proxy::set_maintenance(&s, &headers, &body)
+}
+
+pub async fn cancel(
+    State(s): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    Proxy::cancel_stream(&s, &headers, &id)
+}
+
+/// Fails when a tier's default model isn't among its models, so a typo is
+/// caught at startup rather than on the first request.
+pub async fn check_default_models(s: &SharedState) -> Result<(), String> {
+    let tiers = [Tier::Free, Tier::Stealth]
+        .into_iter()
+        .chain((0..s.config.custom_tiers.len()).map(Tier::Custom));
+    let mut problems = Vec::new();
+    for tier in tiers {
+        let Some(id) = tier.default_model(&s.config) else {
+            continue;
+        };
+        let models = tier.models(&*s.cache.read().await);
+        if !Proxy::serves(s, &models, id) {
+            let name = tier.name(&s.config);
+            problems.push(format!("default model '{id}' is not in the {name} tier"));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
 pub async fn not_found() -> Response {
     Proxy::error(
         StatusCode::NOT_FOUND,
@@ -118,3 +402,45 @@ pub async fn not_found() -> Response {
         Some("unknown_url"),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(query: serde_json::Value) -> ModelFilter {
+        serde_json::from_value(query).unwrap()
+    }
+
+    fn dated(models: &[(&str, i64)]) -> Vec<Model> {
+        models
+            .iter()
+            .map(|&(id, created)| {
+                serde_json::from_value(json!({"id": id, "name": id, "created": created})).unwrap()
+            })
+            .collect()
+    }
+
+    fn ids(models: &[Model]) -> Vec<&str> {
+        models.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    #[test]
+    fn created_sorts_put_undated_models_last() {
+        let all = [("undated", 0), ("old", 100), ("new", 300), ("mid", 200)];
+        let mut models = dated(&all);
+        filter(json!({"sort": "created_desc"})).sort(&mut models);
+        assert_eq!(ids(&models), ["new", "mid", "old", "undated"]);
+        filter(json!({"sort": "created_asc"})).sort(&mut models);
+        assert_eq!(ids(&models), ["old", "mid", "new", "undated"]);
+    }
+
+    #[test]
+    fn created_after_skips_older_and_undated_models() {
+        let models = dated(&[("undated", 0), ("old", 100), ("cutoff", 200), ("new", 300)]);
+        let newest = filter(json!({"created_after": 200}));
+        let kept: Vec<Model> = models.into_iter().filter(|m| newest.matches(m)).collect();
+        assert_eq!(ids(&kept), ["new"]);
+        assert!(filter(json!({})).matches(&dated(&[("undated", 0)])[0]));
+    }
+}
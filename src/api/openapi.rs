@@ -0,0 +1,590 @@
+use crate::config::CustomTier;
+use serde_json::{json, Value};
+
+const TIERS: &[(&str, &str)] = &[("free", "Free models"), ("stealth", "Stealth/cloaked models")];
+
+/// Hand-maintained description of the proxy's own routes. Upstream request and
+/// response bodies are passed through, so they're described loosely.
+pub fn spec(custom_tiers: &[CustomTier]) -> Value {
+    let mut paths = serde_json::Map::new();
+    let builtin = TIERS.iter().map(|&(tier, label)| (tier.to_string(), label.to_string()));
+    let custom = custom_tiers
+        .iter()
+        .map(|t| (t.prefix().to_string(), format!("{} models", t.name)));
+    for (tier, label) in builtin.chain(custom) {
+        let tag = json!([tier]);
+        let model_id = json!({
+            "name": "id",
+            "in": "path",
+            "required": true,
+            "description": "Full (`vendor/model:free`) or short display id",
+            "schema": {"type": "string"}
+        });
+
+        paths.insert(format!("/{tier}/v1/models"), json!({
+            "get": {
+                "tags": tag,
+                "summary": format!("List {}", label.to_lowercase()),
+                "parameters": [{
+                    "name": "supports",
+                    "in": "query",
+                    "required": false,
+                    "description": "Comma-separated capabilities every listed model must have",
+                    "schema": {"type": "string", "example": "tools,vision"}
+                }, {
+                    "name": "sort",
+                    "in": "query",
+                    "required": false,
+                    "description": "`health`: most recently health-checked first; \
+                                    `created_desc`/`created_asc`: by release date",
+                    "schema": {"type": "string", "enum": ["health", "created_desc", "created_asc"]}
+                }, {
+                    "name": "created_after",
+                    "in": "query",
+                    "required": false,
+                    "description": "Only models created after this unix timestamp",
+                    "schema": {"type": "integer"}
+                }, {
+                    "name": "available_only",
+                    "in": "query",
+                    "required": false,
+                    "description": "Only models that passed their last check and work live",
+                    "schema": {"type": "boolean", "default": false}
+                }, {
+                    "name": "format",
+                    "in": "query",
+                    "required": false,
+                    "description": "`csv` returns a `text/csv` table: id, name, context_length, \
+                                    prompt_price, completion_price, then capability flags",
+                    "schema": {"type": "string", "enum": ["json", "csv"], "default": "json"}
+                }],
+                "responses": {"200": ok("ModelList")}
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/models/capabilities"), json!({
+            "get": {
+                "tags": tag,
+                "summary": "Model ids grouped by capability",
+                "responses": {"200": ok("CapabilitySummary")}
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/models/watch"), json!({
+            "get": {
+                "tags": tag,
+                "summary": "Long-poll until a refresh changes the models",
+                "description": "Takes the same filters as the model list",
+                "parameters": [{
+                    "name": "version",
+                    "in": "query",
+                    "required": false,
+                    "description": "Last x-models-version seen; if outdated, answers at once",
+                    "schema": {"type": "integer"}
+                }, {
+                    "name": "timeout_secs",
+                    "in": "query",
+                    "required": false,
+                    "schema": {"type": "integer", "default": 30, "maximum": 300}
+                }],
+                "responses": {
+                    "200": ok("ModelList"),
+                    "304": {"description": "No change before the timeout"}
+                }
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/models/{{id}}"), json!({
+            "get": {
+                "tags": tag,
+                "summary": "Retrieve a model",
+                "parameters": [model_id],
+                "responses": {"200": ok("Model"), "404": error(), "409": error()}
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/models/ping/{{id}}"), json!({
+            "post": {
+                "tags": tag,
+                "summary": "Run a live health check against one model",
+                "parameters": [model_id, admin_token()],
+                "responses": {
+                    "200": ok("PingResult"),
+                    "401": error(),
+                    "403": error(),
+                    "404": error()
+                }
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/tokenize"), json!({
+            "post": {
+                "tags": tag,
+                "summary": "Estimate prompt tokens locally",
+                "requestBody": body("TokenizeRequest"),
+                "responses": {"200": ok("TokenizeResponse"), "400": error(), "404": error()}
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/chat/completions"), json!({
+            "post": {
+                "tags": tag,
+                "summary": "Chat Completions, forwarded to OpenRouter",
+                "parameters": [{
+                    "name": "x-shadow-model",
+                    "in": "header",
+                    "required": false,
+                    "description": "Also send the request to this model and log its answer",
+                    "schema": {"type": "string"}
+                }, {
+                    "name": "Idempotency-Key",
+                    "in": "header",
+                    "required": false,
+                    "description": "Repeats of this non-streaming request get the first response",
+                    "schema": {"type": "string"}
+                }, hide_reasoning(), deadline()],
+                "requestBody": passthrough(),
+                "responses": {
+                    "200": passthrough(),
+                    "400": error(),
+                    "404": error(),
+                    "415": error(),
+                    "422": error(),
+                    "504": error()
+                }
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/images/generations"), json!({
+            "post": {
+                "tags": tag,
+                "summary": "Image generation, forwarded to OpenRouter",
+                "requestBody": passthrough(),
+                "responses": {"200": passthrough(), "400": error(), "404": error(), "415": error()}
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/batch"), json!({
+            "post": {
+                "tags": tag,
+                "summary": "Run many non-streaming chat completions concurrently",
+                "requestBody": {
+                    "required": true,
+                    "content": {"application/json": {"schema": {
+                        "type": "array",
+                        "maxItems": 100,
+                        "items": {"type": "object"}
+                    }}}
+                },
+                "responses": {"200": ok("BatchResult"), "400": error(), "415": error()}
+            }
+        }));
+        paths.insert(format!("/{tier}/v1/responses"), json!({
+            "post": {
+                "tags": tag,
+                "summary": "Responses API, translated to Chat Completions",
+                "parameters": [{
+                    "name": "x-max-tool-calls",
+                    "in": "header",
+                    "required": false,
+                    "description": "Drop tool calls beyond this many; the response is incomplete",
+                    "schema": {"type": "integer", "minimum": 0}
+                }, hide_reasoning(), deadline()],
+                "requestBody": passthrough(),
+                "responses": {
+                    "200": passthrough(),
+                    "400": error(),
+                    "401": error(),
+                    "404": error(),
+                    "415": error(),
+                    "504": error()
+                }
+            }
+        }));
+        let response_id = json!({
+            "name": "id",
+            "in": "path",
+            "required": true,
+            "schema": {"type": "string"}
+        });
+        paths.insert(format!("/{tier}/v1/responses/{{id}}"), json!({
+            "get": {
+                "tags": tag,
+                "summary": "Retrieve a stored response (needs RESPONSE_STORE_SIZE)",
+                "parameters": [response_id],
+                "responses": {"200": passthrough(), "404": error()}
+            },
+            "delete": {
+                "tags": tag,
+                "summary": "Delete a stored response",
+                "parameters": [response_id],
+                "responses": {"200": ok("DeletedResponse"), "404": error()}
+            }
+        }));
+    }
+
+    let routers = json!(["routers"]);
+    paths.insert("/routers/v1/models".into(), json!({
+        "get": {
+            "tags": routers,
+            "summary": "List meta-router models such as openrouter/auto",
+            "responses": {"200": ok("ModelList")}
+        }
+    }));
+    paths.insert("/routers/v1/models/{id}".into(), json!({
+        "get": {
+            "tags": routers,
+            "summary": "Retrieve a meta-router model",
+            "parameters": [
+                {"name": "id", "in": "path", "required": true, "schema": {"type": "string"}}
+            ],
+            "responses": {"200": ok("Model"), "404": error()}
+        }
+    }));
+    paths.insert("/routers/v1/chat/completions".into(), json!({
+        "post": {
+            "tags": routers,
+            "summary": "Chat Completions via a meta-router; requires ALLOW_META_ROUTERS",
+            "requestBody": passthrough(),
+            "responses": {"200": passthrough(), "403": error(), "404": error(), "415": error()}
+        }
+    }));
+
+    paths.insert("/health".into(), json!({
+        "get": {"summary": "Liveness probe", "responses": {"200": {"description": "OK"}}}
+    }));
+    paths.insert("/status".into(), json!({
+        "get": {
+            "summary": "Cache stats and refresh state",
+            "parameters": [{
+                "name": "history",
+                "in": "query",
+                "required": false,
+                "description": "Include the last STATUS_HISTORY_SIZE refreshes, oldest first",
+                "schema": {"type": "boolean", "default": false}
+            }],
+            "responses": {"200": ok("Status")}
+        }
+    }));
+    paths.insert("/admin/maintenance".into(), json!({
+        "post": {
+            "summary": "Turn maintenance mode on or off; an empty body toggles it",
+            "parameters": [admin_token()],
+            "requestBody": {
+                "required": false,
+                "content": {"application/json": {"schema": {
+                    "type": "object",
+                    "properties": {"enabled": {"type": "boolean"}}
+                }}}
+            },
+            "responses": {
+                "200": {
+                    "description": "OK",
+                    "content": {"application/json": {"schema": {
+                        "type": "object",
+                        "properties": {"maintenance": {"type": "boolean"}}
+                    }}}
+                },
+                "400": error(),
+                "401": error(),
+                "403": error()
+            }
+        }
+    }));
+    paths.insert("/cancel/{id}".into(), json!({
+        "post": {
+            "summary": "Stop a forwarded stream you started, by its x-stream-id",
+            "parameters": [
+                {"name": "id", "in": "path", "required": true, "schema": {"type": "string"}}
+            ],
+            "responses": {
+                "200": {
+                    "description": "OK",
+                    "content": {"application/json": {"schema": {
+                        "type": "object",
+                        "properties": {
+                            "id": {"type": "string"},
+                            "cancelled": {"type": "boolean"}
+                        }
+                    }}}
+                },
+                "403": error(),
+                "404": error()
+            }
+        }
+    }));
+    paths.insert("/metrics".into(), json!({
+        "get": {
+            "summary": "Request/response body size histograms (Prometheus text format)",
+            "responses": {"200": {
+                "description": "OK",
+                "content": {"text/plain": {"schema": {"type": "string"}}}
+            }}
+        }
+    }));
+    paths.insert("/admin/classify/{id}".into(), json!({
+        "get": {
+            "summary": "Explain how a fetched model was classified",
+            "parameters": [
+                {"name": "id", "in": "path", "required": true, "schema": {"type": "string"}},
+                admin_token()
+            ],
+            "responses": {
+                "200": ok("Classification"),
+                "401": error(),
+                "403": error(),
+                "404": error()
+            }
+        }
+    }));
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "OpenRouter free/stealth proxy",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths,
+        "components": {"schemas": schemas()}
+    })
+}
+
+fn ok(schema: &str) -> Value {
+    json!({
+        "description": "OK",
+        "content": {"application/json": {"schema": {"$ref": schema_ref(schema)}}}
+    })
+}
+
+fn body(schema: &str) -> Value {
+    json!({
+        "required": true,
+        "content": {"application/json": {"schema": {"$ref": schema_ref(schema)}}}
+    })
+}
+
+fn schema_ref(schema: &str) -> String {
+    format!("#/components/schemas/{schema}")
+}
+
+fn error() -> Value {
+    json!({
+        "description": "Error",
+        "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}
+    })
+}
+
+fn passthrough() -> Value {
+    json!({
+        "description": "OpenAI-compatible body",
+        "content": {
+            "application/json": {"schema": {"type": "object"}},
+            "text/event-stream": {"schema": {"type": "string"}}
+        }
+    })
+}
+
+fn admin_token() -> Value {
+    json!({"name": "x-admin-token", "in": "header", "required": true, "schema": {"type": "string"}})
+}
+
+fn hide_reasoning() -> Value {
+    json!({
+        "name": "x-hide-reasoning",
+        "in": "header",
+        "required": false,
+        "description": "Override HIDE_REASONING for this request",
+        "schema": {"type": "boolean"}
+    })
+}
+
+fn deadline() -> Value {
+    json!({
+        "name": "x-deadline-ms",
+        "in": "header",
+        "required": false,
+        "description": "Give up with a 504 if upstream hasn't answered (first byte, for streams) \
+                        within this many ms, counting queueing and failover",
+        "schema": {"type": "integer", "minimum": 0}
+    })
+}
+
+fn schemas() -> Value {
+    let flag = json!({"type": "boolean"});
+    json!({
+        "Model": {
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "object": {"type": "string", "enum": ["model"]},
+                "created": {"type": "integer"},
+                "owned_by": {"type": "string"},
+                "reasoning": {
+                    "type": "boolean",
+                    "description": "Whether the model can return its reasoning"
+                },
+                "aliased_to": {
+                    "type": "string",
+                    "description": "Only on MODEL_ALIASES entries: the model the alias resolves to"
+                }
+            }
+        },
+        "ModelList": {
+            "type": "object",
+            "properties": {
+                "object": {"type": "string", "enum": ["list"]},
+                "data": {"type": "array", "items": {"$ref": "#/components/schemas/Model"}}
+            }
+        },
+        "CapabilitySummary": {
+            "type": "object",
+            "additionalProperties": {"type": "array", "items": {"type": "string"}}
+        },
+        "PingResult": {
+            "type": "object",
+            "properties": {
+                "alive": {"type": "boolean"},
+                "status": {"type": "integer", "nullable": true},
+                "latency_ms": {"type": "integer"},
+                "error": {"type": "string", "description": "Why a dead model failed"}
+            }
+        },
+        "HealthFailure": {
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "status": {"type": "integer", "nullable": true},
+                "reason": {"type": "string"},
+                "checked_at": {"type": "string", "format": "date-time"}
+            }
+        },
+        "BatchResult": {
+            "type": "object",
+            "properties": {
+                "object": {"type": "string", "enum": ["list"]},
+                "data": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "index": {"type": "integer"},
+                            "status": {"type": "integer"},
+                            "body": {"description": "Upstream response or an Error object"}
+                        }
+                    }
+                }
+            }
+        },
+        "Classification": {
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "tier": {"type": "string", "enum": ["free", "stealth"], "nullable": true},
+                "is_free": flag,
+                "is_stealth": flag,
+                "is_meta_router": flag,
+                "generates_images": flag,
+                "free_suffix": flag,
+                "prompt_price": {"type": "string", "nullable": true},
+                "completion_price": {"type": "string", "nullable": true},
+                "stealth_signals": {
+                    "type": "array",
+                    "items": {"type": "string", "enum": ["description", "name", "id_prefix"]}
+                },
+                "in_free_cache": flag,
+                "in_stealth_cache": flag
+            }
+        },
+        "TokenizeRequest": {
+            "type": "object",
+            "required": ["model"],
+            "properties": {
+                "model": {"type": "string"},
+                "messages": {"type": "array", "items": {"type": "object"}},
+                "input": {"type": "string"}
+            }
+        },
+        "TokenizeResponse": {
+            "type": "object",
+            "properties": {
+                "model": {"type": "string"},
+                "tokens": {"type": "integer"},
+                "method": {"type": "string", "enum": ["o200k_base", "cl100k_base", "heuristic"]},
+                "context_length": {"type": "integer", "nullable": true}
+            }
+        },
+        "Status": {
+            "type": "object",
+            "properties": {
+                "free_models": {"type": "integer"},
+                "stealth_models": {"type": "integer"},
+                "image_models": {"type": "integer"},
+                "router_models": {"type": "integer"},
+                "last_refreshed": {"type": "string", "format": "date-time"},
+                "last_refresh_error": {"type": "string", "nullable": true},
+                "last_refresh_error_at": {
+                    "type": "string",
+                    "format": "date-time",
+                    "nullable": true
+                },
+                "refresh_in_progress": {"type": "boolean"},
+                "next_refresh_at": {"type": "string", "format": "date-time", "nullable": true},
+                "refresh_interval_secs": {"type": "integer"},
+                "consecutive_refresh_failures": {"type": "integer"},
+                "maintenance": {"type": "boolean"},
+                "custom_models": {
+                    "type": "object",
+                    "additionalProperties": {"type": "integer"}
+                },
+                "health_failures": {
+                    "type": "object",
+                    "description": "Models the last refresh's health checks dropped, by tier",
+                    "additionalProperties": {
+                        "type": "array",
+                        "items": {"$ref": schema_ref("HealthFailure")}
+                    }
+                },
+                "health_fail_counts": {
+                    "type": "object",
+                    "description": "Health checks each model has failed in a row, by tier",
+                    "additionalProperties": {
+                        "type": "object",
+                        "additionalProperties": {"type": "integer"}
+                    }
+                },
+                "history": {"type": "array", "items": {"$ref": schema_ref("RefreshEvent")}}
+            }
+        },
+        "DeletedResponse": {
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "object": {"type": "string", "enum": ["response"]},
+                "deleted": {"type": "boolean"}
+            }
+        },
+        "RefreshEvent": {
+            "type": "object",
+            "properties": {
+                "at": {"type": "string", "format": "date-time"},
+                "kind": {"type": "string", "enum": ["full", "diff"]},
+                "duration_ms": {"type": "integer"},
+                "free_models": {"type": "integer"},
+                "stealth_models": {"type": "integer"},
+                "custom_models": {
+                    "type": "object",
+                    "additionalProperties": {"type": "integer"}
+                },
+                "added": {"type": "integer"},
+                "removed": {"type": "integer"},
+                "error": {"type": "string", "nullable": true}
+            }
+        },
+        "Error": {
+            "type": "object",
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "properties": {
+                        "message": {"type": "string"},
+                        "type": {"type": "string"},
+                        "param": {"type": "string", "nullable": true},
+                        "code": {"type": "string", "nullable": true}
+                    }
+                }
+            }
+        }
+    })
+}
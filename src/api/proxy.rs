@@ -1,16 +1,37 @@
 use super::responses;
-use super::{ModelFilter, Tier};
-use crate::model::OpenAIModelList;
+use super::{ModelFilter, Tier, WatchQuery};
+use crate::cache::{
+    caller_key, Cached, FlightFollower, FlightLeader, IdempotencyStore, Joined, RequestKey,
+    ResponseCache,
+};
+use crate::cancel::StreamTicket;
+use crate::config::{glob_match, HeaderFilter, ROUTES, SAMPLING_RANGES, SERVICE_TIERS};
+use crate::metrics::StreamedBytes;
+use crate::model::{Capabilities, Model, OpenAIModel, OpenAIModelList, SOFT_PARAMS};
+use crate::ratelimit::UpstreamPermit;
 use crate::state::SharedState;
+use crate::tokens;
 use axum::{
     body::Body,
-    http::StatusCode,
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use http_body_util::BodyExt;
-
-const UPSTREAM: &str = "https://openrouter.ai/api/v1";
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
 
 const FORWARDED_HEADERS: &[&str] = &[
     "content-type",
@@ -22,6 +43,46 @@ const FORWARDED_HEADERS: &[&str] = &[
     "x-title",
 ];
 
+/// `x-` headers that configure the proxy itself and must not reach upstream.
+const PROXY_ONLY_HEADERS: &[&str] = &[
+    "x-shadow-model",
+    "x-admin-token",
+    "x-max-tool-calls",
+    "x-hide-reasoning",
+    "x-deadline-ms",
+    "x-system-prompt",
+];
+
+/// Request fields only some models accept, paired with the `supported_parameters`
+/// entry that advertises them.
+const GATED_PARAMS: &[(&str, &str)] = &[
+    ("logprobs", "logprobs"),
+    ("top_logprobs", "top_logprobs"),
+    ("response_format", "response_format"),
+];
+
+/// Message and delta fields that carry reasoning text, removed under `HIDE_REASONING`.
+const REASONING_FIELDS: &[&str] = &["reasoning", "reasoning_details", "reasoning_content"];
+
+/// Response header naming the upstream provider that served a completion.
+const PROVIDER_HEADER: HeaderName = HeaderName::from_static("x-openrouter-provider");
+
+/// Set on `/models/watch` replies: the model list version they reflect.
+const MODELS_VERSION_HEADER: HeaderName = HeaderName::from_static("x-models-version");
+
+/// Set on forwarded streams: the id `POST /cancel/{id}` stops them by.
+const STREAM_ID_HEADER: HeaderName = HeaderName::from_static("x-stream-id");
+
+/// Set on streams replayed from an identical request already in flight.
+const COALESCED_HEADER: HeaderName = HeaderName::from_static("x-coalesced");
+
+/// Upper bound on entries in one `/batch` request.
+const MAX_BATCH_SIZE: usize = 100;
+
+/// Default and longest wait of a `/models/watch` long-poll.
+const DEFAULT_WATCH_SECS: u64 = 30;
+const MAX_WATCH_SECS: u64 = 300;
+
 pub struct Proxy;
 
 impl Proxy {
@@ -29,28 +90,387 @@ impl Proxy {
         tier: Tier,
         state: &SharedState,
         filter: &ModelFilter,
-    ) -> Json<OpenAIModelList> {
+    ) -> Response {
         let all = tier.models(&*state.cache.read().await);
-        let filtered: Vec<_> = all.iter().filter(|m| filter.matches(m)).cloned().collect();
-        Json(OpenAIModelList::from_models(&filtered))
+        let mut filtered: Vec<_> = all
+            .iter()
+            .filter(|m| filter.matches(m))
+            .filter(|m| !filter.available_only || state.is_available(tier, m))
+            .cloned()
+            .collect();
+        filter.sort(&mut filtered);
+        let mode = state.config.id_display_mode();
+        match filter.format.as_deref() {
+            None | Some("json") => {
+                let mut list = OpenAIModelList::from_models(&filtered, mode);
+                list.data.extend(Self::list_aliases(tier, state, &all, filter));
+                Json(list).into_response()
+            }
+            Some("csv") => (
+                [("content-type", "text/csv; charset=utf-8")],
+                OpenAIModelList::csv(&filtered, mode),
+            )
+                .into_response(),
+            Some(other) => Self::error(
+                StatusCode::BAD_REQUEST,
+                format!("unsupported format '{other}' (use json or csv)"),
+                Some("invalid_format"),
+            ),
+        }
+    }
+
+    /// Entries for the aliases that currently resolve in this tier, sorted by
+    /// name, filtered by the model each resolves to.
+    fn list_aliases(
+        tier: Tier,
+        state: &SharedState,
+        models: &[Model],
+        filter: &ModelFilter,
+    ) -> Vec<OpenAIModel> {
+        let mode = state.config.id_display_mode();
+        let mut aliases: Vec<&String> = state.config.model_aliases.keys().collect();
+        aliases.sort();
+        aliases
+            .into_iter()
+            .filter_map(|alias| Some((alias, Self::resolve(state, models, alias)?)))
+            .filter(|(_, m)| filter.matches(m))
+            .filter(|(_, m)| !filter.available_only || state.is_available(tier, m))
+            .map(|(alias, m)| m.to_openai_alias(alias, mode))
+            .collect()
+    }
+
+    /// Long-polls until a refresh changes the models, then answers like
+    /// `list_models`; a 304 if nothing changed within the timeout. Either way
+    /// `x-models-version` tells the client what to pass as `version` next.
+    pub async fn watch_models(
+        tier: Tier,
+        state: &SharedState,
+        filter: &ModelFilter,
+        watch: &WatchQuery,
+    ) -> Response {
+        let wait = watch.timeout_secs.unwrap_or(DEFAULT_WATCH_SECS).min(MAX_WATCH_SECS);
+        // Created before reading the version, so a refresh in between still wakes it.
+        let changed = state.models_changed.notified();
+        let current = state.cache.read().await.models_version;
+        let stale = watch.version.is_some_and(|v| v != current);
+        if !stale && tokio::time::timeout(Duration::from_secs(wait), changed).await.is_err() {
+            let mut resp = StatusCode::NOT_MODIFIED.into_response();
+            resp.headers_mut().insert(MODELS_VERSION_HEADER, current.into());
+            return resp;
+        }
+        let version = state.cache.read().await.models_version;
+        let mut resp = Self::list_models(tier, state, filter).await;
+        resp.headers_mut().insert(MODELS_VERSION_HEADER, version.into());
+        resp
+    }
+
+    pub async fn capabilities(
+        tier: Tier,
+        state: &SharedState,
+    ) -> Json<BTreeMap<&'static str, Vec<String>>> {
+        let models = tier.models(&*state.cache.read().await);
+        let mut summary: BTreeMap<&'static str, Vec<String>> = Capabilities::NAMES
+            .iter()
+            .map(|&n| (n, Vec::new()))
+            .collect();
+        for m in models.iter() {
+            let caps = m.capabilities();
+            for (name, ids) in summary.iter_mut() {
+                if caps.get(name) == Some(true) {
+                    ids.push(m.display_id(state.config.id_display_mode()));
+                }
+            }
+        }
+        Json(summary)
     }
 
     pub async fn get_model(tier: Tier, state: &SharedState, raw_id: &str) -> Response {
         let models = tier.models(&*state.cache.read().await);
         let id = raw_id.trim_start_matches('/');
-        match models.iter().find(|m| m.matches_display_id(id)) {
-            Some(m) => Json(m.to_openai()).into_response(),
-            None => Self::error(
-                StatusCode::NOT_FOUND,
-                format!("The model '{id}' does not exist"),
-                Some("model_not_found"),
-            ),
+        if state.config.ambiguous_id_conflict && !state.config.model_aliases.contains_key(id) {
+            if let (found, true) = Self::find_model(state, &models, id) {
+                let ids: Vec<&str> = found.iter().map(|m| m.id.as_str()).collect();
+                return Self::error(
+                    StatusCode::CONFLICT,
+                    format!("'{id}' matches several models, use a full id: {}", ids.join(", ")),
+                    Some("ambiguous_model"),
+                );
+            }
+        }
+        let mode = state.config.id_display_mode();
+        match Self::resolve(state, &models, id) {
+            Some(m) if state.config.model_aliases.contains_key(id) => {
+                Json(m.to_openai_alias(id, mode)).into_response()
+            }
+            Some(m) => Json(m.to_openai(mode)).into_response(),
+            None => Self::model_not_found(state, id),
+        }
+    }
+
+    pub async fn ping_model(
+        tier: Tier,
+        state: &SharedState,
+        headers: &HeaderMap,
+        raw_id: &str,
+    ) -> Response {
+        if let Some(resp) = Self::check_admin(state, headers) {
+            return resp;
+        }
+
+        let id = raw_id.trim_start_matches('/');
+        let Some(key) = tier.api_key(&state.config) else {
+            return Self::error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No OpenRouter API key configured for health checks".into(),
+                Some("health_check_disabled"),
+            );
+        };
+
+        let models = tier.models(&*state.cache.read().await);
+        match Self::resolve(state, &models, id) {
+            Some(m) => {
+                let timeout = state.config.health_check_timeout();
+                let result = m.ping(&state.client, &state.config.upstream_url, key, timeout).await;
+                state.live_health.observe(&m.id, result.status);
+                Json(result).into_response()
+            }
+            None => Self::model_not_found(state, id),
+        }
+    }
+
+    pub async fn tokenize(tier: Tier, state: &SharedState, body: &[u8]) -> Response {
+        let json: serde_json::Value = match serde_json::from_slice(body) {
+            Ok(v) => v,
+            Err(e) => return Self::invalid_json(&e),
+        };
+
+        let Some(mid) = Self::extract_model(&json) else {
+            return Self::error(
+                StatusCode::BAD_REQUEST,
+                "missing required parameter: model".into(),
+                Some("missing_parameter"),
+            );
+        };
+        let models = tier.models(&*state.cache.read().await);
+        let Some(m) = Self::resolve(state, &models, &mid) else {
+            return Self::model_not_found(state, &mid);
+        };
+
+        let estimate = match (json.get("messages"), json.get("input")) {
+            (Some(serde_json::Value::Array(messages)), _) => tokens::count_messages(m, messages),
+            (_, Some(serde_json::Value::String(text))) => tokens::count_text(m, text),
+            _ => {
+                return Self::error(
+                    StatusCode::BAD_REQUEST,
+                    "provide either a `messages` array or an `input` string".into(),
+                    Some("missing_parameter"),
+                )
+            }
+        };
+
+        Json(serde_json::json!({
+            "model": m.display_id(state.config.id_display_mode()),
+            "tokens": estimate.tokens,
+            "method": estimate.method,
+            "context_length": m.context_length,
+        }))
+        .into_response()
+    }
+
+    /// Runs `handler` in a `proxy_request` span with the tier, and once it
+    /// answers the status and latency (to response headers, for streams).
+    /// Upstream calls made from it are `upstream` child spans.
+    pub async fn traced(
+        tier: Tier,
+        state: &SharedState,
+        handler: impl Future<Output = Response>,
+    ) -> Response {
+        let span = info_span!(
+            "proxy_request",
+            tier = tier.name(&state.config),
+            model = field::Empty,
+            status = field::Empty,
+            latency_ms = field::Empty,
+        );
+        let started = Instant::now();
+        let resp = handler.instrument(span.clone()).await;
+        span.record("status", resp.status().as_u16());
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+        resp
+    }
+
+    /// Sends an upstream request in an `upstream` span recording `model`,
+    /// status and latency. The model is also set on the enclosing request span.
+    pub async fn send_traced(
+        model: Option<&str>,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        if let Some(model) = model {
+            Span::current().record("model", model);
+        }
+        let span = info_span!("upstream", model, status = field::Empty, latency_ms = field::Empty);
+        let started = Instant::now();
+        let sent = request.send().instrument(span.clone()).await;
+        if let Ok(ref resp) = sent {
+            span.record("status", resp.status().as_u16());
         }
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+        sent
     }
 
     pub async fn forward(tier: Tier, state: &SharedState, req: axum::extract::Request) -> Response {
         let models = tier.models(&*state.cache.read().await);
+        Self::forward_to(tier, state, &models, req).await
+    }
+
+    pub async fn forward_images(
+        tier: Tier,
+        state: &SharedState,
+        req: axum::extract::Request,
+    ) -> Response {
+        let models = tier.image_models(&*state.cache.read().await);
+        Self::forward_to(tier, state, &models, req).await
+    }
+
+    /// Runs an array of non-streaming chat completion requests through the
+    /// normal forwarding path, a few at a time, and collects every result.
+    pub async fn batch(tier: Tier, state: &SharedState, req: axum::extract::Request) -> Response {
+        if let Some(resp) = Self::check_maintenance(state) {
+            return resp;
+        }
         let (parts, body) = req.into_parts();
+        if let Some(resp) = Self::check_content_type(&parts.headers) {
+            return resp;
+        }
+        let items: Vec<serde_json::Value> = match body.collect().await {
+            Ok(c) => match serde_json::from_slice(&c.to_bytes()) {
+                Ok(items) => items,
+                Err(_) => {
+                    return Self::error(
+                        StatusCode::BAD_REQUEST,
+                        "batch body must be a JSON array of chat completion requests".into(),
+                        None,
+                    )
+                }
+            },
+            Err(e) => {
+                return Self::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("failed to read body: {e}"),
+                    None,
+                )
+            }
+        };
+        if items.len() > MAX_BATCH_SIZE {
+            return Self::error(
+                StatusCode::BAD_REQUEST,
+                format!("a batch may hold at most {MAX_BATCH_SIZE} requests"),
+                Some("batch_too_large"),
+            );
+        }
+
+        let models = tier.models(&*state.cache.read().await);
+        let sem = Arc::new(Semaphore::new(state.config.batch_concurrency));
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut indices = HashMap::new();
+        for (index, item) in items.into_iter().enumerate() {
+            let request = Self::batch_item(&parts, &item);
+            let (state, models, sem) = (state.clone(), models.clone(), sem.clone());
+            let task = tasks.spawn(async move {
+                let resp = match request {
+                    Some(req) => {
+                        let _permit = sem.acquire_owned().await;
+                        Self::forward_to(tier, &state, &models, req).await
+                    }
+                    None => Self::error(
+                        StatusCode::BAD_REQUEST,
+                        "batch entries must be non-streaming chat completion requests".into(),
+                        Some("invalid_batch_entry"),
+                    ),
+                };
+                Self::batch_result(index, resp).await
+            });
+            indices.insert(task.id(), index);
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        while let Some(done) = tasks.join_next_with_id().await {
+            match done {
+                Ok((_, result)) => results.push(result),
+                // A panicked entry still gets its slot, so indices stay complete.
+                Err(e) => {
+                    let index = indices[&e.id()];
+                    warn!("Batch entry {index} failed: {e}");
+                    let resp = Self::error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "the batch entry failed unexpectedly".into(),
+                        Some("batch_entry_failed"),
+                    );
+                    results.push(Self::batch_result(index, resp).await);
+                }
+            }
+        }
+        results.sort_by_key(|(index, _)| *index);
+        let data: Vec<_> = results.into_iter().map(|(_, r)| r).collect();
+        Json(serde_json::json!({"object": "list", "data": data})).into_response()
+    }
+
+    /// One batch entry's outcome as `{index, status, body}`, with the body
+    /// parsed as JSON where possible.
+    async fn batch_result(index: usize, resp: Response) -> (usize, serde_json::Value) {
+        let status = resp.status().as_u16();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap_or_default();
+        let body = serde_json::from_slice(&bytes).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(&bytes).into_owned())
+        });
+        (index, serde_json::json!({"index": index, "status": status, "body": body}))
+    }
+
+    /// Builds the `/chat/completions` request for one batch entry, carrying
+    /// over the caller's headers and address. `None` for entries that aren't
+    /// request objects or that ask to stream.
+    fn batch_item(
+        parts: &axum::http::request::Parts,
+        item: &serde_json::Value,
+    ) -> Option<axum::extract::Request> {
+        if !item.is_object() || item.get("stream").and_then(|v| v.as_bool()) == Some(true) {
+            return None;
+        }
+        let mut req = axum::extract::Request::new(Body::from(item.to_string()));
+        *req.method_mut() = axum::http::Method::POST;
+        *req.uri_mut() = axum::http::Uri::from_static("/chat/completions");
+        for (name, value) in &parts.headers {
+            // Results are parsed as JSON, so upstream must not compress them,
+            // and one Idempotency-Key can't cover entries with different bodies.
+            if !matches!(name.as_str(), "content-length" | "accept-encoding" | "idempotency-key") {
+                req.headers_mut().append(name, value.clone());
+            }
+        }
+        if let Some(info) = parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+            req.extensions_mut().insert(*info);
+        }
+        Some(req)
+    }
+
+    /// Forwards a request to the same upstream path, validating and rewriting
+    /// its `model` against `models`.
+    async fn forward_to(
+        tier: Tier,
+        state: &SharedState,
+        models: &[Model],
+        req: axum::extract::Request,
+    ) -> Response {
+        let started = Instant::now();
+        if let Some(resp) = Self::check_maintenance(state) {
+            return resp;
+        }
+        let (mut parts, body) = req.into_parts();
+        if let Some(resp) = Self::check_deadline(&parts.headers) {
+            return resp;
+        }
+        let deadline = Self::deadline(&parts.headers, started);
 
         let path = parts
             .uri
@@ -58,7 +478,13 @@ impl Proxy {
             .map(|pq| pq.as_str())
             .unwrap_or(parts.uri.path());
 
-        let url = format!("{UPSTREAM}{path}");
+        let Some(url) = Self::upstream_url(&state.config.upstream_url, path) else {
+            return Self::error(
+                StatusCode::BAD_REQUEST,
+                format!("Refusing to forward to a path outside the upstream API: {path}"),
+                Some("invalid_path"),
+            );
+        };
 
         let mut body_bytes = match body.collect().await {
             Ok(c) => c.to_bytes(),
@@ -71,29 +497,151 @@ impl Proxy {
             }
         };
 
-        if let Some(mid) = Self::extract_model(&body_bytes) {
-            match models.iter().find(|m| m.matches_display_id(&mid)) {
-                Some(m) if m.id != mid => {
-                    let mut json: serde_json::Value =
-                        serde_json::from_slice(&body_bytes).unwrap();
-                    json["model"] = serde_json::Value::String(m.id.clone());
-                    body_bytes = axum::body::Bytes::from(json.to_string());
+        state.metrics.request_bytes.observe(body_bytes.len() as u64);
+        let warn_at = state.config.large_request_warn_bytes;
+        if warn_at > 0 && body_bytes.len() > warn_at {
+            // Logged by address only; API keys must not end up in logs.
+            let from = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+                Some(ConnectInfo(addr)) => addr.ip().to_string(),
+                None => "unknown".into(),
+            };
+            warn!("Large request body: {} bytes to {path} from {from}", body_bytes.len());
+        }
+
+        if let Some(resp) = Self::check_content_type(&parts.headers) {
+            return resp;
+        }
+        let mut json: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+            Ok(v @ serde_json::Value::Object(_)) => v,
+            Ok(_) => return Self::not_an_object(),
+            Err(e) => return Self::invalid_json(&e),
+        };
+        if let Some(resp) = Self::check_rate_limit(state, &parts, &json) {
+            return resp;
+        }
+        // Idempotency keys are scoped to this; injected callers all share one key.
+        let own_auth = Self::own_credentials(state, &parts.headers)
+            .and_then(|_| parts.headers.get("authorization").cloned());
+        Self::inject_api_key(tier, state, &mut parts.headers);
+        let hide_reasoning = Self::hides_reasoning(state, &parts.headers);
+
+        let mut cache_key = None;
+        let mut target = None;
+        let mut idempotency = None;
+        let mut streaming = false;
+        let mut leader = None;
+
+        let mut rewritten = match Self::resolve_fallbacks(state, models, &json) {
+            Ok(Some(ids)) => {
+                let ids = serde_json::Value::from(ids);
+                let changed = json["models"] != ids;
+                json["models"] = ids;
+                changed
+            }
+            Ok(None) => false,
+            Err(unknown) => return Self::model_not_found(state, &unknown),
+        };
+        let default = tier.default_model(&state.config).map(String::from);
+        if let Some(mid) = Self::extract_model(&json).or(default) {
+            let session = Self::session_key(&parts.headers, &json);
+            let required = Self::required_params(&json);
+            let Some(m) = Self::resolve_for(state, models, &mid, session, &required) else {
+                return Self::model_not_found(state, &mid);
+            };
+            if let Some(resp) = Self::check_supported(state, m, &json) {
+                return resp;
+            }
+            if let Some(resp) = Self::check_service_tier(&json) {
+                return resp;
+            }
+            if let Some(resp) = Self::check_routing(&json) {
+                return resp;
+            }
+            if let Some(resp) = Self::check_sampling(state, &json) {
+                return resp;
+            }
+            target = Some(m.id.clone());
+            if state.config.precheck_context {
+                if let Some(resp) = Self::check_context(m, &json) {
+                    return resp;
                 }
-                Some(_) => {}
-                None => {
-                    return Self::error(
-                        StatusCode::NOT_FOUND,
-                        format!("The model '{mid}' does not exist"),
-                        Some("model_not_found"),
-                    );
+            }
+            rewritten |= Self::drop_unsupported(m, &mut json);
+            rewritten |= Self::rename_max_tokens(m, &mut json);
+            rewritten |= tier.sampling_defaults(&state.config).apply(&mut json);
+            rewritten |= state.config.apply_service_tier(&mut json);
+            rewritten |= state.config.apply_route(&mut json);
+            rewritten |= state.config.apply_usage_accounting(&mut json);
+            rewritten |= state.config.clamp_sampling(&mut json);
+            if hide_reasoning {
+                rewritten |= Self::exclude_reasoning(&mut json);
+            }
+            if let Some(prompt) = Self::system_prompt(state, &parts.headers) {
+                rewritten |= Self::prepend_system_prompt(&mut json, &prompt);
+            }
+            for t in state.config.model_transforms.iter().filter(|t| t.matches(&m.id)) {
+                rewritten |= t.apply(&mut json);
+            }
+            // Also covers a tier default filled in for a request without `model`.
+            if json.get("model").and_then(|v| v.as_str()) != Some(m.id.as_str()) || rewritten {
+                json["model"] = serde_json::Value::String(m.id.clone());
+                body_bytes = axum::body::Bytes::from(json.to_string());
+            }
+            if state.response_cache.enabled() && ResponseCache::is_cacheable(&json) {
+                let key = RequestKey::new(path, &parts.headers, &json);
+                // Before coalescing, idempotency and shadowing: a hit costs nothing upstream.
+                if let Some(hit) = state.response_cache.get(&key) {
+                    return Self::cached(&hit, Some(("x-cache", "HIT")));
+                }
+                cache_key = Some(key);
+            }
+            streaming = json.get("stream").and_then(|v| v.as_bool()) == Some(true);
+            if streaming && state.inflight.enabled() {
+                match state.inflight.join(RequestKey::new(path, &parts.headers, &json)) {
+                    Joined::Leader(l) => leader = Some(l),
+                    // A leader that failed before answering leaves this to go upstream.
+                    Joined::Follower(flight) => {
+                        if let Some(resp) = Self::follow(flight).await {
+                            return resp;
+                        }
+                    }
                 }
             }
+            let idempotency_key = parts.headers.get("idempotency-key").zip(own_auth);
+            if let Some((k, auth)) = idempotency_key {
+                if state.idempotency.enabled() && !streaming {
+                    let k = String::from_utf8_lossy(k.as_bytes());
+                    let key = IdempotencyStore::key(path, auth, &parts.headers, &k);
+                    let Some(slot) = state.idempotency.slot(key, &json) else {
+                        return Self::error(
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            "This Idempotency-Key was already used with a different body"
+                                .into(),
+                            Some("idempotency_key_reused"),
+                        );
+                    };
+                    idempotency = Some(slot);
+                }
+            }
+
+            if let Some(shadow) = parts.headers.get("x-shadow-model") {
+                match shadow
+                    .to_str()
+                    .ok()
+                    .and_then(|id| Self::resolve(state, models, id))
+                {
+                    Some(s) => Self::spawn_shadow(state, &parts.headers, json, &m.id, &s.id),
+                    None => warn!("Ignoring unknown x-shadow-model {shadow:?}"),
+                }
+            }
+        } else if rewritten {
+            body_bytes = axum::body::Bytes::from(json.to_string());
         }
 
         let mut upstream = state.client.request(parts.method, &url);
 
         for (name, value) in &parts.headers {
-            if FORWARDED_HEADERS.contains(&name.as_str()) || name.as_str().starts_with("x-") {
+            if Self::is_forwarded(name.as_str()) {
                 upstream = upstream.header(name, value);
             }
         }
@@ -102,19 +650,327 @@ impl Proxy {
             upstream = upstream.body(body_bytes);
         }
 
-        match upstream.send().await {
-            Ok(resp) => Self::stream(resp),
+        // Held until this attempt's outcome is stored; repeats wait here.
+        let mut replay = match idempotency {
+            Some(ref slot) => {
+                let guard = slot.result.lock().await;
+                if let Some(ref done) = *guard {
+                    return Self::cached(done, Some(("idempotent-replayed", "true")));
+                }
+                Some(guard)
+            }
+            None => None,
+        };
+
+        let permit = match Self::within(deadline, state.upstream_limiter.acquire()).await {
+            Some(Some(permit)) => permit,
+            Some(None) => return Self::upstream_busy(),
+            None => return Self::deadline_exceeded(),
+        };
+        let sent_at = Instant::now();
+        let send = Self::send_traced(target.as_deref(), upstream);
+        let Some(sent) = Self::within(deadline, send).await else {
+            return Self::deadline_exceeded();
+        };
+        let latency = sent_at.elapsed();
+        if let Some(ref id) = target {
+            let status = sent.as_ref().ok().map(|r| r.status().as_u16());
+            state.live_health.observe(id, status);
+        }
+        match sent {
+            // Non-streaming replies are read whole so the provider header can be set.
+            Ok(resp) if !streaming || cache_key.is_some() || replay.is_some() => {
+                let filter = &state.config.response_headers;
+                let Some(buffered) = Self::within(deadline, Self::buffer(resp, filter)).await else {
+                    return Self::deadline_exceeded();
+                };
+                match buffered {
+                    Ok(mut entry) => {
+                        drop(permit);
+                        let waited = sent_at.elapsed();
+                        if let Some(provider) = Self::provider_of(&entry.body) {
+                            entry.headers.push((PROVIDER_HEADER, provider));
+                        }
+                        if hide_reasoning {
+                            Self::strip_reasoning_body(&mut entry);
+                        }
+                        state.metrics.response_bytes.observe(entry.body.len() as u64);
+                        let marker = match (&cache_key, &replay) {
+                            (Some(_), _) => Some(("x-cache", "MISS")),
+                            (None, Some(_)) => Some(("idempotent-replayed", "false")),
+                            (None, None) => None,
+                        };
+                        if let Some(key) = cache_key {
+                            if (200..300).contains(&entry.status) {
+                                state.response_cache.insert(key, entry.clone());
+                            }
+                        }
+                        // Server errors aren't remembered, so a retry really retries.
+                        if let Some(ref mut outcome) = replay {
+                            if entry.status < 500 {
+                                **outcome = Some(entry.clone());
+                            }
+                        }
+                        let mut resp = Self::cached(&entry, marker);
+                        Self::add_timing(&mut resp, started, latency, waited);
+                        resp
+                    }
+                    Err(e) => {
+                        Self::error(StatusCode::BAD_GATEWAY, format!("upstream error: {e}"), None)
+                    }
+                }
+            }
+            Ok(resp) => {
+                let ticket = state.streams.register(Self::own_credentials(state, &parts.headers));
+                // Headers go out right away; each chunk also names its provider.
+                let mut resp = Self::stream(resp, state, permit, Some(ticket), hide_reasoning);
+                Self::add_timing(&mut resp, started, latency, latency);
+                match leader {
+                    Some(leader) => Self::lead(leader, resp),
+                    None => resp,
+                }
+            }
             Err(e) => Self::error(StatusCode::BAD_GATEWAY, format!("upstream error: {e}"), None),
         }
     }
 
+    /// Tees a streamed reply into `leader`'s flight for identical requests.
+    fn lead(leader: FlightLeader, resp: Response) -> Response {
+        use tokio_stream::StreamExt;
+        let (parts, body) = resp.into_parts();
+        let mut head = parts.headers.clone();
+        // Followers can't cancel the shared stream.
+        head.remove(STREAM_ID_HEADER);
+        head.insert(COALESCED_HEADER, HeaderValue::from_static("true"));
+        leader.start(parts.status, head);
+        let body = body.into_data_stream().map(move |chunk| {
+            if let Ok(ref bytes) = chunk {
+                leader.push(bytes);
+            }
+            chunk
+        });
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+
+    /// Replays a running identical stream, or `None` if its request failed
+    /// before getting a response.
+    async fn follow(flight: FlightFollower) -> Option<Response> {
+        let (status, headers) = flight.head().await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut index = 0;
+            while let Some(chunk) = flight.chunk(index).await {
+                if tx.send(Ok::<_, std::convert::Infallible>(chunk)).await.is_err() {
+                    break;
+                }
+                index += 1;
+            }
+        });
+        let mut resp = Response::new(Body::from_stream(ReceiverStream::new(rx)));
+        *resp.status_mut() = status;
+        *resp.headers_mut() = headers;
+        Some(resp)
+    }
+
+    /// `base` joined with a request's path and query, or `None` when the path
+    /// isn't absolute or has empty, `.` or `..` segments (percent-encoded
+    /// too), which could reach past `base` on upstream.
+    fn upstream_url(base: &str, path_and_query: &str) -> Option<String> {
+        let path = path_and_query.split('?').next().unwrap_or_default();
+        let rest = path.strip_prefix('/')?;
+        let escapes = rest.split('/').any(|seg| {
+            let seg = seg.to_ascii_lowercase().replace("%2e", ".");
+            seg.is_empty() || seg == "." || seg == ".." || seg.contains("%2f")
+        });
+        (!escapes).then(|| format!("{base}{path_and_query}"))
+    }
+
+    /// Adds `x-upstream-latency-ms`, how long upstream took to start answering,
+    /// and `x-proxy-overhead-ms`, the time since `started` not spent `waited`
+    /// on upstream (which for buffered replies includes reading the body).
+    pub(super) fn add_timing(
+        resp: &mut Response,
+        started: Instant,
+        latency: Duration,
+        waited: Duration,
+    ) {
+        let overhead = started.elapsed().saturating_sub(waited);
+        let headers = resp.headers_mut();
+        headers.insert("x-upstream-latency-ms", (latency.as_millis() as u64).into());
+        headers.insert("x-proxy-overhead-ms", (overhead.as_millis() as u64).into());
+    }
+
+    /// The `provider` OpenRouter reports in a completion body, as a header
+    /// value. `None` for compressed or provider-less bodies.
+    fn provider_of(body: &[u8]) -> Option<HeaderValue> {
+        let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+        HeaderValue::from_str(value.get("provider")?.as_str()?).ok()
+    }
+
+    /// Reads a whole upstream response into a cacheable entry.
+    async fn buffer(resp: reqwest::Response, filter: &HeaderFilter) -> reqwest::Result<Cached> {
+        let status = resp.status().as_u16();
+        let headers = resp
+            .headers()
+            .iter()
+            .filter(|(name, _)| Self::is_relayed(name.as_str(), filter))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        let body = resp.bytes().await?;
+        Ok(Cached {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    /// Relays a buffered response, optionally tagged with a marker header.
+    fn cached(entry: &Cached, marker: Option<(&'static str, &'static str)>) -> Response {
+        let mut builder = Response::builder().status(entry.status);
+        for (name, value) in &entry.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some((name, value)) = marker {
+            builder = builder.header(name, value);
+        }
+        builder
+            .body(Body::from(entry.body.clone()))
+            .unwrap_or_else(|_| {
+                Self::error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to build response".into(),
+                    None,
+                )
+            })
+    }
+
+    /// Sends a non-streaming copy of the request to a second model in the
+    /// background and logs its answer next to the primary model's id. Skipped
+    /// when `MAX_CONCURRENT_UPSTREAM` has no slot free, so it never queues.
+    fn spawn_shadow(
+        state: &SharedState,
+        headers: &HeaderMap,
+        mut json: serde_json::Value,
+        primary: &str,
+        shadow: &str,
+    ) {
+        json["model"] = serde_json::Value::String(shadow.to_owned());
+        json["stream"] = serde_json::Value::Bool(false);
+        if let Some(obj) = json.as_object_mut() {
+            obj.remove("stream_options");
+        }
+
+        let mut upstream = state
+            .client
+            .post(format!("{}/chat/completions", state.config.upstream_url))
+            .json(&json);
+        for name in ["authorization", "http-referer", "x-title"] {
+            if let Some(value) = headers.get(name) {
+                upstream = upstream.header(name, value);
+            }
+        }
+
+        let (primary, shadow) = (primary.to_owned(), shadow.to_owned());
+        let state = state.clone();
+        tokio::spawn(async move {
+            let Some(_permit) = state.upstream_limiter.try_acquire() else {
+                info!("[shadow] {shadow} (primary {primary}) skipped, upstream slots are full");
+                return;
+            };
+            let started = Instant::now();
+            match upstream.send().await {
+                Ok(resp) => {
+                    let status = resp.status();
+                    let body = resp.bytes().await.unwrap_or_default();
+                    let ms = started.elapsed().as_millis();
+                    let size = body.len();
+                    // Completions may hold user data, so only their size is logged by default.
+                    let outcome = format!("{status}, {ms}ms, {size} bytes");
+                    info!("[shadow] {shadow} (primary {primary}) -> {outcome}");
+                    let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+                    let content = body
+                        .pointer("/choices/0/message/content")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or_default();
+                    debug!("[shadow] {shadow} (primary {primary}) answered: {content}");
+                }
+                Err(e) => warn!("[shadow] {shadow} (primary {primary}) -> {e}"),
+            }
+        });
+    }
+
+    /// Applies the per-caller rate limit, then the per-user one within that
+    /// caller. Callers are identified by a hash of their API key, else their IP
+    /// address; the body's `user` field only picks a bucket inside the caller's,
+    /// so varying it can't get around the caller limit.
+    fn check_rate_limit(
+        state: &SharedState,
+        parts: &axum::http::request::Parts,
+        json: &serde_json::Value,
+    ) -> Option<Response> {
+        if !state.rate_limiter.enabled() && !state.user_rate_limiter.enabled() {
+            return None;
+        }
+        let caller = if parts.headers.contains_key("authorization") {
+            format!("key:{:x}", caller_key(&parts.headers))
+        } else if let Some(ConnectInfo(addr)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+            format!("ip:{}", addr.ip())
+        } else {
+            "anonymous".into()
+        };
+
+        let retry_after = state.rate_limiter.check(&caller).err().or_else(|| {
+            let user = json.get("user")?.as_str()?;
+            state.user_rate_limiter.check(&format!("{caller}/user:{user}")).err()
+        })?;
+        let mut resp = Self::error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Rate limit exceeded, slow down".into(),
+            Some("rate_limit_exceeded"),
+        );
+        resp.headers_mut()
+            .insert("retry-after", (retry_after.as_secs() + 1).into());
+        Some(resp)
+    }
+
+    /// Rejects prompts whose estimated size already exceeds the model's context.
+    fn check_context(model: &Model, json: &serde_json::Value) -> Option<Response> {
+        let allowed = model.context_length?;
+        let messages = json.get("messages")?.as_array()?;
+        let estimate = tokens::count_messages(model, messages);
+        (estimate.tokens as u64 > allowed).then(|| {
+            Self::error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "This model's maximum context length is {allowed} tokens, but the \
+                     messages are estimated at {} tokens",
+                    estimate.tokens
+                ),
+                Some("context_length_exceeded"),
+            )
+        })
+    }
+
+    fn is_forwarded(name: &str) -> bool {
+        (FORWARDED_HEADERS.contains(&name) || name.starts_with("x-"))
+            && !PROXY_ONLY_HEADERS.contains(&name)
+    }
+
     pub async fn handle_responses(
         tier: Tier,
         state: &SharedState,
         req: axum::extract::Request,
     ) -> Response {
+        let started = Instant::now();
+        if let Some(resp) = Self::check_maintenance(state) {
+            return resp;
+        }
         let models = tier.models(&*state.cache.read().await);
-        let (parts, body) = req.into_parts();
+        let (mut parts, body) = req.into_parts();
+        if let Some(resp) = Self::check_deadline(&parts.headers) {
+            return resp;
+        }
+        let deadline = Self::deadline(&parts.headers, started);
 
         let body_bytes = match body.collect().await {
             Ok(c) => c.to_bytes(),
@@ -127,20 +983,19 @@ impl Proxy {
             }
         };
 
-        let json_body: serde_json::Value = match serde_json::from_slice(&body_bytes) {
-            Ok(v) => v,
-            Err(e) => {
-                return Self::error(
-                    StatusCode::BAD_REQUEST,
-                    format!("invalid JSON: {e}"),
-                    None,
-                )
-            }
+        if let Some(resp) = Self::check_content_type(&parts.headers) {
+            return resp;
+        }
+        let mut json_body: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+            Ok(v @ serde_json::Value::Object(_)) => v,
+            Ok(_) => return Self::not_an_object(),
+            Err(e) => return Self::invalid_json(&e),
         };
 
         let model_str = json_body
             .get("model")
             .and_then(|v| v.as_str())
+            .or(tier.default_model(&state.config))
             .unwrap_or("")
             .to_string();
 
@@ -152,20 +1007,56 @@ impl Proxy {
             );
         }
 
-        let resolved_model = match models.iter().find(|m| m.matches_display_id(&model_str)) {
-            Some(m) => m,
-            None => {
-                return Self::error(
-                    StatusCode::NOT_FOUND,
-                    format!("The model '{}' does not exist", model_str),
-                    Some("model_not_found"),
-                );
-            }
-        };
+        let session = Self::session_key(&parts.headers, &json_body);
+        let required = Self::required_params(&json_body);
+        let chain = Self::failover_chain(state, &models, &model_str, session, &required);
+        if chain.is_empty() {
+            return Self::model_not_found(state, &model_str);
+        }
+        // Passed through to upstream as-is, so validate and rewrite it like chat.
+        match Self::resolve_fallbacks(state, &models, &json_body) {
+            Ok(Some(ids)) => json_body["models"] = ids.into(),
+            Ok(None) => {}
+            Err(unknown) => return Self::model_not_found(state, &unknown),
+        }
 
-        let api_key = parts
-            .headers
-            .get("authorization")
+        if let Some(resp) = Self::check_rate_limit(state, &parts, &json_body) {
+            return resp;
+        }
+        // Taken before injection: injected callers all share the server's key.
+        let owner = Self::own_credentials(state, &parts.headers);
+        Self::inject_api_key(tier, state, &mut parts.headers);
+
+        if let Some(resp) = Self::check_service_tier(&json_body) {
+            return resp;
+        }
+        if let Some(resp) = Self::check_routing(&json_body) {
+            return resp;
+        }
+        if let Some(resp) = Self::check_sampling(state, &json_body) {
+            return resp;
+        }
+        state.config.clamp_sampling(&mut json_body);
+        tier.sampling_defaults(&state.config).apply(&mut json_body);
+        state.config.apply_service_tier(&mut json_body);
+        state.config.apply_route(&mut json_body);
+
+        if Self::hides_reasoning(state, &parts.headers) {
+            Self::exclude_reasoning(&mut json_body);
+        }
+
+        // The header wins over the body's `max_tool_calls`, which wins over config.
+        if let Some(max) = parts
+            .headers
+            .get("x-max-tool-calls")
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        {
+            json_body["max_tool_calls"] = max.into();
+        }
+
+        let api_key = parts
+            .headers
+            .get("authorization")
             .and_then(|v| v.to_str().ok())
             .and_then(|v| v.strip_prefix("Bearer "))
             .unwrap_or("")
@@ -179,28 +1070,741 @@ impl Proxy {
             );
         }
 
-        responses::handle_responses(&state.client, &api_key, &resolved_model.id, json_body).await
+        // Like OpenAI, responses are stored unless the client sends `store: false`.
+        // Callers without a key of their own couldn't be told apart, so theirs aren't.
+        let store = owner
+            .filter(|_| {
+                state.stored_responses.enabled()
+                    && json_body.get("store").and_then(serde_json::Value::as_bool) != Some(false)
+            })
+            .map(|owner| (state.stored_responses.clone(), owner));
+
+        let permit = match Self::within(deadline, state.upstream_limiter.acquire()).await {
+            Some(Some(permit)) => permit,
+            Some(None) => return Self::upstream_busy(),
+            None => return Self::deadline_exceeded(),
+        };
+        responses::handle_responses(
+            &state.client,
+            &state.config,
+            &state.live_health,
+            &api_key,
+            &chain,
+            json_body,
+            permit,
+            started,
+            deadline,
+            store,
+            Self::system_prompt(state, &parts.headers).as_deref(),
+        )
+        .await
+    }
+
+    /// With `INJECT_API_KEY`, requests without credentials use the tier's
+    /// server-side OpenRouter key. Runs after rate limiting so anonymous callers
+    /// are still told apart by address.
+    fn inject_api_key(tier: Tier, state: &SharedState, headers: &mut HeaderMap) {
+        if !state.config.inject_api_key || headers.contains_key("authorization") {
+            return;
+        }
+        let Some(key) = tier.api_key(&state.config) else {
+            return;
+        };
+        if let Ok(value) = format!("Bearer {key}").parse() {
+            headers.insert("authorization", value);
+        }
     }
 
-    fn extract_model(body: &[u8]) -> Option<String> {
-        if body.is_empty() {
+    /// The caller's identity for owning a stream: `None` without an API key or
+    /// with one of the server's own (as sent by `INJECT_API_KEY`), since every
+    /// such caller would look the same.
+    fn own_credentials(state: &SharedState, headers: &HeaderMap) -> Option<u64> {
+        let auth = headers.get("authorization")?.to_str().ok()?;
+        let token = auth.strip_prefix("Bearer ").unwrap_or(auth);
+        let config = &state.config;
+        let server_keys = [&config.health_check_key, &config.free_api_key, &config.stealth_api_key];
+        if server_keys.iter().any(|k| k.as_deref() == Some(token)) {
             return None;
         }
-        let json: serde_json::Value = serde_json::from_slice(body).ok()?;
+        Some(caller_key(headers))
+    }
+
+    /// `HIDE_REASONING`, unless the request's `x-hide-reasoning` header says otherwise.
+    fn hides_reasoning(state: &SharedState, headers: &HeaderMap) -> bool {
+        match headers.get("x-hide-reasoning").and_then(|v| v.to_str().ok()) {
+            Some("true" | "1") => true,
+            Some("false" | "0") => false,
+            _ => state.config.hide_reasoning,
+        }
+    }
+
+    /// `INJECT_SYSTEM_PROMPT`, unless the request's `x-system-prompt` header
+    /// replaces it or (with `none`) turns it off.
+    fn system_prompt(state: &SharedState, headers: &HeaderMap) -> Option<String> {
+        match headers.get("x-system-prompt").map(|v| v.to_str()) {
+            Some(Ok("none")) => None,
+            Some(Ok(prompt)) if !prompt.is_empty() => Some(prompt.to_owned()),
+            _ => state.config.inject_system_prompt.clone(),
+        }
+    }
+
+    /// Puts `prompt` first in a chat body's `messages`, ahead of any system or
+    /// developer messages the client sent. Returns whether the body changed.
+    pub fn prepend_system_prompt(json: &mut serde_json::Value, prompt: &str) -> bool {
+        let Some(messages) = json.get_mut("messages").and_then(|m| m.as_array_mut()) else {
+            return false;
+        };
+        messages.insert(0, serde_json::json!({"role": "system", "content": prompt}));
+        true
+    }
+
+    /// Asks OpenRouter to leave reasoning text out of the response. Usage
+    /// still reports the reasoning tokens. Returns whether the body changed.
+    fn exclude_reasoning(json: &mut serde_json::Value) -> bool {
+        let Some(obj) = json.as_object_mut() else {
+            return false;
+        };
+        let reasoning = obj.entry("reasoning").or_insert_with(|| serde_json::json!({}));
+        match reasoning.as_object_mut() {
+            Some(r) if r.get("exclude") != Some(&serde_json::Value::Bool(true)) => {
+                r.insert("exclude".into(), true.into());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops reasoning text from a chat completion or stream chunk, for
+    /// providers that ignore `reasoning.exclude`. Usage, and with it the
+    /// reasoning token count, is left alone. Returns whether anything was removed.
+    fn strip_reasoning(json: &mut serde_json::Value) -> bool {
+        let mut changed = false;
+        let choices = json.get_mut("choices").and_then(|c| c.as_array_mut());
+        for choice in choices.into_iter().flatten() {
+            for key in ["message", "delta"] {
+                if let Some(m) = choice.get_mut(key).and_then(|m| m.as_object_mut()) {
+                    for &field in REASONING_FIELDS {
+                        changed |= m.remove(field).is_some();
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    /// `strip_reasoning` for a buffered reply; compressed bodies are left as is.
+    fn strip_reasoning_body(entry: &mut Cached) {
+        let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&entry.body) else {
+            return;
+        };
+        if Self::strip_reasoning(&mut json) {
+            entry.body = axum::body::Bytes::from(json.to_string());
+            entry.headers.retain(|(name, _)| name != "content-length");
+        }
+    }
+
+    /// Rejects an `x-deadline-ms` header that isn't a whole number of ms.
+    fn check_deadline(headers: &HeaderMap) -> Option<Response> {
+        let value = headers.get("x-deadline-ms")?;
+        if value.to_str().is_ok_and(|v| v.trim().parse::<u64>().is_ok()) {
+            return None;
+        }
+        Some(Self::error(
+            StatusCode::BAD_REQUEST,
+            "x-deadline-ms must be a whole number of milliseconds".into(),
+            Some("invalid_deadline"),
+        ))
+    }
+
+    /// When the client's `x-deadline-ms` budget, counted from `started`, runs out.
+    fn deadline(headers: &HeaderMap, started: Instant) -> Option<Instant> {
+        let ms = headers.get("x-deadline-ms")?.to_str().ok()?.trim().parse().ok()?;
+        Some(started + Duration::from_millis(ms))
+    }
+
+    /// Runs `fut` to completion, or until `deadline` passes (`None`).
+    pub(super) async fn within<F: std::future::Future>(
+        deadline: Option<Instant>,
+        fut: F,
+    ) -> Option<F::Output> {
+        match deadline {
+            Some(at) => tokio::time::timeout_at(at.into(), fut).await.ok(),
+            None => Some(fut.await),
+        }
+    }
+
+    /// JSON endpoints accept a missing `content-type`, but anything declared
+    /// must be JSON (`application/json` or a `+json` type) or it's a 415.
+    fn check_content_type(headers: &HeaderMap) -> Option<Response> {
+        let value = headers.get("content-type")?;
+        let mime = value.to_str().unwrap_or("").split(';').next().unwrap_or("").trim();
+        let mime = mime.to_ascii_lowercase();
+        if mime == "application/json" || mime.ends_with("+json") {
+            return None;
+        }
+        Some(Self::error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Expected a JSON body (content-type: application/json), got {value:?}"),
+            Some("unsupported_media_type"),
+        ))
+    }
+
+    fn invalid_json(e: &serde_json::Error) -> Response {
+        Self::error(
+            StatusCode::BAD_REQUEST,
+            format!("Request body is not valid JSON: {e}"),
+            Some("invalid_json"),
+        )
+    }
+
+    fn not_an_object() -> Response {
+        Self::error(
+            StatusCode::BAD_REQUEST,
+            "Request body must be a JSON object".into(),
+            Some("invalid_json"),
+        )
+    }
+
+    pub(super) fn deadline_exceeded() -> Response {
+        Self::error(
+            StatusCode::GATEWAY_TIMEOUT,
+            "The request's x-deadline-ms budget ran out before upstream answered".into(),
+            Some("deadline_exceeded"),
+        )
+    }
+
+    fn upstream_busy() -> Response {
+        Self::error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many concurrent upstream requests, try again shortly".into(),
+            Some("upstream_busy"),
+        )
+    }
+
+    /// Shows how the last fetched copy of a model was classified. Admin only.
+    pub async fn classify_model(state: &SharedState, headers: &HeaderMap, id: &str) -> Response {
+        if let Some(resp) = Self::check_admin(state, headers) {
+            return resp;
+        }
+        let cache = state.cache.read().await;
+        let Some(&m) = Self::find_model(state, &cache.all_models, id).0.first() else {
+            return Self::model_not_found(state, id);
+        };
+        let mut body = serde_json::to_value(m.classification()).unwrap_or_default();
+        body["in_free_cache"] = cache.free_models.iter().any(|f| f.id == m.id).into();
+        body["in_stealth_cache"] = cache.stealth_models.iter().any(|f| f.id == m.id).into();
+        Json(body).into_response()
+    }
+
+    /// `GET /v1/responses/{id}`: a stored response, if it belongs to the caller.
+    /// Callers without their own API key never own one.
+    pub fn get_response(state: &SharedState, headers: &HeaderMap, id: &str) -> Response {
+        let Some(owner) = Self::own_credentials(state, headers) else {
+            return Self::response_not_found(id);
+        };
+        match state.stored_responses.get(owner, id) {
+            Some(response) => Json(response).into_response(),
+            None => Self::response_not_found(id),
+        }
+    }
+
+    /// `DELETE /v1/responses/{id}`.
+    pub fn delete_response(state: &SharedState, headers: &HeaderMap, id: &str) -> Response {
+        let Some(owner) = Self::own_credentials(state, headers) else {
+            return Self::response_not_found(id);
+        };
+        if !state.stored_responses.remove(owner, id) {
+            return Self::response_not_found(id);
+        }
+        Json(serde_json::json!({"id": id, "object": "response", "deleted": true})).into_response()
+    }
+
+    /// Stops a forwarded stream by the id from its `x-stream-id` header. Only
+    /// the caller that started it may; others get the same 404 as unknown ids.
+    pub fn cancel_stream(state: &SharedState, headers: &HeaderMap, id: &str) -> Response {
+        let Some(owner) = Self::own_credentials(state, headers) else {
+            return Self::error(
+                StatusCode::FORBIDDEN,
+                "Cancelling a stream requires the API key that started it".into(),
+                Some("stream_not_cancellable"),
+            );
+        };
+        if !state.streams.cancel(id, owner) {
+            return Self::error(
+                StatusCode::NOT_FOUND,
+                format!("No stream '{id}' in progress"),
+                Some("stream_not_found"),
+            );
+        }
+        Json(serde_json::json!({"id": id, "cancelled": true})).into_response()
+    }
+
+    /// Unknown, expired and other callers' responses all look the same.
+    fn response_not_found(id: &str) -> Response {
+        Self::error(
+            StatusCode::NOT_FOUND,
+            format!("Response '{id}' not found"),
+            Some("response_not_found"),
+        )
+    }
+
+    /// Returns an error response when the request lacks a valid `x-admin-token`.
+    pub fn check_admin(state: &SharedState, headers: &HeaderMap) -> Option<Response> {
+        let Some(ref token) = state.config.admin_token else {
+            return Some(Self::error(
+                StatusCode::FORBIDDEN,
+                "Admin endpoints are disabled (ADMIN_TOKEN not set)".into(),
+                Some("admin_disabled"),
+            ));
+        };
+        let supplied = headers.get("x-admin-token").and_then(|v| v.to_str().ok());
+        (supplied != Some(token.as_str())).then(|| {
+            Self::error(
+                StatusCode::UNAUTHORIZED,
+                "Invalid or missing x-admin-token header".into(),
+                Some("invalid_admin_token"),
+            )
+        })
+    }
+
+    /// Sheds generation requests while maintenance mode is on.
+    fn check_maintenance(state: &SharedState) -> Option<Response> {
+        if !state.maintenance.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut resp = Self::error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The proxy is in maintenance mode; model listings still work. Please retry later"
+                .into(),
+            Some("maintenance"),
+        );
+        resp.headers_mut().insert("retry-after", 60.into());
+        Some(resp)
+    }
+
+    /// Turns maintenance mode on or off (`{"enabled": bool}`), or flips it when
+    /// the body is empty. Admin only.
+    pub fn set_maintenance(state: &SharedState, headers: &HeaderMap, body: &[u8]) -> Response {
+        if let Some(resp) = Self::check_admin(state, headers) {
+            return resp;
+        }
+        let requested = if body.is_empty() {
+            None
+        } else {
+            let parsed = serde_json::from_slice::<serde_json::Value>(body).ok();
+            match parsed.as_ref().and_then(|j| j.get("enabled")?.as_bool()) {
+                Some(enabled) => Some(enabled),
+                None => {
+                    return Self::error(
+                        StatusCode::BAD_REQUEST,
+                        "body must be {\"enabled\": true|false} or empty to toggle".into(),
+                        None,
+                    )
+                }
+            }
+        };
+        let enabled = match requested {
+            Some(enabled) => {
+                state.maintenance.store(enabled, Ordering::Relaxed);
+                enabled
+            }
+            None => !state.maintenance.fetch_xor(true, Ordering::Relaxed),
+        };
+        warn!("Maintenance mode {}", if enabled { "on" } else { "off" });
+        Json(serde_json::json!({"maintenance": enabled})).into_response()
+    }
+
+    /// Resolves a client-supplied id to a model in the tier. Configured aliases
+    /// take precedence and resolve to their first candidate present in the tier.
+    fn resolve<'a>(state: &SharedState, models: &'a [Model], id: &str) -> Option<&'a Model> {
+        Self::resolve_for(state, models, id, None, &[])
+    }
+
+    /// Whether `id` (a model or alias) resolves to one of `models`.
+    pub fn serves(state: &SharedState, models: &[Model], id: &str) -> bool {
+        Self::resolve(state, models, id).is_some()
+    }
+
+    /// Every model `id` may name (not following aliases), best first, and
+    /// whether the first two tie; see `Model::find_by_display_id`.
+    fn find_model<'a>(
+        state: &SharedState,
+        models: &'a [Model],
+        id: &str,
+    ) -> (Vec<&'a Model>, bool) {
+        let mode = state.config.id_display_mode();
+        Model::find_by_display_id(models, id, mode, &state.config.provider_preference)
+    }
+
+    /// Like `resolve`, but an alias skips candidates lacking any `required`
+    /// parameter, and with `STICKY_ALIASES` picks among its candidates by
+    /// rendezvous hashing of `session`, so a session keeps its model and only
+    /// sessions on a model that fails live traffic move.
+    fn resolve_for<'a>(
+        state: &SharedState,
+        models: &'a [Model],
+        id: &str,
+        session: Option<&str>,
+        required: &[&str],
+    ) -> Option<&'a Model> {
+        let Some(present) = Self::alias_candidates(state, models, id, required) else {
+            return Self::find_model(state, models, id).0.first().copied();
+        };
+        let Some(session) = session.filter(|_| state.config.sticky_aliases) else {
+            return present.first().copied();
+        };
+        let score = |m: &Model| {
+            let mut h = DefaultHasher::new();
+            (session, m.id.as_str()).hash(&mut h);
+            h.finish()
+        };
+        present
+            .iter()
+            .filter(|m| state.live_health.is_available(&m.id))
+            .max_by_key(|m| score(m))
+            .or(present.first())
+            .copied()
+    }
+
+    /// The tier's models an alias may resolve to, in candidate order. A
+    /// candidate with `*` is a pattern over full ids and may match several
+    /// models, taken in tier order. Candidates advertising every `required`
+    /// parameter are kept; if none does, all are, so the request fails with
+    /// the usual unsupported-parameter error. `None` if `id` isn't an alias.
+    fn alias_candidates<'a>(
+        state: &SharedState,
+        models: &'a [Model],
+        id: &str,
+        required: &[&str],
+    ) -> Option<Vec<&'a Model>> {
+        let candidates = state.config.model_aliases.get(id)?;
+        let present: Vec<&Model> = candidates
+            .iter()
+            .flat_map(|c| -> Vec<&Model> {
+                if c.contains('*') {
+                    models.iter().filter(|m| glob_match(c, &m.id)).collect()
+                } else {
+                    Self::find_model(state, models, c).0.into_iter().take(1).collect()
+                }
+            })
+            .collect();
+        let capable: Vec<&Model> = present
+            .iter()
+            .copied()
+            .filter(|m| required.iter().all(|p| m.has_param(p)))
+            .collect();
+        Some(if capable.is_empty() { present } else { capable })
+    }
+
+    /// Whether a gated field's value asks for anything. `false` and the default
+    /// `{"type": "text"}` format don't.
+    fn in_use(value: &serde_json::Value) -> bool {
+        !value.is_null()
+            && value != &serde_json::Value::Bool(false)
+            && value.get("type").and_then(|t| t.as_str()) != Some("text")
+    }
+
+    /// `supported_parameters` entries a model needs to serve this chat or
+    /// Responses body: `tools` when tools are given, plus any `GATED_PARAMS`
+    /// in use. A Responses `text.format` counts as `response_format`.
+    fn required_params(json: &serde_json::Value) -> Vec<&'static str> {
+        let mut required = Vec::new();
+        if json.get("tools").and_then(|t| t.as_array()).is_some_and(|t| !t.is_empty()) {
+            required.push("tools");
+        }
+        for &(field, param) in GATED_PARAMS {
+            if json.get(field).is_some_and(Self::in_use) {
+                required.push(param);
+            }
+        }
+        let text_format = json.pointer("/text/format").is_some_and(Self::in_use);
+        if text_format && !required.contains(&"response_format") {
+            required.push("response_format");
+        }
+        required
+    }
+
+    /// The models to try for `id`, in order: the one `resolve_for` picks, then
+    /// an alias's other candidates that aren't failing live traffic. Empty if
+    /// nothing resolves.
+    fn failover_chain<'a>(
+        state: &SharedState,
+        models: &'a [Model],
+        id: &str,
+        session: Option<&str>,
+        required: &[&str],
+    ) -> Vec<&'a Model> {
+        let Some(first) = Self::resolve_for(state, models, id, session, required) else {
+            return Vec::new();
+        };
+        let mut chain = vec![first];
+        for m in Self::alias_candidates(state, models, id, required).unwrap_or_default() {
+            if state.live_health.is_available(&m.id) && !chain.iter().any(|c| c.id == m.id) {
+                chain.push(m);
+            }
+        }
+        chain
+    }
+
+    /// The key sticky alias routing hashes: the body's `user`, else an
+    /// `x-session-id` header.
+    fn session_key<'a>(headers: &'a HeaderMap, json: &'a serde_json::Value) -> Option<&'a str> {
+        json.get("user")
+            .and_then(|u| u.as_str())
+            .or_else(|| headers.get("x-session-id")?.to_str().ok())
+            .filter(|k| !k.is_empty())
+    }
+
+    /// Resolves each entry of OpenRouter's `models` fallback list to a full id
+    /// in the tier. `Err` carries the first entry that isn't available.
+    fn resolve_fallbacks(
+        state: &SharedState,
+        models: &[Model],
+        json: &serde_json::Value,
+    ) -> Result<Option<Vec<String>>, String> {
+        let Some(entries) = json.get("models").and_then(|v| v.as_array()) else {
+            return Ok(None);
+        };
+        entries
+            .iter()
+            .map(|entry| {
+                let id = entry.as_str().ok_or_else(|| entry.to_string())?;
+                Self::resolve(state, models, id)
+                    .map(|m| m.id.clone())
+                    .ok_or_else(|| id.to_owned())
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    fn model_not_found(state: &SharedState, id: &str) -> Response {
+        let message = match state.config.model_aliases.get(id) {
+            Some(candidates) => format!(
+                "The alias '{id}' has no available model in this tier (candidates: {})",
+                candidates.join(", ")
+            ),
+            None => format!("The model '{id}' does not exist"),
+        };
+        Self::error(StatusCode::NOT_FOUND, message, Some("model_not_found"))
+    }
+
+    fn extract_model(json: &serde_json::Value) -> Option<String> {
         json.get("model")?.as_str().map(String::from)
     }
 
-    pub fn stream(resp: reqwest::Response) -> Response {
+    /// Rejects requests using parameters or input types the target model doesn't
+    /// advertise, instead of letting upstream fail with an opaque error.
+    fn check_supported(
+        state: &SharedState,
+        model: &Model,
+        json: &serde_json::Value,
+    ) -> Option<Response> {
+        let mode = state.config.id_display_mode();
+        let sends_audio = json
+            .get("messages")
+            .and_then(|m| m.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|msg| msg.get("content")?.as_array())
+            .flatten()
+            .any(|part| part.get("type").and_then(|t| t.as_str()) == Some("input_audio"));
+        if sends_audio && !model.supports_audio() {
+            return Some(Self::error(
+                StatusCode::BAD_REQUEST,
+                format!("The model '{}' does not accept audio input", model.display_id(mode)),
+                Some("unsupported_content"),
+            ));
+        }
+
+        let (field, _) = GATED_PARAMS.iter().find(|(field, param)| {
+            json.get(*field).is_some_and(Self::in_use) && !model.has_param(param)
+        })?;
+        Some(Self::error(
+            StatusCode::BAD_REQUEST,
+            format!("The model '{}' does not support '{field}'", model.display_id(mode)),
+            Some("unsupported_parameter"),
+        ))
+    }
+
+    /// Removes `SOFT_PARAMS` the model doesn't support, so it gets the request
+    /// without them instead of an error. Returns whether the body changed.
+    fn drop_unsupported(model: &Model, json: &mut serde_json::Value) -> bool {
+        let Some(obj) = json.as_object_mut() else {
+            return false;
+        };
+        let mut changed = false;
+        for &param in SOFT_PARAMS.iter().filter(|p| !model.has_param(p)) {
+            if obj.remove(param).is_some() {
+                info!("Dropping `{param}` for {}, which doesn't support it", model.id);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Moves `max_tokens` or `max_completion_tokens` to the variant the model
+    /// takes, unless the client sent both.
+    fn rename_max_tokens(model: &Model, json: &mut serde_json::Value) -> bool {
+        let Some(obj) = json.as_object_mut() else {
+            return false;
+        };
+        let wanted = model.max_tokens_param();
+        let other = match wanted {
+            "max_tokens" => "max_completion_tokens",
+            _ => "max_tokens",
+        };
+        if obj.contains_key(wanted) {
+            return false;
+        }
+        let Some(value) = obj.remove(other) else {
+            return false;
+        };
+        obj.insert(wanted.into(), value);
+        true
+    }
+
+    /// Rejects a client `service_tier` outside `SERVICE_TIERS`.
+    fn check_service_tier(json: &serde_json::Value) -> Option<Response> {
+        let tier = json.get("service_tier").filter(|v| !v.is_null())?;
+        if tier.as_str().is_some_and(|t| SERVICE_TIERS.contains(&t)) {
+            return None;
+        }
+        Some(Self::error(
+            StatusCode::BAD_REQUEST,
+            format!("`service_tier` must be one of {} (got {tier})", SERVICE_TIERS.join(", ")),
+            Some("invalid_service_tier"),
+        ))
+    }
+
+    /// Validates OpenRouter's routing controls: `route` must be a known value
+    /// and `num_retries` a non-negative integer.
+    fn check_routing(json: &serde_json::Value) -> Option<Response> {
+        if let Some(route) = json.get("route").filter(|v| !v.is_null()) {
+            if !route.as_str().is_some_and(|r| ROUTES.contains(&r)) {
+                return Some(Self::error(
+                    StatusCode::BAD_REQUEST,
+                    format!("`route` must be one of {} (got {route})", ROUTES.join(", ")),
+                    Some("invalid_route"),
+                ));
+            }
+        }
+        let retries = json.get("num_retries").filter(|v| !v.is_null())?;
+        if retries.is_u64() {
+            return None;
+        }
+        Some(Self::error(
+            StatusCode::BAD_REQUEST,
+            format!("`num_retries` must be a non-negative integer (got {retries})"),
+            Some("invalid_num_retries"),
+        ))
+    }
+
+    /// With `VALIDATE_SAMPLING_PARAMS` set, rejects non-numeric sampling
+    /// fields, and in `reject` mode also ones outside `SAMPLING_RANGES`.
+    fn check_sampling(state: &SharedState, json: &serde_json::Value) -> Option<Response> {
+        let mode = state.config.validate_sampling_params.as_deref()?;
+        SAMPLING_RANGES.iter().find_map(|&(field, min, max)| {
+            let value = json.get(field).filter(|v| !v.is_null())?;
+            let in_range = value.as_f64().is_some_and(|v| (min..=max).contains(&v));
+            if in_range || (value.is_number() && mode == "clamp") {
+                return None;
+            }
+            Some(Self::error(
+                StatusCode::BAD_REQUEST,
+                format!("`{field}` must be a number from {min} to {max} (got {value})"),
+                Some("invalid_sampling_param"),
+            ))
+        })
+    }
+
+    /// Relays an upstream response. `permit` is released only once the body
+    /// has been fully sent, so long streams keep their upstream slot. With a
+    /// `ticket`, `POST /cancel/{id}` ends the body early and drops upstream.
+    /// With `STREAM_IDLE_TIMEOUT_SECS`, an upstream that goes quiet that long
+    /// is dropped, and event streams end with an `error` event saying so.
+    pub fn stream(
+        resp: reqwest::Response,
+        state: &SharedState,
+        permit: UpstreamPermit,
+        ticket: Option<StreamTicket>,
+        hide_reasoning: bool,
+    ) -> Response {
+        use tokio_stream::StreamExt;
+        let filter = &state.config.response_headers;
         let status = resp.status();
         let headers = resp.headers().clone();
-        let body = Body::from_stream(resp.bytes_stream());
+        let is_sse = headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.starts_with("text/event-stream"));
+        let mut size = StreamedBytes::new(state.metrics.clone());
+        let chunks = resp.bytes_stream().map(move |chunk| {
+            let _held = &permit;
+            if let Ok(ref bytes) = chunk {
+                size.add(bytes.len());
+            }
+            chunk
+        });
+        let idle_secs = state.config.stream_idle_timeout_secs;
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let chunks: Pin<Box<dyn Stream<Item = _> + Send>> = match idle_secs {
+            0 => Box::pin(chunks),
+            secs => Box::pin(Self::until_idle(chunks, secs, timed_out.clone())),
+        };
+        let idle_error = tokio_stream::iter([()]).filter_map(move |()| {
+            let event = format!(
+                "data: {}\n\n",
+                serde_json::json!({"error": {
+                    "message": format!("Upstream sent nothing for {idle_secs}s"),
+                    "type": "server_error",
+                    "code": "stream_idle_timeout",
+                }})
+            );
+            let ended = is_sse && timed_out.load(Ordering::Relaxed);
+            ended.then(|| Ok(axum::body::Bytes::from(event)))
+        });
+        let stream_id = ticket.as_ref().map(|t| t.id.clone());
+        let chunks: Pin<Box<dyn Stream<Item = _> + Send>> = match ticket {
+            Some(ticket) => Box::pin(Self::cancellable(chunks, ticket)),
+            None => Box::pin(chunks),
+        };
+        // Compressed streams can't be inspected, so they stay raw.
+        let plain_sse = is_sse && status.is_success() && !headers.contains_key("content-encoding");
+        let validate = state.config.validate_stream && plain_sse;
+        let hide_reasoning = hide_reasoning && plain_sse;
+        let body = if validate || hide_reasoning {
+            let validator = Arc::new(std::sync::Mutex::new(SseValidator {
+                check: validate,
+                hide_reasoning,
+                ..SseValidator::default()
+            }));
+            let at_end = validator.clone();
+            let events = chunks.map(move |chunk| chunk.map(|b| validator.lock().unwrap().feed(&b)));
+            let tail = tokio_stream::iter([()])
+                .map(move |()| Ok::<_, reqwest::Error>(at_end.lock().unwrap().finish()));
+            Body::from_stream(events.chain(idle_error).chain(tail))
+        } else {
+            Body::from_stream(chunks.chain(idle_error))
+        };
 
         let mut builder = Response::builder().status(status.as_u16());
         for (name, value) in &headers {
-            if !matches!(name.as_str(), "transfer-encoding" | "connection") {
+            if Self::is_relayed(name.as_str(), filter) && name != PROVIDER_HEADER {
                 builder = builder.header(name, value);
             }
         }
+        // No chunk has arrived yet to read the provider from, so it's only
+        // known when upstream names it in a header.
+        if let Some(provider) = headers.get(PROVIDER_HEADER) {
+            builder = builder.header(PROVIDER_HEADER, provider);
+        }
+        // Stop reverse proxies such as nginx from holding back SSE frames.
+        if is_sse {
+            builder = builder.header("x-accel-buffering", "no");
+        }
+        if let Some(id) = stream_id {
+            builder = builder.header(STREAM_ID_HEADER, id);
+        }
 
         builder.body(body).unwrap_or_else(|_| {
             Self::error(
@@ -211,6 +1815,54 @@ impl Proxy {
         })
     }
 
+    /// Ends `chunks`, dropping upstream, once nothing arrives for `secs`.
+    fn until_idle<S: Stream>(
+        chunks: S,
+        secs: u64,
+        timed_out: Arc<AtomicBool>,
+    ) -> impl Stream<Item = S::Item> {
+        use tokio_stream::StreamExt;
+        chunks.timeout(Duration::from_secs(secs)).map_while(move |chunk| match chunk {
+            Ok(chunk) => Some(chunk),
+            Err(_) => {
+                warn!("Upstream stream idle for {secs}s, closing it");
+                timed_out.store(true, Ordering::Relaxed);
+                None
+            }
+        })
+    }
+
+    /// Moves `chunks` through a task that stops reading (dropping upstream)
+    /// once the ticket is cancelled or the client goes away.
+    fn cancellable<S>(chunks: S, ticket: StreamTicket) -> ReceiverStream<S::Item>
+    where
+        S: Stream + Send + 'static,
+        S::Item: Send,
+    {
+        use tokio_stream::StreamExt;
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            let relay = async {
+                tokio::pin!(chunks);
+                while let Some(chunk) = chunks.next().await {
+                    if tx.send(chunk).await.is_err() {
+                        break;
+                    }
+                }
+            };
+            tokio::select! {
+                () = relay => {}
+                () = tx.closed() => {}
+                () = ticket.cancel.notified() => info!("Stream {} cancelled", ticket.id),
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+
+    fn is_relayed(name: &str, filter: &HeaderFilter) -> bool {
+        !matches!(name, "transfer-encoding" | "connection") && filter.permits(name)
+    }
+
     pub fn error(status: StatusCode, message: String, code: Option<&str>) -> Response {
         let error_type = match status.as_u16() {
             401 => "authentication_error",
@@ -234,3 +1886,587 @@ impl Proxy {
             .unwrap()
     }
 }
+
+/// Re-frames a chat-completions SSE stream into whole events. With `check`
+/// (`VALIDATE_STREAM`) malformed `data:` payloads are logged and a missing
+/// terminal `[DONE]` is added when upstream ends; with `hide_reasoning`
+/// reasoning text is stripped from each chunk.
+#[derive(Default)]
+struct SseValidator {
+    pending: Vec<u8>,
+    saw_done: bool,
+    check: bool,
+    hide_reasoning: bool,
+}
+
+impl SseValidator {
+    /// The complete events available after `chunk`; a trailing partial event
+    /// is held back until the rest arrives.
+    fn feed(&mut self, chunk: &[u8]) -> axum::body::Bytes {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = Self::events_end(&self.pending) else {
+            return axum::body::Bytes::new();
+        };
+        let complete: Vec<u8> = self.pending.drain(..end).collect();
+        self.relay(&complete)
+    }
+
+    /// Ends the stream. When checking, an unterminated final event is dropped
+    /// and `[DONE]` supplied; otherwise what's left is passed on.
+    fn finish(&mut self) -> axum::body::Bytes {
+        if !self.check {
+            let rest = std::mem::take(&mut self.pending);
+            return self.relay(&rest);
+        }
+        if !self.pending.iter().all(u8::is_ascii_whitespace) {
+            warn!("Dropping incomplete final SSE event ({} bytes)", self.pending.len());
+        }
+        if self.saw_done {
+            return axum::body::Bytes::new();
+        }
+        warn!("Upstream stream ended without [DONE]; adding it");
+        axum::body::Bytes::from_static(b"data: [DONE]\n\n")
+    }
+
+    /// Inspects (and with `hide_reasoning`, rewrites) each `data:` line.
+    fn relay(&mut self, events: &[u8]) -> axum::body::Bytes {
+        let mut out = Vec::with_capacity(events.len());
+        for (line, ending) in Self::lines(events) {
+            let text = String::from_utf8_lossy(line);
+            if let Some(data) = text.strip_prefix("data:").map(str::trim) {
+                if data == "[DONE]" {
+                    self.saw_done = true;
+                } else {
+                    match serde_json::from_str::<serde_json::Value>(data) {
+                        Ok(mut chunk) if self.hide_reasoning => {
+                            if Proxy::strip_reasoning(&mut chunk) {
+                                out.extend_from_slice(format!("data: {chunk}").as_bytes());
+                                out.extend_from_slice(ending);
+                                continue;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(_) if self.check => {
+                            let snippet: String = data.chars().take(200).collect();
+                            warn!("Malformed upstream SSE chunk: {snippet}");
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+            out.extend_from_slice(line);
+            out.extend_from_slice(ending);
+        }
+        axum::body::Bytes::from(out)
+    }
+
+    /// Splits `buf` into `(line, line ending)` pairs, with lines ending in
+    /// `\n`, `\r\n` or `\r` as the SSE spec allows. A final unterminated line
+    /// has an empty ending.
+    fn lines(buf: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut lines = Vec::new();
+        let (mut start, mut i) = (0, 0);
+        while i < buf.len() {
+            let len = match buf[i] {
+                b'\n' => 1,
+                b'\r' if buf.get(i + 1) == Some(&b'\n') => 2,
+                b'\r' => 1,
+                _ => 0,
+            };
+            if len == 0 {
+                i += 1;
+                continue;
+            }
+            lines.push((&buf[start..i], &buf[i..i + len]));
+            i += len;
+            start = i;
+        }
+        if start < buf.len() {
+            lines.push((&buf[start..], &buf[buf.len()..]));
+        }
+        lines
+    }
+
+    /// Where the last complete event in `buf` ends: just past its last blank line.
+    fn events_end(buf: &[u8]) -> Option<usize> {
+        let mut pos = 0;
+        let mut end = None;
+        for (line, ending) in Self::lines(buf) {
+            pos += line.len() + ending.len();
+            if line.is_empty() && !ending.is_empty() {
+                end = Some(pos);
+            }
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::state::AppState;
+    use serde_json::json;
+
+    fn state(configure: impl FnOnce(&mut Config)) -> SharedState {
+        let mut config = Config::from_env();
+        configure(&mut config);
+        AppState::new(config).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    fn roles_and_content(messages: &serde_json::Value) -> Vec<(String, String)> {
+        let messages = messages.as_array().unwrap();
+        let text = |m: &serde_json::Value, key| m[key].as_str().unwrap_or_default().to_owned();
+        messages.iter().map(|m| (text(m, "role"), text(m, "content"))).collect()
+    }
+
+    #[test]
+    fn sse_validator_splits_on_every_line_ending() {
+        for sep in ["\n\n", "\r\n\r\n", "\r\r"] {
+            let mut v = SseValidator {
+                check: true,
+                ..SseValidator::default()
+            };
+            let first = format!("data: {{\"a\":1}}{sep}data: {{\"b\"");
+            assert_eq!(v.feed(first.as_bytes()), format!("data: {{\"a\":1}}{sep}"));
+            let rest = format!(":2}}{sep}data: [DONE]{sep}");
+            assert_eq!(v.feed(rest.as_bytes()), format!("data: {{\"b\":2}}{sep}data: [DONE]{sep}"));
+            assert!(v.finish().is_empty());
+        }
+    }
+
+    #[test]
+    fn sse_validator_holds_partial_events_and_adds_done() {
+        let mut v = SseValidator {
+            check: true,
+            ..SseValidator::default()
+        };
+        assert!(v.feed(b"data: {\"a\":1}\r\n").is_empty());
+        assert_eq!(v.feed(b"\r\n"), "data: {\"a\":1}\r\n\r\n");
+        assert!(v.feed(b"data: {\"partial").is_empty());
+        assert_eq!(v.finish(), "data: [DONE]\n\n");
+    }
+
+    #[test]
+    fn strip_reasoning_keeps_content_and_usage() {
+        let mut reply = serde_json::json!({
+            "choices": [{"message": {
+                "role": "assistant",
+                "content": "4",
+                "reasoning": "2 + 2 is 4",
+                "reasoning_details": [{"type": "reasoning.text", "text": "2 + 2 is 4"}]
+            }}],
+            "usage": {"completion_tokens": 9, "completion_tokens_details": {"reasoning_tokens": 8}}
+        });
+        assert!(Proxy::strip_reasoning(&mut reply));
+        assert_eq!(reply["choices"][0]["message"], serde_json::json!({
+            "role": "assistant",
+            "content": "4"
+        }));
+        assert_eq!(reply["usage"]["completion_tokens_details"]["reasoning_tokens"], 8);
+        assert!(!Proxy::strip_reasoning(&mut reply));
+    }
+
+    #[test]
+    fn sse_validator_hides_reasoning_deltas() {
+        let mut v = SseValidator {
+            hide_reasoning: true,
+            ..SseValidator::default()
+        };
+        let events = v.feed(
+            b"data: {\"choices\":[{\"delta\":{\"reasoning\":\"hmm\"}}]}\r\n\r\n\
+              data: {\"choices\":[{\"delta\":{\"content\":\"4\"}}]}\r\n\r\n",
+        );
+        assert_eq!(
+            events,
+            "data: {\"choices\":[{\"delta\":{}}]}\r\n\r\n\
+             data: {\"choices\":[{\"delta\":{\"content\":\"4\"}}]}\r\n\r\n"
+        );
+        // Without `check`, an unterminated tail is still passed on.
+        assert_eq!(v.finish(), "");
+        v.feed(b"data: [DONE]");
+        assert_eq!(v.finish(), "data: [DONE]");
+    }
+
+    #[test]
+    fn system_prompt_leads_chat_messages() {
+        let mut body = json!({
+            "model": "m",
+            "messages": [
+                {"role": "system", "content": "client"},
+                {"role": "user", "content": "hi"}
+            ]
+        });
+        assert!(Proxy::prepend_system_prompt(&mut body, "policy"));
+        assert_eq!(roles_and_content(&body["messages"]), [
+            ("system".to_owned(), "policy".to_owned()),
+            ("system".to_owned(), "client".to_owned()),
+            ("user".to_owned(), "hi".to_owned()),
+        ]);
+        assert!(!Proxy::prepend_system_prompt(&mut json!({"prompt": "hi"}), "policy"));
+    }
+
+    #[test]
+    fn system_prompt_leads_translated_responses_messages() {
+        let target: Model = serde_json::from_value(json!({"id": "m", "name": "M"})).unwrap();
+        let body = json!({
+            "model": "m",
+            "instructions": "instructions",
+            "input": [
+                {"role": "developer", "content": "developer"},
+                {"role": "user", "content": "hi"}
+            ]
+        });
+        let mut req = responses::translate_request(&body, &target, &[]).unwrap();
+        assert!(Proxy::prepend_system_prompt(&mut req.cc_body, "policy"));
+        assert_eq!(roles_and_content(&req.cc_body["messages"]), [
+            ("system".to_owned(), "policy".to_owned()),
+            ("system".to_owned(), "instructions".to_owned()),
+            ("system".to_owned(), "developer".to_owned()),
+            ("user".to_owned(), "hi".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn system_prompt_header_overrides_config() {
+        let configured = state(|c| c.inject_system_prompt = Some("policy".into()));
+        let prompt = |pairs| Proxy::system_prompt(&configured, &headers(pairs));
+        assert_eq!(prompt(&[]).as_deref(), Some("policy"));
+        assert_eq!(prompt(&[("x-system-prompt", "persona")]).as_deref(), Some("persona"));
+        assert_eq!(prompt(&[("x-system-prompt", "none")]), None);
+        assert_eq!(prompt(&[("x-system-prompt", "")]).as_deref(), Some("policy"));
+
+        let unset = state(|c| c.inject_system_prompt = None);
+        assert_eq!(Proxy::system_prompt(&unset, &headers(&[])), None);
+    }
+
+    fn model_with(params: &[&str]) -> Model {
+        serde_json::from_value(json!({"id": "m", "name": "M", "supported_parameters": params}))
+            .unwrap()
+    }
+
+    fn models(ids: &[&str]) -> Vec<Model> {
+        ids.iter()
+            .map(|id| serde_json::from_value(json!({"id": id, "name": id})).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn alias_candidates_expand_globs_in_tier_order() {
+        let state = state(|c| {
+            c.model_aliases = [(
+                "coder".to_owned(),
+                vec!["qwen/*:free".to_owned(), "meta-llama/llama-3.3-70b-instruct:free".to_owned()],
+            )]
+            .into();
+        });
+        let tier = models(&[
+            "qwen/qwen3-coder:free",
+            "google/gemma-3-27b-it:free",
+            "qwen/qwen3-32b",
+            "qwen/qwen3-32b:free",
+            "meta-llama/llama-3.3-70b-instruct:free",
+        ]);
+        let ids = |found: Option<Vec<&Model>>| -> Vec<String> {
+            found.unwrap().into_iter().map(|m| m.id.clone()).collect()
+        };
+        assert_eq!(ids(Proxy::alias_candidates(&state, &tier, "coder", &[])), [
+            "qwen/qwen3-coder:free",
+            "qwen/qwen3-32b:free",
+            "meta-llama/llama-3.3-70b-instruct:free",
+        ]);
+        assert!(Proxy::alias_candidates(&state, &tier, "qwen/*:free", &[]).is_none());
+
+        let no_match = models(&["google/gemma-3-27b-it:free"]);
+        assert!(ids(Proxy::alias_candidates(&state, &no_match, "coder", &[])).is_empty());
+    }
+
+    #[test]
+    fn resolve_fallbacks_rewrites_display_ids() {
+        let state = state(|c| {
+            c.id_display_mode = "short".into();
+            let coder = vec!["qwen/qwen3-coder:free".to_owned()];
+            c.model_aliases = [("coder".to_owned(), coder)].into();
+        });
+        let tier = models(&["qwen/qwen3-coder:free", "google/gemma-3-27b-it:free"]);
+        let resolve = |body| Proxy::resolve_fallbacks(&state, &tier, &body);
+        assert_eq!(resolve(json!({"model": "coder"})), Ok(None));
+        assert_eq!(
+            resolve(json!({"models": ["gemma-3-27b-it", "coder", "google/gemma-3-27b-it:free"]})),
+            Ok(Some(vec![
+                "google/gemma-3-27b-it:free".to_owned(),
+                "qwen/qwen3-coder:free".to_owned(),
+                "google/gemma-3-27b-it:free".to_owned(),
+            ]))
+        );
+    }
+
+    #[test]
+    fn resolve_fallbacks_reports_first_invalid_entry() {
+        let state = state(|c| c.id_display_mode = "short".into());
+        let tier = models(&["qwen/qwen3-coder:free"]);
+        let resolve = |body| Proxy::resolve_fallbacks(&state, &tier, &body);
+        assert_eq!(
+            resolve(json!({"models": ["qwen3-coder", "openai/gpt-4o", "missing"]})),
+            Err("openai/gpt-4o".to_owned())
+        );
+        assert_eq!(resolve(json!({"models": ["qwen3-coder", 7]})), Err("7".to_owned()));
+        assert_eq!(resolve(json!({"models": []})), Ok(Some(vec![])));
+    }
+
+    #[test]
+    fn prediction_is_dropped_for_chat_models_without_it() {
+        let prediction = json!({"type": "content", "content": "fn main() {}"});
+        let mut body = json!({"model": "m", "messages": [], "prediction": prediction});
+        assert!(!Proxy::drop_unsupported(&model_with(&["prediction"]), &mut body));
+        assert_eq!(body["prediction"], prediction);
+        assert!(Proxy::drop_unsupported(&model_with(&["tools"]), &mut body));
+        assert!(body.get("prediction").is_none());
+
+        assert!(model_with(&["prediction"]).capabilities().prediction);
+        assert!(!model_with(&["tools"]).capabilities().prediction);
+    }
+
+    #[test]
+    fn sampling_params_are_dropped_for_chat_models_without_them() {
+        for param in ["top_k", "min_p", "top_a", "repetition_penalty"] {
+            let mut body = json!({"model": "m", "messages": []});
+            body[param] = json!(1);
+            assert!(!Proxy::drop_unsupported(&model_with(&[param]), &mut body), "{param}");
+            assert_eq!(body[param], 1, "{param}");
+            assert!(Proxy::drop_unsupported(&model_with(&["temperature"]), &mut body), "{param}");
+            assert!(body.get(param).is_none(), "{param}");
+        }
+    }
+
+    #[test]
+    fn required_params_come_from_the_body() {
+        let required = |body| Proxy::required_params(&body);
+        assert!(required(json!({"messages": [], "tools": []})).is_empty());
+        assert_eq!(required(json!({"tools": [{"type": "function"}], "logprobs": true})), [
+            "tools", "logprobs"
+        ]);
+        assert!(required(json!({"logprobs": false, "response_format": {"type": "text"}}))
+            .is_empty());
+        let format = json!({"type": "json_schema", "schema": {}});
+        assert_eq!(required(json!({"text": {"format": format}})), ["response_format"]);
+        assert_eq!(required(json!({"response_format": format, "text": {"format": format}})), [
+            "response_format"
+        ]);
+    }
+
+    #[test]
+    fn alias_routing_skips_candidates_without_required_params() {
+        let state = state(|c| {
+            let coder = vec!["vendor/plain:free".to_owned(), "vendor/tooled:free".to_owned()];
+            c.model_aliases = [("coder".to_owned(), coder)].into();
+            c.sticky_aliases = false;
+        });
+        let params = [("vendor/plain:free", vec![]), ("vendor/tooled:free", vec!["tools"])];
+        let tier: Vec<Model> = params
+            .into_iter()
+            .map(|(id, params)| {
+                let model = json!({"id": id, "name": id, "supported_parameters": params});
+                serde_json::from_value(model).unwrap()
+            })
+            .collect();
+        let chain = |required: &[&str]| -> Vec<String> {
+            let chain = Proxy::failover_chain(&state, &tier, "coder", None, required);
+            chain.into_iter().map(|m| m.id.clone()).collect()
+        };
+        assert_eq!(chain(&[]), ["vendor/plain:free", "vendor/tooled:free"]);
+        assert_eq!(chain(&["tools"]), ["vendor/tooled:free"]);
+        // With no capable candidate the first is kept, to fail as unsupported.
+        assert_eq!(chain(&["logprobs"]), ["vendor/plain:free", "vendor/tooled:free"]);
+        let resolved = Proxy::resolve_for(&state, &tier, "coder", None, &["tools"]);
+        assert_eq!(resolved.map(|m| m.id.as_str()), Some("vendor/tooled:free"));
+    }
+
+    #[test]
+    fn upstream_url_stays_under_the_base() {
+        let base = "https://openrouter.ai/api/v1";
+        let url = |path| Proxy::upstream_url(base, path);
+        assert_eq!(
+            url("/chat/completions?x=1").as_deref(),
+            Some("https://openrouter.ai/api/v1/chat/completions?x=1")
+        );
+        assert_eq!(
+            url("/models/qwen/qwen3-coder:free/endpoints").as_deref(),
+            Some("https://openrouter.ai/api/v1/models/qwen/qwen3-coder:free/endpoints")
+        );
+        // Dots in the query are not path segments.
+        assert!(url("/models?q=../x").is_some());
+        for escape in [
+            "chat/completions",
+            "/../keys",
+            "/models/./x",
+            "//evil.example/x",
+            "/%2e%2e/keys",
+            "/%2E./keys",
+            "/models%2f..%2fkeys",
+        ] {
+            assert_eq!(url(escape), None, "{escape}");
+        }
+    }
+
+    #[tokio::test]
+    async fn ambiguous_short_id_is_a_conflict_when_enabled() {
+        let tier = models(&[
+            "nvidia/llama-3.3-70b-instruct:free",
+            "meta-llama/llama-3.3-70b-instruct:free",
+        ]);
+        for (conflict, status) in [(true, StatusCode::CONFLICT), (false, StatusCode::OK)] {
+            let state = state(|c| {
+                c.id_display_mode = "short".into();
+                c.provider_preference = Vec::new();
+                c.ambiguous_id_conflict = conflict;
+            });
+            state.cache.write().await.free_models = Arc::new(tier.clone());
+            let resp = Proxy::get_model(Tier::Free, &state, "llama-3.3-70b-instruct").await;
+            assert_eq!(resp.status(), status);
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            if conflict {
+                let message = body["error"]["message"].as_str().unwrap();
+                assert!(message.contains("meta-llama/llama-3.3-70b-instruct:free"), "{message}");
+                assert!(message.contains("nvidia/llama-3.3-70b-instruct:free"), "{message}");
+            } else {
+                assert_eq!(body["owned_by"], "meta-llama");
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_bounds_are_inclusive_when_rejecting() {
+        let state = state(|c| c.validate_sampling_params = Some("reject".into()));
+        let status = |body| Proxy::check_sampling(&state, &body).map(|r| r.status());
+        for ok in [
+            json!({"temperature": 0, "top_p": 0}),
+            json!({"temperature": 2, "top_p": 1}),
+            json!({"temperature": 2.0, "top_p": 0.5}),
+            json!({"temperature": null}),
+        ] {
+            assert_eq!(status(ok.clone()), None, "{ok}");
+        }
+        for bad in [
+            json!({"temperature": -0.01}),
+            json!({"temperature": 2.01}),
+            json!({"top_p": -1}),
+            json!({"top_p": 1.0001}),
+            json!({"temperature": "hot"}),
+        ] {
+            assert_eq!(status(bad.clone()), Some(StatusCode::BAD_REQUEST), "{bad}");
+        }
+    }
+
+    #[test]
+    fn sampling_out_of_range_passes_when_clamping_or_unset() {
+        let clamp = state(|c| c.validate_sampling_params = Some("clamp".into()));
+        assert!(Proxy::check_sampling(&clamp, &json!({"temperature": 3, "top_p": -1})).is_none());
+        let rejected = Proxy::check_sampling(&clamp, &json!({"top_p": "high"})).map(|r| r.status());
+        assert_eq!(rejected, Some(StatusCode::BAD_REQUEST));
+
+        let unset = state(|c| c.validate_sampling_params = None);
+        assert!(Proxy::check_sampling(&unset, &json!({"temperature": "hot"})).is_none());
+    }
+
+    #[test]
+    fn max_tokens_is_renamed_to_the_models_field() {
+        let newer = model_with(&["max_completion_tokens"]);
+        let older = model_with(&["max_tokens"]);
+        let renamed = |model: &Model, mut body: serde_json::Value| {
+            (Proxy::rename_max_tokens(model, &mut body), body)
+        };
+        assert_eq!(
+            renamed(&newer, json!({"max_tokens": 64})),
+            (true, json!({"max_completion_tokens": 64}))
+        );
+        assert_eq!(
+            renamed(&older, json!({"max_completion_tokens": 64})),
+            (true, json!({"max_tokens": 64}))
+        );
+        for body in [json!({"max_tokens": 64}), json!({})] {
+            assert_eq!(renamed(&older, body.clone()), (false, body));
+        }
+        let both = json!({"max_tokens": 64, "max_completion_tokens": 32});
+        assert_eq!(renamed(&newer, both.clone()), (false, both.clone()));
+        assert_eq!(renamed(&older, both.clone()), (false, both));
+    }
+
+    #[tokio::test]
+    async fn idle_chat_stream_ends_with_an_error_event() {
+        let state = state(|c| {
+            c.stream_idle_timeout_secs = 1;
+            c.validate_stream = false;
+        });
+        let (chunks, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(1);
+        let upstream: reqwest::Response = axum::http::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(reqwest::Body::wrap_stream(ReceiverStream::new(rx)))
+            .unwrap()
+            .into();
+        let delta = r#"data: {"choices":[{"index":0,"delta":{"content":"Hel"}}]}"#;
+        chunks.send(Ok(format!("{delta}\n\n"))).await.unwrap();
+        let permit = state.upstream_limiter.acquire().await.unwrap();
+        let resp = Proxy::stream(upstream, &state, permit, None, false);
+        let body = tokio::time::timeout(Duration::from_secs(5), resp.into_body().collect())
+            .await
+            .expect("idle stream was not closed")
+            .unwrap()
+            .to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        let (first, last) = body.trim_end().split_once("\n\n").unwrap();
+        assert_eq!(first, delta);
+        let error: serde_json::Value = serde_json::from_str(&last["data: ".len()..]).unwrap();
+        assert_eq!(error["error"]["code"], "stream_idle_timeout");
+        // Upstream was dropped rather than left open.
+        assert!(chunks.is_closed());
+    }
+
+    #[test]
+    fn stored_responses_need_the_callers_own_key() {
+        let state = state(|c| {
+            c.response_store_size = 10;
+            c.free_api_key = Some("server".into());
+        });
+        let own = headers(&[("authorization", "Bearer mine")]);
+        let owner = Proxy::own_credentials(&state, &own).unwrap();
+        state.stored_responses.insert(owner, json!({"id": "resp_1"}));
+
+        assert_eq!(Proxy::get_response(&state, &own, "resp_1").status(), StatusCode::OK);
+        for other in [headers(&[]), headers(&[("authorization", "Bearer server")])] {
+            assert!(Proxy::own_credentials(&state, &other).is_none());
+            let resp = Proxy::get_response(&state, &other, "resp_1");
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            let resp = Proxy::delete_response(&state, &other, "resp_1");
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(Proxy::delete_response(&state, &own, "resp_1").status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn streams_pass_on_an_upstream_provider_header() {
+        let state = state(|c| c.response_headers.allow = Some(vec![]));
+        for provider in [Some("MockAI"), None] {
+            let mut upstream = axum::http::Response::builder()
+                .header("content-type", "text/event-stream")
+                .header("x-other", "dropped");
+            if let Some(provider) = provider {
+                upstream = upstream.header(PROVIDER_HEADER, provider);
+            }
+            let upstream: reqwest::Response = upstream.body("data: [DONE]\n\n").unwrap().into();
+            let permit = state.upstream_limiter.acquire().await.unwrap();
+            let resp = Proxy::stream(upstream, &state, permit, None, false);
+            let relayed = resp.headers().get(PROVIDER_HEADER).map(|v| v.to_str().unwrap());
+            assert_eq!(relayed, provider);
+            assert!(!resp.headers().contains_key("x-other"));
+        }
+    }
+}
@@ -1,15 +1,27 @@
+use super::proxy::Proxy;
+use crate::cache::ResponseStore;
+use crate::config::Config;
+use crate::health::LiveHealth;
+use crate::model::{Model, SOFT_PARAMS};
+use crate::ratelimit::UpstreamPermit;
 use axum::body::Body;
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::Response;
 use reqwest::Client;
 use serde_json::{json, Value};
 use std::sync::atomic::{AtomicU64, Ordering};
-use tracing::warn;
-
-const UPSTREAM: &str = "https://openrouter.ai/api/v1";
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendTimeoutError;
+use tracing::{info, warn};
 
 static SEQ: AtomicU64 = AtomicU64::new(1);
 
+/// Translated SSE frames waiting for the client. Each frame is yielded to the
+/// body as soon as it's sent, so this only bounds how far translation may run
+/// ahead of a slow reader; keeping it small keeps memory and lag low.
+const STREAM_CHANNEL_CAPACITY: usize = 16;
+
 fn next_id(prefix: &str) -> String {
     let n = SEQ.fetch_add(1, Ordering::Relaxed);
     let ts = std::time::SystemTime::now()
@@ -37,9 +49,22 @@ pub struct TranslatedRequest {
     pub tool_choice: Value,
     pub parallel_tool_calls: Value,
     pub is_stream: bool,
+    /// Tool calls beyond this many are dropped and the response is incomplete.
+    pub max_tool_calls: Option<usize>,
 }
 
-pub fn translate_request(body: &Value) -> Result<TranslatedRequest, String> {
+/// Translates a Responses request into a Chat Completions body. Any JSON shape
+/// is accepted without panicking: unknown item and part types are skipped, and
+/// on success `cc_body.messages` is non-empty and every message role is one of
+/// `CHAT_ROLES` or `tool`. Anything that can't meet that is an `Err`.
+///
+/// Top-level fields named in `passthrough` and every key of an `extra_body`
+/// object are copied verbatim, except those the translation itself owns.
+pub fn translate_request(
+    body: &Value,
+    target: &Model,
+    passthrough: &[String],
+) -> Result<TranslatedRequest, String> {
     let model = body
         .get("model")
         .and_then(|v| v.as_str())
@@ -50,8 +75,14 @@ pub fn translate_request(body: &Value) -> Result<TranslatedRequest, String> {
 
     let mut messages: Vec<Value> = Vec::new();
 
+    // Top-level `instructions` always lead as a system message; developer/system
+    // items from `input` follow in their original order.
     if let Some(instr) = body.get("instructions").and_then(|v| v.as_str()) {
-        messages.push(json!({"role": "developer", "content": instr}));
+        messages.push(json!({"role": "system", "content": instr}));
+    }
+
+    if has_audio_input(body.get("input")) && !target.supports_audio() {
+        return Err(format!("The model '{}' does not accept audio input", target.id));
     }
 
     match body.get("input") {
@@ -60,11 +91,14 @@ pub fn translate_request(body: &Value) -> Result<TranslatedRequest, String> {
         }
         Some(Value::Array(items)) => {
             for item in items {
-                translate_input_item(item, &mut messages);
+                translate_input_item(item, &mut messages)?;
             }
         }
         _ => {}
     }
+    if messages.is_empty() {
+        return Err("`input` must contain at least one message".into());
+    }
 
     let mut cc_tools: Vec<Value> = Vec::new();
     if let Some(Value::Array(tools)) = body.get("tools") {
@@ -100,7 +134,7 @@ pub fn translate_request(body: &Value) -> Result<TranslatedRequest, String> {
         cc["top_p"] = v.clone();
     }
     if let Some(v) = body.get("max_output_tokens") {
-        cc["max_tokens"] = v.clone();
+        cc[target.max_tokens_param()] = v.clone();
     }
     if let Some(v) = body.get("tool_choice") {
         cc["tool_choice"] = translate_tool_choice(v);
@@ -108,6 +142,45 @@ pub fn translate_request(body: &Value) -> Result<TranslatedRequest, String> {
     if let Some(v) = body.get("parallel_tool_calls") {
         cc["parallel_tool_calls"] = v.clone();
     }
+    for &param in SOFT_PARAMS {
+        let Some(v) = body.get(param).filter(|v| !v.is_null()) else {
+            continue;
+        };
+        if target.has_param(param) {
+            cc[param] = v.clone();
+        } else {
+            info!("Dropping `{param}` for {}, which doesn't support it", target.id);
+        }
+    }
+    for field in ["service_tier", "route", "num_retries"] {
+        if let Some(v) = body.get(field).filter(|v| !v.is_null()) {
+            cc[field] = v.clone();
+        }
+    }
+    // OpenRouter's unified `reasoning` object takes `effort` like Responses,
+    // plus `exclude` and `max_tokens`; `summary` has no equivalent.
+    if let Some(Value::Object(r)) = body.get("reasoning") {
+        let mut reasoning = r.clone();
+        reasoning.remove("summary");
+        if !reasoning.is_empty() {
+            cc["reasoning"] = Value::Object(reasoning);
+        }
+    }
+    if let Some(v) = body.get("stop").filter(|v| !v.is_null()) {
+        let valid = match v {
+            Value::String(_) => true,
+            Value::Array(items) => items.iter().all(Value::is_string),
+            _ => false,
+        };
+        if !valid {
+            return Err("`stop` must be a string or an array of strings".into());
+        }
+        if target.has_param("stop") {
+            cc["stop"] = v.clone();
+        } else {
+            info!("Dropping `stop` for {}, which doesn't support it", target.id);
+        }
+    }
     if let Some(v) = body.get("text") {
         if let Some(fmt) = v.get("format") {
             let fmt_type = fmt.get("type").and_then(|t| t.as_str()).unwrap_or("text");
@@ -130,8 +203,27 @@ pub fn translate_request(body: &Value) -> Result<TranslatedRequest, String> {
         }
     }
 
+    for field in passthrough {
+        if cc.get(field).is_none() {
+            if let Some(v) = body.get(field).filter(|v| !v.is_null()) {
+                cc[field.as_str()] = v.clone();
+            }
+        }
+    }
+    if let Some(Value::Object(extra)) = body.get("extra_body") {
+        for (key, v) in extra {
+            if RESERVED_FIELDS.contains(&key.as_str()) {
+                info!("Ignoring reserved `extra_body.{key}`");
+            } else {
+                cc[key.as_str()] = v.clone();
+            }
+        }
+    }
+
     if is_stream {
         cc["stream"] = json!(true);
+        // Without this OpenRouter sends no usage chunk and totals stay at zero.
+        cc["stream_options"] = json!({"include_usage": true});
     }
 
     Ok(TranslatedRequest {
@@ -148,80 +240,189 @@ pub fn translate_request(body: &Value) -> Result<TranslatedRequest, String> {
             .cloned()
             .unwrap_or(json!(true)),
         is_stream,
+        max_tool_calls: body
+            .get("max_tool_calls")
+            .and_then(|v| v.as_u64())
+            .map(|n| n as usize),
+    })
+}
+
+/// Chat Completions fields that `extra_body` may not override: they carry the
+/// translated conversation and decide how the response is read back.
+const RESERVED_FIELDS: &[&str] = &["model", "messages", "stream", "stream_options"];
+
+/// Responses roles that Chat Completions spells differently. Every input shape
+/// goes through `chat_role`, so new mappings only need adding here.
+const ROLE_MAP: &[(&str, &str)] = &[("developer", "system")];
+
+/// Roles a translated message item may carry once `chat_role` has mapped it.
+const CHAT_ROLES: &[&str] = &["system", "user", "assistant"];
+
+fn chat_role(role: &str) -> &str {
+    ROLE_MAP
+        .iter()
+        .find(|(from, _)| *from == role)
+        .map_or(role, |&(_, to)| to)
+}
+
+/// Whether any message in a Responses `input` carries an `input_audio` part.
+fn has_audio_input(input: Option<&Value>) -> bool {
+    let Some(Value::Array(items)) = input else {
+        return false;
+    };
+    items.iter().any(|item| match item.get("content") {
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .any(|p| p.get("type").and_then(|t| t.as_str()) == Some("input_audio")),
+        _ => false,
     })
 }
 
-fn translate_input_item(item: &Value, messages: &mut Vec<Value>) {
+/// Tool-call `arguments` as the JSON text the spec requires. Some providers
+/// send a JSON object instead, which is serialized; null counts as missing.
+fn arguments_text(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::String(text) => Some(text.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+fn translate_input_item(item: &Value, messages: &mut Vec<Value>) -> Result<(), String> {
     let item_type = item.get("type").and_then(|v| v.as_str()).unwrap_or("");
     match item_type {
-        "message" => {
-            let role = item
-                .get("role")
-                .and_then(|v| v.as_str())
-                .unwrap_or("user");
-            let cc_role = match role {
-                "developer" => "system",
-                other => other,
-            };
-
-            if let Some(Value::Array(content_parts)) = item.get("content") {
-                let mut cc_content: Vec<Value> = Vec::new();
-                for part in content_parts {
-                    let ptype = part.get("type").and_then(|v| v.as_str()).unwrap_or("");
-                    match ptype {
-                        "input_text" => {
-                            cc_content.push(json!({
-                                "type": "text",
-                                "text": part.get("text").unwrap_or(&Value::Null)
-                            }));
-                        }
-                        "input_image" => {
-                            if let Some(url) = part.get("image_url").and_then(|v| v.as_str()) {
-                                cc_content.push(json!({
-                                    "type": "image_url",
-                                    "image_url": {"url": url}
-                                }));
-                            }
-                        }
-                        _ => {
-                            if let Some(text) = part.get("text") {
-                                cc_content.push(json!({
-                                    "type": "text",
-                                    "text": text
-                                }));
-                            }
-                        }
-                    }
+        "message" => translate_message(item, messages)?,
+        // Easy-input messages (`{"role", "content"}` without a type) are
+        // shorthand for the same thing.
+        "" if item.get("role").is_some_and(Value::is_string) => translate_message(item, messages)?,
+        "function_call" => {
+            let arguments = arguments_text(item.get("arguments")).unwrap_or_else(|| "{}".into());
+            let call = json!({
+                "id": item.get("call_id").unwrap_or(&Value::Null),
+                "type": "function",
+                "function": {
+                    "name": item.get("name").unwrap_or(&Value::Null),
+                    "arguments": arguments
                 }
-                if cc_content.len() == 1
-                    && cc_content[0].get("type").and_then(|v| v.as_str()) == Some("text")
-                {
-                    messages.push(json!({
-                        "role": cc_role,
-                        "content": cc_content[0].get("text").unwrap_or(&Value::Null)
-                    }));
-                    return;
+            });
+            // Parallel calls, and text the model wrote alongside them, belong to
+            // one assistant turn, which must come right before their outputs.
+            match messages.last_mut() {
+                Some(last) if last.get("role").and_then(|r| r.as_str()) == Some("assistant") => {
+                    match last.get_mut("tool_calls").and_then(Value::as_array_mut) {
+                        Some(calls) => calls.push(call),
+                        None => last["tool_calls"] = json!([call]),
+                    }
                 }
-                messages.push(json!({"role": cc_role, "content": cc_content}));
-            } else if let Some(Value::String(text)) = item.get("content") {
-                messages.push(json!({"role": cc_role, "content": text}));
+                _ => messages.push(json!({
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [call]
+                })),
             }
         }
         "function_call_output" => {
             messages.push(json!({
                 "role": "tool",
                 "tool_call_id": item.get("call_id").unwrap_or(&Value::Null),
-                "content": item.get("output").unwrap_or(&json!(""))
+                "content": tool_output_text(item.get("output"))
             }));
         }
-        "" => {
-            if let Some(role) = item.get("role").and_then(|v| v.as_str()) {
-                let cc_role = if role == "developer" { "system" } else { role };
-                let content = item.get("content").unwrap_or(&Value::Null);
-                messages.push(json!({"role": cc_role, "content": content}));
+        // Reasoning items from earlier responses have no chat-completions
+        // equivalent; the model regenerates its reasoning anyway.
+        "reasoning" => {}
+        _ => {}
+    }
+    Ok(())
+}
+
+fn translate_message(item: &Value, messages: &mut Vec<Value>) -> Result<(), String> {
+    let role = item
+        .get("role")
+        .and_then(|v| v.as_str())
+        .unwrap_or("user");
+    let cc_role = chat_role(role);
+    if !CHAT_ROLES.contains(&cc_role) {
+        return Err(format!("unsupported message role '{role}'"));
+    }
+
+    if let Some(Value::Array(content_parts)) = item.get("content") {
+        let mut cc_content: Vec<Value> = Vec::new();
+        for part in content_parts {
+            let ptype = part.get("type").and_then(|v| v.as_str()).unwrap_or("");
+            let pushed_before = cc_content.len();
+            match ptype {
+                "input_text" => {
+                    cc_content.push(json!({
+                        "type": "text",
+                        "text": part.get("text").unwrap_or(&Value::Null)
+                    }));
+                }
+                "input_image" => {
+                    if let Some(url) = part.get("image_url").and_then(|v| v.as_str()) {
+                        cc_content.push(json!({
+                            "type": "image_url",
+                            "image_url": {"url": url}
+                        }));
+                    }
+                }
+                "input_audio" => {
+                    // Accept both `{input_audio: {data, format}}` and flat `{data, format}`.
+                    let audio = part.get("input_audio").unwrap_or(part);
+                    cc_content.push(json!({
+                        "type": "input_audio",
+                        "input_audio": {
+                            "data": audio.get("data").unwrap_or(&Value::Null),
+                            "format": audio.get("format").unwrap_or(&Value::Null)
+                        }
+                    }));
+                }
+                _ => {
+                    if let Some(text) = part.get("text") {
+                        cc_content.push(json!({
+                            "type": "text",
+                            "text": text
+                        }));
+                    }
+                }
+            }
+            // Prompt-caching markers must survive on the translated block.
+            if let Some(cache) = part.get("cache_control") {
+                if cc_content.len() > pushed_before {
+                    cc_content[pushed_before]["cache_control"] = cache.clone();
+                }
             }
         }
-        _ => {}
+        if cc_content.len() == 1
+            && cc_content[0].get("type").and_then(|v| v.as_str()) == Some("text")
+            && cc_content[0].get("cache_control").is_none()
+        {
+            messages.push(json!({
+                "role": cc_role,
+                "content": cc_content[0].get("text").unwrap_or(&Value::Null)
+            }));
+            return Ok(());
+        }
+        messages.push(json!({"role": cc_role, "content": cc_content}));
+    } else if let Some(Value::String(text)) = item.get("content") {
+        messages.push(json!({"role": cc_role, "content": text}));
+    }
+    Ok(())
+}
+
+fn tool_output_text(output: Option<&Value>) -> String {
+    match output {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(parts)) => parts
+            .iter()
+            .filter_map(|p| match p {
+                Value::String(s) => Some(s.as_str()),
+                _ => p.get("text").and_then(|t| t.as_str()),
+            })
+            .collect::<Vec<_>>()
+            .join(""),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
     }
 }
 
@@ -254,6 +455,8 @@ pub fn translate_response(cc_resp: &Value, req: &TranslatedRequest) -> Value {
         .unwrap_or(&req.model);
 
     let mut output: Vec<Value> = Vec::new();
+    let mut tool_call_count = 0;
+    let mut tool_calls_truncated = false;
 
     if let Some(Value::Array(choices)) = cc_resp.get("choices") {
         for choice in choices {
@@ -262,11 +465,41 @@ pub fn translate_response(cc_resp: &Value, req: &TranslatedRequest) -> Value {
                 None => continue,
             };
 
+            // Text first, as in streams: fed back as input, the calls then
+            // join its assistant turn right before their outputs.
+            let mut parts = Vec::new();
+            if let Some(content) = msg.get("content").and_then(|v| v.as_str()) {
+                if !content.is_empty() {
+                    parts.push(json!({
+                        "type": "output_text",
+                        "text": content,
+                        "annotations": []
+                    }));
+                }
+            }
+            if let Some(refusal) = msg.get("refusal").and_then(|v| v.as_str()) {
+                if !refusal.is_empty() {
+                    parts.push(refusal_part(refusal));
+                }
+            }
+            if !parts.is_empty() {
+                output.push(json!({
+                    "id": next_id("msg"),
+                    "type": "message",
+                    "role": "assistant",
+                    "status": "completed",
+                    "content": parts
+                }));
+            }
             if let Some(Value::Array(tool_calls)) = msg.get("tool_calls") {
                 for tc in tool_calls {
+                    if req.max_tool_calls.is_some_and(|max| tool_call_count >= max) {
+                        tool_calls_truncated = true;
+                        break;
+                    }
+                    tool_call_count += 1;
                     let empty_obj = json!({});
                     let func = tc.get("function").unwrap_or(&empty_obj);
-                    let empty_str = json!("");
                     let tc_id = next_id("fc");
                     output.push(json!({
                         "id": tc_id,
@@ -274,37 +507,26 @@ pub fn translate_response(cc_resp: &Value, req: &TranslatedRequest) -> Value {
                         "status": "completed",
                         "call_id": tc.get("id").unwrap_or(&Value::Null),
                         "name": func.get("name").unwrap_or(&Value::Null),
-                        "arguments": func.get("arguments").unwrap_or(&empty_str)
+                        "arguments": arguments_text(func.get("arguments")).unwrap_or_default()
                     }));
                 }
             }
 
-            if let Some(content) = msg.get("content").and_then(|v| v.as_str()) {
-                if !content.is_empty() {
-                    let msg_id = next_id("msg");
-                    output.push(json!({
-                        "id": msg_id,
-                        "type": "message",
-                        "role": "assistant",
-                        "status": "completed",
-                        "content": [{
-                            "type": "output_text",
-                            "text": content,
-                            "annotations": []
-                        }]
-                    }));
-                }
+            if let Some(Value::Array(images)) = msg.get("images") {
+                output.extend(images.iter().filter_map(image_item));
             }
         }
     }
 
     let usage = if let Some(u) = cc_resp.get("usage") {
-        json!({
+        let mut usage = json!({
             "input_tokens": u.get("prompt_tokens").unwrap_or(&json!(0)),
             "output_tokens": u.get("completion_tokens").unwrap_or(&json!(0)),
-            "output_tokens_details": {"reasoning_tokens": 0},
+            "output_tokens_details": {"reasoning_tokens": reasoning_tokens(u).unwrap_or(0)},
             "total_tokens": u.get("total_tokens").unwrap_or(&json!(0))
-        })
+        });
+        copy_accounting(u, &mut usage);
+        usage
     } else {
         Value::Null
     };
@@ -314,18 +536,33 @@ pub fn translate_response(cc_resp: &Value, req: &TranslatedRequest) -> Value {
         .and_then(|v| v.as_str())
         .unwrap_or("stop");
 
-    let status = if finish_reason == "length" {
-        "incomplete"
-    } else {
-        "completed"
-    };
+    // A 200 without any choice is an upstream hiccup, not an empty answer.
+    let no_choices = cc_resp
+        .get("choices")
+        .and_then(|v| v.as_array())
+        .is_none_or(|c| c.is_empty());
 
-    let incomplete_details = if finish_reason == "length" {
-        json!({"reason": "max_output_tokens"})
+    let (status, incomplete_reason) = if no_choices {
+        ("incomplete", Some("empty_response"))
+    } else if tool_calls_truncated {
+        ("incomplete", Some("max_tool_calls"))
     } else {
-        Value::Null
+        finish_status(finish_reason)
+    };
+    let incomplete_details = match incomplete_reason {
+        Some(reason) => json!({"reason": reason}),
+        None => Value::Null,
     };
 
+    // The model actually served, which differs from the request after failover.
+    let mut metadata = json!({"model": req.model});
+    if let Some(id) = cc_resp.get("id").and_then(|v| v.as_str()) {
+        metadata["upstream_id"] = json!(id);
+    }
+    if let Some(provider) = cc_resp.get("provider").and_then(|v| v.as_str()) {
+        metadata["provider"] = json!(provider);
+    }
+
     json!({
         "id": req.resp_id,
         "object": "response",
@@ -346,20 +583,106 @@ pub fn translate_response(cc_resp: &Value, req: &TranslatedRequest) -> Value {
         "top_p": req.top_p,
         "truncation": "disabled",
         "usage": usage,
-        "metadata": {}
+        "metadata": metadata
     })
 }
 
-pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest) -> Response {
+/// Maps a chat-completions `finish_reason` to a Responses `status` and, for
+/// incomplete responses, the `incomplete_details.reason`.
+const FINISH_REASONS: &[(&str, &str, Option<&str>)] = &[
+    ("stop", "completed", None),
+    ("tool_calls", "completed", None),
+    ("function_call", "completed", None),
+    ("length", "incomplete", Some("max_output_tokens")),
+    ("content_filter", "incomplete", Some("content_filter")),
+];
+
+fn finish_status(finish_reason: &str) -> (&'static str, Option<&'static str>) {
+    FINISH_REASONS
+        .iter()
+        .find(|(fr, _, _)| *fr == finish_reason)
+        .map(|&(_, status, reason)| (status, reason))
+        .unwrap_or(("completed", None))
+}
+
+/// Reasoning tokens are billed as completion tokens even when the reasoning
+/// text itself is excluded.
+fn reasoning_tokens(usage: &Value) -> Option<u64> {
+    usage
+        .pointer("/completion_tokens_details/reasoning_tokens")
+        .and_then(|v| v.as_u64())
+}
+
+/// OpenRouter's cost accounting fields in chat `usage`, kept as-is in the
+/// Responses `usage`.
+const ACCOUNTING_FIELDS: &[&str] = &["cost", "cost_details", "is_byok"];
+
+fn copy_accounting(cc_usage: &Value, usage: &mut Value) {
+    for &field in ACCOUNTING_FIELDS {
+        if let Some(v) = cc_usage.get(field) {
+            usage[field] = v.clone();
+        }
+    }
+}
+
+/// A Responses `image_generation_call` item for one entry of OpenRouter's
+/// `message.images` (`{"type": "image_url", "image_url": {"url": ...}}`).
+/// `result` holds the base64 payload of a data URL, or a plain URL as-is.
+fn image_item(image: &Value) -> Option<Value> {
+    let url = image.pointer("/image_url/url").and_then(|v| v.as_str())?;
+    let mut item = json!({
+        "id": next_id("ig"),
+        "type": "image_generation_call",
+        "status": "completed",
+        "result": url,
+    });
+    if let Some((header, data)) = url.strip_prefix("data:").and_then(|u| u.split_once(',')) {
+        item["result"] = json!(data);
+        let mime = header.trim_end_matches(";base64");
+        if let Some(format) = mime.strip_prefix("image/") {
+            item["output_format"] = json!(format);
+        }
+    }
+    Some(item)
+}
+
+/// Models may decline via `message.refusal` instead of `content`; Responses
+/// carries that as its own content part type.
+fn refusal_part(refusal: &str) -> Value {
+    json!({"type": "refusal", "refusal": refusal})
+}
+
+pub async fn stream_response(
+    cc_resp: reqwest::Response,
+    req: TranslatedRequest,
+    config: &Config,
+    permit: UpstreamPermit,
+    mut retry: Option<reqwest::RequestBuilder>,
+    store: Option<(ResponseStore, u64)>,
+) -> Response {
+    let idle_timeout = (config.stream_idle_timeout_secs > 0)
+        .then(|| Duration::from_secs(config.stream_idle_timeout_secs));
+    let repair_args = config.repair_tool_args;
+    let coalesce_args = config.coalesce_arg_deltas();
     let resp_id = req.resp_id.clone();
     let msg_id = next_id("msg");
     let model = req.model.clone();
 
-    let (tx, rx) = tokio::sync::mpsc::channel::<String>(64);
+    let slow_client_timeout = (config.slow_client_timeout_secs > 0)
+        .then(|| Duration::from_secs(config.slow_client_timeout_secs));
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<String>(STREAM_CHANNEL_CAPACITY);
+    // Set when the client stops reading: the frames already queued are still
+    // delivered, then this error event ends the stream.
+    let abandoned = Arc::new(Mutex::new(None::<String>));
+    let abandoned_tail = abandoned.clone();
 
     tokio::spawn(async move {
+        // Hold the upstream slot until the translated stream is finished.
+        let _permit = permit;
         let mut seq: u64 = 0;
         let mut full_text = String::new();
+        let mut refusal = String::new();
         let mut tool_calls: std::collections::BTreeMap<u64, ToolCallAcc> =
             std::collections::BTreeMap::new();
         #[allow(unused_assignments)]
@@ -368,12 +691,47 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
         let mut input_tokens: u64 = 0;
         let mut output_tokens: u64 = 0;
         let mut total_tokens: u64 = 0;
-
+        let mut reasoning: u64 = 0;
+        // The last upstream `usage`, for its cost accounting fields.
+        let mut cc_usage: Option<Value> = None;
+        let mut upstream_id: Option<String> = None;
+        let mut provider: Option<String> = None;
+        let mut timed_out = false;
+        let mut tool_calls_truncated = false;
+        let mut saw_choice = false;
+        let mut images: Vec<Value> = Vec::new();
+
+        // Every event bumps `seq` before sending, so it doubles as the SSE id.
+        // A client that lets the channel stay full past `slow_client_timeout`
+        // is cut off, releasing the upstream connection; one that hung up is
+        // simply no longer written to.
         macro_rules! send {
             ($event:expr, $data:expr) => {
-                let _ = tx
-                    .send(format!("event: {}\ndata: {}\n\n", $event, $data))
-                    .await;
+                let frame = format!("id: {}\nevent: {}\ndata: {}\n\n", seq, $event, $data);
+                let stalled = match slow_client_timeout {
+                    Some(limit) => matches!(
+                        tx.send_timeout(frame, limit).await,
+                        Err(SendTimeoutError::Timeout(_))
+                    ),
+                    None => {
+                        let _ = tx.send(frame).await;
+                        false
+                    }
+                };
+                if stalled {
+                    warn!("Client stopped reading {resp_id} for too long, closing");
+                    seq += 1;
+                    let evt = json!({
+                        "type": "error",
+                        "code": "slow_client",
+                        "message": "The client did not read the stream fast enough",
+                        "param": null,
+                        "sequence_number": seq
+                    });
+                    *abandoned.lock().unwrap() =
+                        Some(format!("id: {seq}\nevent: error\ndata: {evt}\n\n"));
+                    return;
+                }
             };
         }
 
@@ -444,7 +802,73 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
         let mut byte_stream = cc_resp.bytes_stream();
         use tokio_stream::StreamExt;
 
-        while let Some(chunk_result) = byte_stream.next().await {
+        loop {
+            let read = async {
+                match idle_timeout {
+                    Some(limit) => tokio::time::timeout(limit, byte_stream.next()).await.ok(),
+                    None => Some(byte_stream.next().await),
+                }
+            };
+            // Held-back argument text is sent once its window passes, even if
+            // upstream goes quiet in the meantime.
+            let flush_at = coalesce_args.and_then(|window| {
+                let oldest = tool_calls.values().filter_map(|acc| acc.pending_since).min()?;
+                Some((window, oldest + window))
+            });
+            let next = match flush_at {
+                Some((window, at)) => tokio::select! {
+                    next = read => next,
+                    () = tokio::time::sleep_until(at.into()) => {
+                        for (idx, acc) in tool_calls.iter_mut() {
+                            if acc.pending_since.is_none_or(|since| since.elapsed() < window) {
+                                continue;
+                            }
+                            acc.pending_since = None;
+                            seq += 1;
+                            let evt = json!({
+                                "type": "response.function_call_arguments.delta",
+                                "item_id": &acc.item_id,
+                                "output_index": idx + 1,
+                                "delta": std::mem::take(&mut acc.pending),
+                                "sequence_number": seq
+                            });
+                            send!("response.function_call_arguments.delta", evt);
+                        }
+                        continue;
+                    }
+                },
+                None => read.await,
+            };
+            let Some(next) = next else {
+                let limit = idle_timeout.unwrap_or_default();
+                warn!("Upstream stream idle for {}s, closing", limit.as_secs());
+                timed_out = true;
+                break;
+            };
+            let Some(chunk_result) = next else {
+                // An empty finish is usually a provider hiccup. Nothing but the
+                // opening events has reached the client yet, so retry once.
+                let empty = full_text.is_empty()
+                    && refusal.is_empty()
+                    && tool_calls.is_empty()
+                    && images.is_empty();
+                let Some(retry) = retry.take().filter(|_| empty && !tool_calls_truncated) else {
+                    break;
+                };
+                match retry.send().await {
+                    Ok(r) if r.status().is_success() => {
+                        info!("Empty stream from {model}, retrying once");
+                        byte_stream = r.bytes_stream();
+                        buffer.clear();
+                        saw_choice = false;
+                        finish_reason = String::from("stop");
+                        continue;
+                    }
+                    Ok(r) => warn!("Retry of empty stream from {model} got {}", r.status()),
+                    Err(e) => warn!("Retry of empty stream from {model} failed: {e}"),
+                }
+                break;
+            };
             let chunk = match chunk_result {
                 Ok(c) => c,
                 Err(e) => {
@@ -455,8 +879,7 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
             buffer.push_str(&String::from_utf8_lossy(&chunk));
 
             while let Some(pos) = buffer.find("\n\n") {
-                let block = buffer[..pos].to_string();
-                buffer = buffer[pos + 2..].to_string();
+                let block: String = buffer.drain(..pos + 2).collect();
 
                 for line in block.lines() {
                     let line = line.trim();
@@ -473,6 +896,14 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                         Err(_) => continue,
                     };
 
+                    if upstream_id.is_none() {
+                        upstream_id = parsed.get("id").and_then(|v| v.as_str()).map(String::from);
+                    }
+                    if provider.is_none() {
+                        provider =
+                            parsed.get("provider").and_then(|v| v.as_str()).map(String::from);
+                    }
+
                     if let Some(u) = parsed.get("usage") {
                         input_tokens = u
                             .get("prompt_tokens")
@@ -486,6 +917,8 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                             .get("total_tokens")
                             .and_then(|v| v.as_u64())
                             .unwrap_or(total_tokens);
+                        reasoning = reasoning_tokens(u).unwrap_or(reasoning);
+                        cc_usage = Some(u.clone());
                     }
 
                     let choices = match parsed.get("choices").and_then(|v| v.as_array()) {
@@ -494,6 +927,7 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                     };
 
                     for choice in choices {
+                        saw_choice = true;
                         if let Some(fr) = choice.get("finish_reason").and_then(|v| v.as_str()) {
                             finish_reason = fr.to_string();
                         }
@@ -519,10 +953,49 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                             }
                         }
 
+                        if let Some(text) = delta.get("refusal").and_then(|v| v.as_str()) {
+                            if !text.is_empty() {
+                                if refusal.is_empty() {
+                                    seq += 1;
+                                    let evt = json!({
+                                        "type": "response.content_part.added",
+                                        "item_id": &msg_id,
+                                        "output_index": 0,
+                                        "content_index": 1,
+                                        "part": refusal_part(""),
+                                        "sequence_number": seq
+                                    });
+                                    send!("response.content_part.added", evt);
+                                }
+                                refusal.push_str(text);
+                                seq += 1;
+                                let evt = json!({
+                                    "type": "response.refusal.delta",
+                                    "item_id": &msg_id,
+                                    "output_index": 0,
+                                    "content_index": 1,
+                                    "delta": text,
+                                    "sequence_number": seq
+                                });
+                                send!("response.refusal.delta", evt);
+                            }
+                        }
+
+                        // Generated images arrive whole and are sent at the end.
+                        if let Some(Value::Array(parts)) = delta.get("images") {
+                            images.extend(parts.iter().filter_map(image_item));
+                        }
+
                         if let Some(Value::Array(tcs)) = delta.get("tool_calls") {
                             for tc in tcs {
                                 let idx =
                                     tc.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                                if !tool_calls.contains_key(&idx)
+                                    && req.max_tool_calls.is_some_and(|max| tool_calls.len() >= max)
+                                {
+                                    tool_calls_truncated = true;
+                                    continue;
+                                }
 
                                 let acc =
                                     tool_calls.entry(idx).or_insert_with(|| ToolCallAcc {
@@ -535,6 +1008,8 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                                         name: String::new(),
                                         arguments: String::new(),
                                         announced: false,
+                                        pending: String::new(),
+                                        pending_since: None,
                                     });
 
                                 if let Some(id) = tc.get("id").and_then(|v| v.as_str()) {
@@ -547,9 +1022,7 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                                     if let Some(name) = f.get("name").and_then(|v| v.as_str()) {
                                         acc.name.push_str(name);
                                     }
-                                    if let Some(args) =
-                                        f.get("arguments").and_then(|v| v.as_str())
-                                    {
+                                    if let Some(args) = arguments_text(f.get("arguments")) {
                                         if !acc.announced && !acc.name.is_empty() {
                                             let output_idx = idx + 1;
                                             seq += 1;
@@ -570,14 +1043,23 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                                             acc.announced = true;
                                         }
 
-                                        acc.arguments.push_str(args);
+                                        acc.arguments.push_str(&args);
+                                        acc.pending.push_str(&args);
+                                        let since = *acc
+                                            .pending_since
+                                            .get_or_insert_with(std::time::Instant::now);
+                                        // Anything still pending is flushed before `.done`.
+                                        if coalesce_args.is_some_and(|w| since.elapsed() < w) {
+                                            continue;
+                                        }
+                                        acc.pending_since = None;
                                         seq += 1;
                                         let output_idx = idx + 1;
                                         let evt = json!({
                                             "type": "response.function_call_arguments.delta",
                                             "item_id": &acc.item_id,
                                             "output_index": output_idx,
-                                            "delta": args,
+                                            "delta": std::mem::take(&mut acc.pending),
                                             "sequence_number": seq
                                         });
                                         send!("response.function_call_arguments.delta", evt);
@@ -618,7 +1100,9 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
             send!("response.content_part.done", evt);
         }
 
-        if full_text.is_empty() && text_content_started && !tool_calls.is_empty() {
+        let has_refusal = !refusal.is_empty();
+        if full_text.is_empty() && text_content_started && (!tool_calls.is_empty() || has_refusal)
+        {
             seq += 1;
             let evt = json!({
                 "type": "response.content_part.done",
@@ -635,26 +1119,58 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
             send!("response.content_part.done", evt);
         }
 
+        if has_refusal {
+            seq += 1;
+            let evt = json!({
+                "type": "response.refusal.done",
+                "item_id": &msg_id,
+                "output_index": 0,
+                "content_index": 1,
+                "refusal": &refusal,
+                "sequence_number": seq
+            });
+            send!("response.refusal.done", evt);
+
+            seq += 1;
+            let evt = json!({
+                "type": "response.content_part.done",
+                "item_id": &msg_id,
+                "output_index": 0,
+                "content_index": 1,
+                "part": refusal_part(&refusal),
+                "sequence_number": seq
+            });
+            send!("response.content_part.done", evt);
+        }
+
         let mut final_output: Vec<Value> = Vec::new();
 
-        let msg_status = if finish_reason == "length" {
-            "incomplete"
+        let (resp_status, incomplete_reason) = if timed_out {
+            ("incomplete", Some("stream_idle_timeout"))
+        } else if !saw_choice {
+            ("incomplete", Some("empty_response"))
+        } else if tool_calls_truncated {
+            ("incomplete", Some("max_tool_calls"))
         } else {
-            "completed"
+            finish_status(&finish_reason)
         };
 
-        if !full_text.is_empty() || tool_calls.is_empty() {
+        if !full_text.is_empty() || tool_calls.is_empty() || has_refusal {
             seq += 1;
+            let mut content = vec![json!({
+                "type": "output_text",
+                "text": &full_text,
+                "annotations": []
+            })];
+            if has_refusal {
+                content.push(refusal_part(&refusal));
+            }
             let msg_item = json!({
                 "id": &msg_id,
                 "type": "message",
                 "role": "assistant",
-                "status": msg_status,
-                "content": [{
-                    "type": "output_text",
-                    "text": &full_text,
-                    "annotations": []
-                }]
+                "status": resp_status,
+                "content": content
             });
             let evt = json!({
                 "type": "response.output_item.done",
@@ -687,13 +1203,27 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
                 send!("response.output_item.added", evt);
             }
 
+            if !acc.pending.is_empty() {
+                seq += 1;
+                let evt = json!({
+                    "type": "response.function_call_arguments.delta",
+                    "item_id": &acc.item_id,
+                    "output_index": output_idx,
+                    "delta": &acc.pending,
+                    "sequence_number": seq
+                });
+                send!("response.function_call_arguments.delta", evt);
+            }
+
+            let (arguments, fc_status) = finalize_arguments(&acc.arguments, repair_args);
+
             seq += 1;
             let evt = json!({
                 "type": "response.function_call_arguments.done",
                 "item_id": &acc.item_id,
                 "output_index": output_idx,
                 "name": &acc.name,
-                "arguments": &acc.arguments,
+                "arguments": &arguments,
                 "sequence_number": seq
             });
             send!("response.function_call_arguments.done", evt);
@@ -702,10 +1232,10 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
             let fc_item = json!({
                 "id": &acc.item_id,
                 "type": "function_call",
-                "status": "completed",
+                "status": fc_status,
                 "call_id": &acc.id,
                 "name": &acc.name,
-                "arguments": &acc.arguments
+                "arguments": &arguments
             });
             let evt = json!({
                 "type": "response.output_item.done",
@@ -717,23 +1247,64 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
             final_output.push(fc_item);
         }
 
-        let resp_status = if finish_reason == "length" {
-            "incomplete"
-        } else {
-            "completed"
-        };
-        let incomplete_details = if finish_reason == "length" {
-            json!({"reason": "max_output_tokens"})
-        } else {
-            Value::Null
+        let first_image_index = tool_calls.keys().next_back().map_or(1, |&idx| idx + 2);
+        for (output_idx, item) in (first_image_index..).zip(images) {
+            seq += 1;
+            let mut added = item.clone();
+            added["status"] = json!("in_progress");
+            if let Some(obj) = added.as_object_mut() {
+                obj.remove("result");
+            }
+            let evt = json!({
+                "type": "response.output_item.added",
+                "output_index": output_idx,
+                "item": added,
+                "sequence_number": seq
+            });
+            send!("response.output_item.added", evt);
+
+            seq += 1;
+            let evt = json!({
+                "type": "response.image_generation_call.completed",
+                "item_id": &item["id"],
+                "output_index": output_idx,
+                "sequence_number": seq
+            });
+            send!("response.image_generation_call.completed", evt);
+
+            seq += 1;
+            let evt = json!({
+                "type": "response.output_item.done",
+                "output_index": output_idx,
+                "item": &item,
+                "sequence_number": seq
+            });
+            send!("response.output_item.done", evt);
+            final_output.push(item);
+        }
+
+        let incomplete_details = match incomplete_reason {
+            Some(reason) => json!({"reason": reason}),
+            None => Value::Null,
         };
 
-        let usage = json!({
+        let mut usage = json!({
             "input_tokens": input_tokens,
             "output_tokens": output_tokens,
-            "output_tokens_details": {"reasoning_tokens": 0},
+            "output_tokens_details": {"reasoning_tokens": reasoning},
             "total_tokens": total_tokens
         });
+        if let Some(ref u) = cc_usage {
+            copy_accounting(u, &mut usage);
+        }
+
+        let mut metadata = json!({"model": req.model});
+        if let Some(id) = upstream_id {
+            metadata["upstream_id"] = json!(id);
+        }
+        if let Some(provider) = provider {
+            metadata["provider"] = json!(provider);
+        }
 
         let completed_at = now_epoch();
         seq += 1;
@@ -762,9 +1333,12 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
             "top_p": req.top_p,
             "truncation": "disabled",
             "usage": usage,
-            "metadata": {}
+            "metadata": metadata
         });
 
+        if let Some((store, owner)) = store {
+            store.insert(owner, final_response.clone());
+        }
         let evt = json!({
             "type": final_event_type,
             "response": final_response,
@@ -773,26 +1347,126 @@ pub async fn stream_response(cc_resp: reqwest::Response, req: TranslatedRequest)
         send!(final_event_type, evt);
     });
 
-    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
-    let body = Body::from_stream(
-        tokio_stream::StreamExt::map(stream, |s| Ok::<_, std::convert::Infallible>(s)),
-    );
+    use tokio_stream::StreamExt;
+    let tail = tokio_stream::iter([()]).filter_map(move |()| abandoned_tail.lock().unwrap().take());
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx).chain(tail);
+    let body = Body::from_stream(stream.map(Ok::<_, std::convert::Infallible>));
 
     Response::builder()
         .status(200)
         .header("content-type", "text/event-stream")
         .header("cache-control", "no-cache")
         .header("connection", "keep-alive")
+        .header("x-accel-buffering", "no")
         .body(body)
         .unwrap()
 }
 
+/// Validates accumulated tool-call arguments. Invalid (usually truncated) JSON
+/// marks the item `incomplete` unless `repair` is on and a repair succeeds.
+fn finalize_arguments(arguments: &str, repair: bool) -> (String, &'static str) {
+    if arguments.is_empty() || serde_json::from_str::<Value>(arguments).is_ok() {
+        return (arguments.to_owned(), "completed");
+    }
+    match repair.then(|| repair_json(arguments)).flatten() {
+        Some(fixed) => {
+            info!("Repaired truncated tool-call arguments");
+            (fixed, "completed")
+        }
+        None => (arguments.to_owned(), "incomplete"),
+    }
+}
+
+/// Best-effort completion of truncated JSON: closes an open string and any open
+/// containers, dropping trailing partial members until the result parses.
+fn repair_json(partial: &str) -> Option<String> {
+    let mut cut = partial.trim_end();
+    loop {
+        let fixed = close_json(cut);
+        if serde_json::from_str::<Value>(&fixed).is_ok() {
+            return Some(fixed);
+        }
+        let next = last_boundary(cut)?;
+        if next >= cut.len() {
+            return None;
+        }
+        cut = &cut[..next];
+    }
+}
+
+fn close_json(s: &str) -> String {
+    let mut closers = Vec::new();
+    let (mut in_str, mut escaped) = (false, false);
+    for c in s.chars() {
+        if in_str {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_str = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_str = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' => {
+                closers.pop();
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = s.to_owned();
+    if in_str {
+        if escaped {
+            out.pop();
+        }
+        out.push('"');
+    }
+    let keep = out.trim_end().trim_end_matches(',').len();
+    out.truncate(keep);
+    if out.ends_with(':') {
+        out.push_str("null");
+    }
+    out.extend(closers.iter().rev());
+    out
+}
+
+/// Byte offset that drops the last member (after the last `,`, `{` or `[`
+/// outside a string).
+fn last_boundary(s: &str) -> Option<usize> {
+    let (mut in_str, mut escaped, mut last) = (false, false, None);
+    for (i, c) in s.char_indices() {
+        if in_str {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_str = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_str = true,
+            ',' => last = Some(i),
+            '{' | '[' => last = Some(i + 1),
+            _ => {}
+        }
+    }
+    last
+}
+
 struct ToolCallAcc {
     id: String,
     item_id: String,
     name: String,
     arguments: String,
     announced: bool,
+    /// Argument text not yet sent as a delta, and when it started collecting.
+    pending: String,
+    pending_since: Option<std::time::Instant>,
 }
 
 fn response_envelope(
@@ -837,88 +1511,936 @@ fn response_envelope(
     evt.to_string()
 }
 
+/// Translates and sends the request to each of `candidates` in turn, failing
+/// over to the next after a transport error, 404, 429 or 5xx. If every
+/// candidate fails, the last failure is returned. All attempts together must
+/// get an answer started before `deadline`. The finished response is kept in
+/// `store` (with its owner) when given. `system_prompt` goes before every
+/// translated request's messages.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_responses(
     client: &Client,
+    config: &Config,
+    live_health: &LiveHealth,
     api_key: &str,
-    model_id: &str,
+    candidates: &[&Model],
     body: Value,
+    permit: UpstreamPermit,
+    started: Instant,
+    deadline: Option<Instant>,
+    store: Option<(ResponseStore, u64)>,
+    system_prompt: Option<&str>,
 ) -> Response {
     let mut body = body;
-    body["model"] = json!(model_id);
-
-    let req = match translate_request(&body) {
-        Ok(r) => r,
-        Err(msg) => {
-            return error_response(StatusCode::BAD_REQUEST, &msg, "invalid_request_error");
+    let mut failure = None;
+
+    for (i, model) in candidates.iter().enumerate() {
+        body["model"] = json!(model.id);
+        let mut req = match translate_request(&body, model, &config.responses_passthrough) {
+            Ok(r) => r,
+            Err(msg) if i == 0 => {
+                return error_response(StatusCode::BAD_REQUEST, &msg, "invalid_request_error");
+            }
+            // A failover model that can't take this request is skipped.
+            Err(msg) => {
+                info!("Not failing over to {}: {msg}", model.id);
+                continue;
+            }
+        };
+        req.max_tool_calls = req.max_tool_calls.or(config.max_tool_calls);
+        config.apply_usage_accounting(&mut req.cc_body);
+        if let Some(prompt) = system_prompt {
+            Proxy::prepend_system_prompt(&mut req.cc_body, prompt);
         }
-    };
 
-    let is_stream = req.is_stream;
-
-    let upstream_resp = match client
-        .post(format!("{UPSTREAM}/chat/completions"))
-        .bearer_auth(api_key)
-        .json(&req.cc_body)
-        .send()
-        .await
-    {
-        Ok(r) => r,
-        Err(e) => {
-            return error_response(
-                StatusCode::BAD_GATEWAY,
-                &format!("upstream error: {e}"),
-                "server_error",
-            );
+        let request = client
+            .post(format!("{}/chat/completions", config.upstream_url))
+            .bearer_auth(api_key)
+            .json(&req.cc_body);
+        let retry = request.try_clone().filter(|_| req.is_stream && config.retry_empty_stream);
+        let sent_at = Instant::now();
+        let send = Proxy::send_traced(Some(&model.id), request);
+        let Some(sent) = Proxy::within(deadline, send).await else {
+            return Proxy::deadline_exceeded();
+        };
+        live_health.observe(&model.id, sent.as_ref().ok().map(|r| r.status().as_u16()));
+
+        let upstream_resp = match sent {
+            Ok(r) if r.status().is_success() => r,
+            Ok(r) => {
+                let status = r.status();
+                let body_text = r.text().await.unwrap_or_default();
+                let snippet = truncate_chars(&body_text, 200);
+                warn!("Upstream error {status} from {}: {snippet}", model.id);
+                failure = Some(upstream_error(
+                    config,
+                    StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY),
+                    &format!("Upstream returned {status}"),
+                    &req.cc_body,
+                    Some(&body_text),
+                ));
+                if !matches!(status.as_u16(), 404 | 429 | 500..=599) {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => {
+                warn!("Upstream error from {}: {e}", model.id);
+                failure = Some(upstream_error(
+                    config,
+                    StatusCode::BAD_GATEWAY,
+                    &format!("upstream error: {e}"),
+                    &req.cc_body,
+                    None,
+                ));
+                continue;
+            }
+        };
+        if i > 0 {
+            info!("Responses request failed over to {}", model.id);
         }
-    };
-
-    if !upstream_resp.status().is_success() {
-        let status = upstream_resp.status();
-        let body_text = upstream_resp.text().await.unwrap_or_default();
-        warn!(
-            "Upstream error {status}: {}",
-            &body_text[..body_text.len().min(200)]
-        );
-        return error_response(
-            StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY),
-            &format!("Upstream returned {status}"),
-            "server_error",
-        );
+        let relayed = relay(upstream_resp, req, config, permit, retry, started, sent_at, store);
+        return Proxy::within(deadline, relayed)
+            .await
+            .unwrap_or_else(Proxy::deadline_exceeded);
     }
 
-    if is_stream {
-        stream_response(upstream_resp, req).await
+    failure.unwrap_or_else(|| {
+        error_response(StatusCode::BAD_GATEWAY, "no model could take the request", "server_error")
+    })
+}
+
+/// Translates a successful upstream reply back into the Responses format.
+/// `retry` re-sends the request if a stream finishes empty.
+#[allow(clippy::too_many_arguments)]
+async fn relay(
+    upstream_resp: reqwest::Response,
+    req: TranslatedRequest,
+    config: &Config,
+    permit: UpstreamPermit,
+    retry: Option<reqwest::RequestBuilder>,
+    started: Instant,
+    sent_at: Instant,
+    store: Option<(ResponseStore, u64)>,
+) -> Response {
+    let latency = sent_at.elapsed();
+
+    if req.is_stream {
+        // Translated events start before upstream's first chunk is read, so
+        // latency here runs to the response headers.
+        let mut resp = stream_response(upstream_resp, req, config, permit, retry, store).await;
+        Proxy::add_timing(&mut resp, started, latency, latency);
+        resp
     } else {
-        match upstream_resp.json::<Value>().await {
+        let body_text = match upstream_resp.text().await {
+            Ok(t) => t,
+            Err(e) => {
+                return error_response(
+                    StatusCode::BAD_GATEWAY,
+                    &format!("failed to read upstream response: {e}"),
+                    "server_error",
+                );
+            }
+        };
+        let waited = sent_at.elapsed();
+
+        match serde_json::from_str::<Value>(&body_text) {
             Ok(cc_resp) => {
                 let resp = translate_response(&cc_resp, &req);
-                Response::builder()
+                if let Some((store, owner)) = store {
+                    store.insert(owner, resp.clone());
+                }
+                let mut builder = Response::builder()
                     .status(200)
-                    .header("content-type", "application/json")
-                    .body(Body::from(resp.to_string()))
-                    .unwrap()
+                    .header("content-type", "application/json");
+                let provider = resp["metadata"]["provider"].as_str();
+                if let Some(v) = provider.and_then(|p| HeaderValue::from_str(p).ok()) {
+                    builder = builder.header("x-openrouter-provider", v);
+                }
+                let mut resp = builder.body(Body::from(resp.to_string())).unwrap();
+                Proxy::add_timing(&mut resp, started, latency, waited);
+                resp
+            }
+            Err(e) => {
+                let snippet = truncate_chars(&body_text, 200);
+                warn!("Unparseable upstream response: {snippet}");
+                upstream_error(
+                    config,
+                    StatusCode::BAD_GATEWAY,
+                    &format!("failed to parse upstream response: {e}; body: {snippet}"),
+                    &req.cc_body,
+                    Some(&body_text),
+                )
             }
-            Err(e) => error_response(
-                StatusCode::BAD_GATEWAY,
-                &format!("failed to parse upstream response: {e}"),
-                "server_error",
-            ),
         }
     }
 }
 
+fn truncate_chars(s: &str, max: usize) -> &str {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
 fn error_response(status: StatusCode, message: &str, error_type: &str) -> Response {
-    let body = json!({
+    json_error(status, error_body(message, error_type))
+}
+
+fn error_body(message: &str, error_type: &str) -> Value {
+    json!({
         "error": {
             "message": message,
             "type": error_type,
             "param": null,
             "code": null
         }
-    });
+    })
+}
+
+/// Error for a failed upstream call. With `DEBUG_ERRORS` on, the translated
+/// request and the upstream reply (if any) are attached under `metadata`.
+fn upstream_error(
+    config: &Config,
+    status: StatusCode,
+    message: &str,
+    cc_body: &Value,
+    upstream_body: Option<&str>,
+) -> Response {
+    let mut body = error_body(message, "server_error");
+    if config.debug_errors {
+        body["metadata"] = json!({
+            "cc_body": redact(cc_body),
+            "upstream_body": upstream_body.map(|b| truncate_chars(b, 2000)),
+        });
+    }
+    json_error(status, body)
+}
+
+/// Field names whose values never appear in debug output.
+const REDACTED_FIELDS: &[&str] = &["authorization", "api_key", "apikey", "access_token", "token"];
+
+fn redact(value: &Value) -> Value {
+    match value {
+        Value::Object(obj) => Value::Object(
+            obj.iter()
+                .map(|(k, v)| {
+                    let v = if REDACTED_FIELDS.contains(&k.to_lowercase().as_str()) {
+                        json!("[redacted]")
+                    } else {
+                        redact(v)
+                    };
+                    (k.clone(), v)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact).collect()),
+        other => other.clone(),
+    }
+}
+
+fn json_error(status: StatusCode, body: Value) -> Response {
     Response::builder()
         .status(status)
         .header("content-type", "application/json")
         .body(Body::from(body.to_string()))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::ConcurrencyLimiter;
+    use http_body_util::BodyExt;
+    use proptest::prelude::*;
+
+    fn model() -> Model {
+        model_with(&["tools", "tool_choice", "temperature", "max_tokens"])
+    }
+
+    fn model_with(supported_parameters: &[&str]) -> Model {
+        serde_json::from_value(json!({
+            "id": "vendor/model:free",
+            "name": "Model",
+            "architecture": {"modality": "text+image+audio->text"},
+            "supported_parameters": supported_parameters
+        }))
+        .unwrap()
+    }
+
+    fn translate(body: Value) -> TranslatedRequest {
+        translate_request(&body, &model(), &[]).unwrap()
+    }
+
+    fn chat_reply(message: Value, finish_reason: &str) -> Value {
+        json!({
+            "id": "gen-1",
+            "choices": [{"index": 0, "message": message, "finish_reason": finish_reason}]
+        })
+    }
+
+    /// An upstream SSE response carrying `chunks` and the final `[DONE]`.
+    fn upstream(chunks: &[Value]) -> reqwest::Response {
+        let mut body: String = chunks.iter().map(|c| format!("data: {c}\n\n")).collect();
+        body.push_str("data: [DONE]\n\n");
+        axum::http::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(body)
+            .unwrap()
+            .into()
+    }
+
+    /// Runs `upstream` through `stream_response` and returns the data of
+    /// every event the client receives.
+    async fn stream_events(
+        config: &Config,
+        req: TranslatedRequest,
+        upstream: reqwest::Response,
+    ) -> Vec<Value> {
+        let permit = ConcurrencyLimiter::new(0, Duration::ZERO).acquire().await.unwrap();
+        let resp = stream_response(upstream, req, config, permit, None, None).await;
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8_lossy(&body)
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect()
+    }
+
+    fn event<'a>(events: &'a [Value], kind: &str) -> &'a Value {
+        events.iter().find(|e| e["type"] == kind).unwrap_or_else(|| panic!("no {kind} event"))
+    }
+
+    /// Keys and strings the translator looks at, so generated values reach
+    /// its branches rather than only the "ignore unknown" paths.
+    const KEYS: &[&str] = &[
+        "type", "role", "content", "text", "name", "arguments", "call_id", "output",
+        "image_url", "file_id", "detail", "input_audio", "data", "format", "refusal",
+        "input", "instructions", "tools", "tool_choice", "stop", "max_output_tokens",
+        "extra_body",
+    ];
+    const WORDS: &[&str] = &[
+        "message", "function_call", "function_call_output", "reasoning", "input_text",
+        "output_text", "input_image", "input_audio", "input_file", "refusal", "user",
+        "assistant", "system", "developer", "tool", "critic", "",
+    ];
+
+    fn json_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            (-1e6f64..1e6).prop_map(Value::from),
+            prop::sample::select(WORDS).prop_map(Value::from),
+            "[a-z{}\"]{0,8}".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 48, 6, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..5).prop_map(Value::from),
+                prop::collection::btree_map(prop::sample::select(KEYS), inner, 0..6).prop_map(
+                    |m| Value::Object(m.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+                ),
+            ]
+        })
+    }
+
+    /// Input items shaped roughly like the Responses API, with any value in
+    /// any field.
+    fn input_item() -> impl Strategy<Value = Value> {
+        (
+            prop::sample::select(WORDS),
+            prop::option::of(prop::sample::select(WORDS)),
+            json_value(),
+            json_value(),
+        )
+            .prop_map(|(kind, role, content, extra)| {
+                let mut item = json!({"type": kind, "content": content, "arguments": extra});
+                if let Some(role) = role {
+                    item["role"] = role.into();
+                }
+                item
+            })
+    }
+
+    fn request_body() -> impl Strategy<Value = Value> {
+        let input = prop_oneof![
+            json_value(),
+            prop::collection::vec(prop_oneof![input_item(), json_value()], 0..6)
+                .prop_map(Value::from),
+        ];
+        (input, json_value(), json_value(), json_value(), json_value()).prop_map(
+            |(input, instructions, tools, stop, reasoning)| {
+                json!({
+                    "model": "vendor/model:free",
+                    "input": input,
+                    "instructions": instructions,
+                    "tools": tools,
+                    "stop": stop,
+                    "reasoning": reasoning
+                })
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn translate_request_never_panics_and_keeps_roles_valid(body in request_body()) {
+            let Ok(req) = translate_request(&body, &model(), &[]) else {
+                return Ok(());
+            };
+            let messages = req.cc_body["messages"].as_array().expect("messages is an array");
+            prop_assert!(!messages.is_empty());
+            for m in messages {
+                let role = m["role"].as_str().unwrap_or("");
+                prop_assert!(CHAT_ROLES.contains(&role) || role == "tool", "bad role in {m}");
+            }
+        }
+
+        #[test]
+        fn translate_request_accepts_any_json(mut body in json_value()) {
+            if let Some(obj) = body.as_object_mut() {
+                obj.insert("model".into(), "vendor/model:free".into());
+            }
+            let _ = translate_request(&body, &model(), &[]);
+        }
+    }
+
+    #[test]
+    fn arguments_text_serializes_objects() {
+        assert_eq!(arguments_text(Some(&json!("{\"a\":1}"))).as_deref(), Some("{\"a\":1}"));
+        assert_eq!(arguments_text(Some(&json!({"a": 1}))).as_deref(), Some("{\"a\":1}"));
+        assert_eq!(arguments_text(Some(&Value::Null)), None);
+        assert_eq!(arguments_text(None), None);
+    }
+
+    #[test]
+    fn object_tool_call_arguments_become_a_string() {
+        let req = translate(json!({"model": "vendor/model:free", "input": "weather?"}));
+        let reply = chat_reply(
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": {"city": "Oslo"}}
+                }]
+            }),
+            "tool_calls",
+        );
+        let resp = translate_response(&reply, &req);
+        assert_eq!(resp["output"][0]["type"], "function_call");
+        assert_eq!(resp["output"][0]["arguments"], "{\"city\":\"Oslo\"}");
+    }
+
+    #[tokio::test]
+    async fn object_tool_call_arguments_accumulate_in_streams() {
+        let body = json!({"model": "vendor/model:free", "input": "weather?", "stream": true});
+        let req = translate(body);
+        let call = |function: Value| {
+            json!({"choices": [{"index": 0, "delta": {"tool_calls": [
+                {"index": 0, "id": "call_1", "function": function}
+            ]}}]})
+        };
+        let events = stream_events(&Config::from_env(), req, upstream(&[
+            call(json!({"name": "get_weather", "arguments": {"city": "Oslo"}})),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}]}),
+        ]))
+        .await;
+        let done = event(&events, "response.function_call_arguments.done");
+        assert_eq!(done["arguments"], "{\"city\":\"Oslo\"}");
+        let completed = event(&events, "response.completed");
+        let output = completed["response"]["output"].as_array().unwrap();
+        let call = output.iter().find(|item| item["type"] == "function_call").unwrap();
+        assert_eq!(call["arguments"], "{\"city\":\"Oslo\"}");
+    }
+
+    #[tokio::test]
+    async fn first_delta_reaches_the_client_while_upstream_is_open() {
+        let (chunks, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(1);
+        let upstream: reqwest::Response = axum::http::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(reqwest::Body::wrap_stream(tokio_stream::wrappers::ReceiverStream::new(rx)))
+            .unwrap()
+            .into();
+        let body = json!({"model": "vendor/model:free", "input": "hi", "stream": true});
+        let permit = ConcurrencyLimiter::new(0, Duration::ZERO).acquire().await.unwrap();
+        let config = Config::from_env();
+        let resp = stream_response(upstream, translate(body), &config, permit, None, None).await;
+        let mut body = resp.into_body();
+
+        let delta = json!({"choices": [{"index": 0, "delta": {"content": "Hel"}}]});
+        let sent = tokio::time::Instant::now();
+        chunks.send(Ok(format!("data: {delta}\n\n"))).await.unwrap();
+        let mut received = String::new();
+        while !received.contains("response.output_text.delta") {
+            let frame = tokio::time::timeout(Duration::from_secs(1), body.frame())
+                .await
+                .expect("first delta held back")
+                .unwrap()
+                .unwrap();
+            received.push_str(&String::from_utf8_lossy(&frame.into_data().unwrap()));
+        }
+        let time_to_first_delta = sent.elapsed();
+        assert!(time_to_first_delta < Duration::from_millis(200), "{time_to_first_delta:?}");
+        assert!(received.contains("\"delta\":\"Hel\""));
+        // The upstream hasn't finished, so nothing here waited for the end.
+        assert!(!chunks.is_closed());
+    }
+
+    fn tool_message(output: Value) -> Value {
+        let req = translate(json!({
+            "model": "vendor/model:free",
+            "input": [
+                {"role": "user", "content": "weather?"},
+                {"type": "function_call", "call_id": "call_1", "name": "f", "arguments": "{}"},
+                {"type": "function_call_output", "call_id": "call_1", "output": output}
+            ]
+        }));
+        req.cc_body["messages"][2].clone()
+    }
+
+    #[test]
+    fn array_tool_output_is_flattened_to_text() {
+        let message = tool_message(json!([
+            {"type": "input_text", "text": "Rain, "},
+            "12C",
+            {"type": "input_image", "image_url": "https://example.com/map.png"}
+        ]));
+        let expected = json!({"role": "tool", "tool_call_id": "call_1", "content": "Rain, 12C"});
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn string_and_other_tool_outputs() {
+        assert_eq!(tool_message(json!("Rain"))["content"], "Rain");
+        assert_eq!(tool_message(json!({"temp": 12}))["content"], "{\"temp\":12}");
+        assert_eq!(tool_message(Value::Null)["content"], "");
+        assert_eq!(tool_message(json!([]))["content"], "");
+    }
+
+    #[test]
+    fn reasoning_input_items_are_dropped() {
+        let req = translate(json!({
+            "model": "vendor/model:free",
+            "input": [
+                {"role": "user", "content": "hi"},
+                {"type": "reasoning", "id": "rs_1", "summary": []},
+                {"role": "assistant", "content": "hello"}
+            ]
+        }));
+        let roles: Vec<&str> = req.cc_body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["user", "assistant"]);
+    }
+
+    /// The `status` and `incomplete_details` of a translated reply that
+    /// finished with `finish_reason`.
+    fn finished(finish_reason: &str, message: Value) -> (Value, Value) {
+        let req = translate(json!({"model": "vendor/model:free", "input": "hi"}));
+        let resp = translate_response(&chat_reply(message, finish_reason), &req);
+        (resp["status"].clone(), resp["incomplete_details"].clone())
+    }
+
+    fn text(content: &str) -> Value {
+        json!({"role": "assistant", "content": content})
+    }
+
+    #[test]
+    fn finish_reason_stop_completes() {
+        assert_eq!(finished("stop", text("done")), (json!("completed"), Value::Null));
+    }
+
+    #[test]
+    fn finish_reason_tool_calls_completes_with_the_calls() {
+        let message = json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [
+                {"id": "call_1", "type": "function", "function": {"name": "f", "arguments": "{}"}}
+            ]
+        });
+        assert_eq!(finished("tool_calls", message.clone()), (json!("completed"), Value::Null));
+        let req = translate(json!({"model": "vendor/model:free", "input": "hi"}));
+        let resp = translate_response(&chat_reply(message, "tool_calls"), &req);
+        assert!(resp["output"].as_array().unwrap().iter().any(|i| i["type"] == "function_call"));
+    }
+
+    #[test]
+    fn finish_reason_function_call_completes() {
+        assert_eq!(finished("function_call", text("")), (json!("completed"), Value::Null));
+    }
+
+    #[test]
+    fn finish_reason_length_is_incomplete() {
+        let reason = json!({"reason": "max_output_tokens"});
+        assert_eq!(finished("length", text("1, 2")), (json!("incomplete"), reason));
+    }
+
+    #[test]
+    fn finish_reason_content_filter_is_incomplete() {
+        let reason = json!({"reason": "content_filter"});
+        assert_eq!(finished("content_filter", text("")), (json!("incomplete"), reason));
+    }
+
+    #[test]
+    fn unknown_finish_reason_completes() {
+        assert_eq!(finished("eos_token", text("done")), (json!("completed"), Value::Null));
+        assert_eq!(finish_status(""), ("completed", None));
+    }
+
+    #[test]
+    fn empty_or_missing_choices_are_incomplete() {
+        let req = translate(json!({"model": "vendor/model:free", "input": "hi"}));
+        for reply in [json!({"id": "gen-1", "choices": []}), json!({"id": "gen-1"})] {
+            let resp = translate_response(&reply, &req);
+            assert_eq!(resp["status"], "incomplete", "{reply}");
+            assert_eq!(resp["incomplete_details"], json!({"reason": "empty_response"}));
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_without_choices_is_incomplete() {
+        let body = json!({"model": "vendor/model:free", "input": "hi", "stream": true});
+        let events = stream_events(&Config::from_env(), translate(body), upstream(&[json!({
+            "choices": [],
+            "usage": {"prompt_tokens": 7, "completion_tokens": 0, "total_tokens": 7}
+        })]))
+        .await;
+        let resp = &event(&events, "response.incomplete")["response"];
+        assert_eq!(resp["incomplete_details"], json!({"reason": "empty_response"}));
+    }
+
+    #[test]
+    fn every_mapped_finish_reason_is_covered() {
+        let tested = ["stop", "tool_calls", "function_call", "length", "content_filter"];
+        let mapped: Vec<&str> = FINISH_REASONS.iter().map(|&(reason, _, _)| reason).collect();
+        assert_eq!(mapped, tested);
+    }
+
+    fn system_messages(req: &TranslatedRequest) -> Vec<&str> {
+        let messages = req.cc_body["messages"].as_array().unwrap();
+        messages
+            .iter()
+            .filter(|m| m["role"] == "system")
+            .map(|m| m["content"].as_str().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn instructions_lead_input_system_and_developer_messages() {
+        let req = translate(json!({
+            "model": "vendor/model:free",
+            "instructions": "top-level",
+            "input": [
+                {"role": "developer", "content": "first developer"},
+                {"role": "user", "content": "hi"},
+                {"type": "message", "role": "system", "content": "then system"},
+                {"role": "developer", "content": [{"type": "input_text", "text": "last"}]}
+            ]
+        }));
+        assert_eq!(system_messages(&req), ["top-level", "first developer", "then system", "last"]);
+        assert_eq!(req.cc_body["messages"][0]["content"], "top-level");
+        assert_eq!(req.instructions, "top-level");
+    }
+
+    #[test]
+    fn input_developer_messages_alone_keep_their_order() {
+        let req = translate(json!({
+            "model": "vendor/model:free",
+            "input": [
+                {"role": "user", "content": "hi"},
+                {"role": "developer", "content": "a"},
+                {"role": "system", "content": "b"}
+            ]
+        }));
+        assert_eq!(system_messages(&req), ["a", "b"]);
+        assert_eq!(req.cc_body["messages"][0]["role"], "user");
+    }
+
+    #[test]
+    fn every_input_shape_maps_roles_alike() {
+        let parts = json!([{"type": "input_text", "text": "x"}]);
+        for role in ["developer", "system", "user", "assistant"] {
+            let input = json!([
+                {"role": role, "content": "x"},
+                {"role": role, "content": parts},
+                {"type": "message", "role": role, "content": "x"},
+                {"type": "message", "role": role, "content": parts}
+            ]);
+            let req = translate(json!({"model": "vendor/model:free", "input": input}));
+            let messages = req.cc_body["messages"].as_array().unwrap();
+            let expected = json!({"role": chat_role(role), "content": "x"});
+            assert_eq!(messages, &vec![expected; 4], "role {role}");
+        }
+        assert_eq!(chat_role("developer"), "system");
+    }
+
+    #[test]
+    fn input_audio_parts_become_chat_audio_blocks() {
+        let input = json!([{"role": "user", "content": [
+            {"type": "input_text", "text": "transcribe"},
+            {"type": "input_audio", "input_audio": {"data": "UklGRg==", "format": "wav"}},
+            {"type": "input_audio", "data": "SUQz", "format": "mp3"}
+        ]}]);
+        let req = translate(json!({"model": "vendor/model:free", "input": input}));
+        assert_eq!(req.cc_body["messages"][0]["content"], json!([
+            {"type": "text", "text": "transcribe"},
+            {"type": "input_audio", "input_audio": {"data": "UklGRg==", "format": "wav"}},
+            {"type": "input_audio", "input_audio": {"data": "SUQz", "format": "mp3"}}
+        ]));
+    }
+
+    #[test]
+    fn input_audio_is_rejected_for_models_without_audio() {
+        let text_only: Model = serde_json::from_value(json!({
+            "id": "vendor/text:free",
+            "name": "Text",
+            "architecture": {"modality": "text->text"}
+        }))
+        .unwrap();
+        let input = json!([{"role": "user", "content": [
+            {"type": "input_audio", "input_audio": {"data": "UklGRg==", "format": "wav"}}
+        ]}]);
+        let body = json!({"model": "vendor/text:free", "input": input});
+        let err = translate_request(&body, &text_only, &[]).err().unwrap();
+        assert_eq!(err, "The model 'vendor/text:free' does not accept audio input");
+    }
+
+    #[test]
+    fn prediction_reaches_supporting_models_only() {
+        let prediction = json!({"type": "content", "content": "fn main() {}"});
+        let body = json!({"model": "vendor/model:free", "input": "edit", "prediction": prediction});
+        let supporting = translate_request(&body, &model_with(&["prediction"]), &[]).unwrap();
+        assert_eq!(supporting.cc_body["prediction"], prediction);
+        let other = translate_request(&body, &model(), &[]).unwrap();
+        assert!(other.cc_body.get("prediction").is_none());
+    }
+
+    #[test]
+    fn function_calls_round_trip_through_input() {
+        let req = translate(json!({"model": "vendor/model:free", "input": "weather?"}));
+        let call = |id: &str, city: &str| {
+            json!({
+                "id": id,
+                "type": "function",
+                "function": {"name": "weather", "arguments": format!("{{\"city\":\"{city}\"}}")}
+            })
+        };
+        let reply = chat_reply(json!({
+            "role": "assistant",
+            "content": "Checking both.",
+            "tool_calls": [call("call_1", "Oslo"), call("call_2", "Rome")]
+        }), "tool_calls");
+        let output = translate_response(&reply, &req)["output"].clone();
+
+        let mut input = vec![json!({"role": "user", "content": "weather?"})];
+        input.extend(output.as_array().unwrap().iter().cloned());
+        for id in ["call_1", "call_2"] {
+            input.push(json!({"type": "function_call_output", "call_id": id, "output": "sunny"}));
+        }
+        let next = translate(json!({"model": "vendor/model:free", "input": input}));
+        assert_eq!(next.cc_body["messages"], json!([
+            {"role": "user", "content": "weather?"},
+            {
+                "role": "assistant",
+                "content": "Checking both.",
+                "tool_calls": [call("call_1", "Oslo"), call("call_2", "Rome")]
+            },
+            {"role": "tool", "tool_call_id": "call_1", "content": "sunny"},
+            {"role": "tool", "tool_call_id": "call_2", "content": "sunny"}
+        ]));
+    }
+
+    #[test]
+    fn sampling_params_reach_supporting_models_only() {
+        for (param, value) in [
+            ("top_k", json!(40)),
+            ("min_p", json!(0.05)),
+            ("top_a", json!(0.2)),
+            ("repetition_penalty", json!(1.1)),
+        ] {
+            let mut body = json!({"model": "vendor/model:free", "input": "hi"});
+            body[param] = value.clone();
+            let supporting = translate_request(&body, &model_with(&[param]), &[]).unwrap();
+            assert_eq!(supporting.cc_body[param], value, "{param}");
+            let other = translate_request(&body, &model(), &[]).unwrap();
+            assert!(other.cc_body.get(param).is_none(), "{param}");
+        }
+    }
+
+    fn image(url: &str) -> Value {
+        json!({"type": "image_url", "image_url": {"url": url}})
+    }
+
+    #[test]
+    fn image_item_unwraps_data_urls_and_keeps_plain_urls() {
+        let item = image_item(&image("data:image/png;base64,iVBORw0KGgo=")).unwrap();
+        assert_eq!(item["type"], "image_generation_call");
+        assert_eq!(item["status"], "completed");
+        assert_eq!(item["result"], "iVBORw0KGgo=");
+        assert_eq!(item["output_format"], "png");
+
+        let item = image_item(&image("https://example.com/cat.webp")).unwrap();
+        assert_eq!(item["result"], "https://example.com/cat.webp");
+        assert!(item.get("output_format").is_none());
+
+        assert!(image_item(&json!({"type": "image_url"})).is_none());
+    }
+
+    #[test]
+    fn generated_images_follow_the_reply_text() {
+        let req = translate(json!({"model": "vendor/model:free", "input": "draw a cat"}));
+        let message = json!({
+            "role": "assistant",
+            "content": "Here you go.",
+            "images": [image("data:image/png;base64,iVBORw0KGgo=")]
+        });
+        let resp = translate_response(&chat_reply(message, "stop"), &req);
+        let output = resp["output"].as_array().unwrap();
+        let types: Vec<&Value> = output.iter().map(|item| &item["type"]).collect();
+        assert_eq!(types, ["message", "image_generation_call"]);
+        assert_eq!(resp["output"][1]["result"], "iVBORw0KGgo=");
+        assert_eq!(resp["status"], "completed");
+    }
+
+    #[tokio::test]
+    async fn streamed_images_are_sent_at_completion() {
+        let body = json!({"model": "vendor/model:free", "input": "draw a cat", "stream": true});
+        let events = stream_events(&Config::from_env(), translate(body), upstream(&[
+            json!({"choices": [{"index": 0, "delta": {"content": "Here you go."}}]}),
+            json!({"choices": [{"index": 0, "delta": {
+                "images": [image("https://example.com/cat.png")]
+            }}]}),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}]}),
+        ]))
+        .await;
+        let added = event(&events, "response.output_item.added");
+        assert_eq!(added["item"]["type"], "message");
+        let done = event(&events, "response.image_generation_call.completed");
+        assert_eq!(done["output_index"], 1);
+        let output = &event(&events, "response.completed")["response"]["output"];
+        assert_eq!(output[1]["type"], "image_generation_call");
+        assert_eq!(output[1]["result"], "https://example.com/cat.png");
+    }
+
+    #[test]
+    fn max_output_tokens_uses_the_models_field() {
+        let body = json!({"model": "vendor/model:free", "input": "hi", "max_output_tokens": 64});
+        let newer = translate_request(&body, &model_with(&["max_completion_tokens"]), &[]).unwrap();
+        assert_eq!(newer.cc_body["max_completion_tokens"], 64);
+        assert!(newer.cc_body.get("max_tokens").is_none());
+
+        let older = translate(body);
+        assert_eq!(older.cc_body["max_tokens"], 64);
+        assert!(older.cc_body.get("max_completion_tokens").is_none());
+    }
+
+    fn translate_stop(stop: Value, target: &Model) -> Result<Value, String> {
+        let body = json!({"model": "vendor/model:free", "input": "count", "stop": stop});
+        translate_request(&body, target, &[]).map(|req| req.cc_body["stop"].clone())
+    }
+
+    #[test]
+    fn stop_string_and_array_are_forwarded() {
+        let target = model_with(&["stop"]);
+        assert_eq!(translate_stop(json!("END"), &target), Ok(json!("END")));
+        assert_eq!(translate_stop(json!(["\n\n", "END"]), &target), Ok(json!(["\n\n", "END"])));
+        assert_eq!(translate_stop(Value::Null, &target), Ok(Value::Null));
+    }
+
+    #[test]
+    fn stop_is_dropped_for_models_without_it() {
+        assert_eq!(translate_stop(json!("END"), &model()), Ok(Value::Null));
+        assert_eq!(translate_stop(json!(["END"]), &model()), Ok(Value::Null));
+    }
+
+    #[test]
+    fn invalid_stop_is_rejected() {
+        let target = model_with(&["stop"]);
+        assert!(translate_stop(json!(3), &target).is_err());
+        assert!(translate_stop(json!(["END", 3]), &target).is_err());
+        assert!(translate_stop(json!({"text": "END"}), &model()).is_err());
+    }
+
+    fn message_content(output: &Value) -> Vec<Value> {
+        let output = output.as_array().unwrap();
+        let message = output.iter().find(|item| item["type"] == "message").unwrap();
+        message["content"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn refusal_only_reply_becomes_a_refusal_part() {
+        let req = translate(json!({"model": "vendor/model:free", "input": "something bad"}));
+        let message = json!({"role": "assistant", "content": null, "refusal": "I can't help."});
+        let resp = translate_response(&chat_reply(message, "stop"), &req);
+        let content = message_content(&resp["output"]);
+        assert!(content.contains(&json!({"type": "refusal", "refusal": "I can't help."})));
+        assert!(!content.iter().any(|p| p["type"] == "output_text" && p["text"] != ""));
+    }
+
+    #[tokio::test]
+    async fn streamed_refusal_becomes_a_refusal_part() {
+        let body = json!({"model": "vendor/model:free", "input": "something bad", "stream": true});
+        let refusal = |text: &str| json!({"choices": [{"index": 0, "delta": {"refusal": text}}]});
+        let events = stream_events(&Config::from_env(), translate(body), upstream(&[
+            refusal("I can't "),
+            refusal("help."),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}]}),
+        ]))
+        .await;
+        let deltas: Vec<&Value> = events
+            .iter()
+            .filter(|e| e["type"] == "response.refusal.delta")
+            .map(|e| &e["delta"])
+            .collect();
+        assert_eq!(deltas, [&json!("I can't "), &json!("help.")]);
+        assert_eq!(event(&events, "response.refusal.done")["refusal"], "I can't help.");
+        let completed = event(&events, "response.completed");
+        let content = message_content(&completed["response"]["output"]);
+        assert!(content.contains(&json!({"type": "refusal", "refusal": "I can't help."})));
+    }
+
+    #[test]
+    fn streaming_requests_ask_for_usage() {
+        let req = translate(json!({"model": "vendor/model:free", "input": "hi", "stream": true}));
+        assert_eq!(req.cc_body["stream"], true);
+        assert_eq!(req.cc_body["stream_options"], json!({"include_usage": true}));
+
+        let req = translate(json!({"model": "vendor/model:free", "input": "hi"}));
+        assert!(req.cc_body.get("stream_options").is_none());
+    }
+
+    #[test]
+    fn extra_body_cannot_turn_off_stream_usage() {
+        let req = translate(json!({
+            "model": "vendor/model:free",
+            "input": "hi",
+            "stream": true,
+            "extra_body": {"stream_options": {"include_usage": false}}
+        }));
+        assert_eq!(req.cc_body["stream_options"], json!({"include_usage": true}));
+    }
+
+    #[tokio::test]
+    async fn streamed_usage_reaches_the_completed_event() {
+        let body = json!({"model": "vendor/model:free", "input": "hi", "stream": true});
+        let events = stream_events(&Config::from_env(), translate(body), upstream(&[
+            json!({"choices": [{"index": 0, "delta": {"content": "Hello"}}]}),
+            json!({"choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}]}),
+            json!({
+                "choices": [],
+                "usage": {"prompt_tokens": 7, "completion_tokens": 2, "total_tokens": 9}
+            }),
+        ]))
+        .await;
+        let usage = &event(&events, "response.completed")["response"]["usage"];
+        assert_eq!(usage["input_tokens"], 7);
+        assert_eq!(usage["output_tokens"], 2);
+        assert_eq!(usage["total_tokens"], 9);
+    }
+}
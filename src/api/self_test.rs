@@ -0,0 +1,78 @@
+use super::responses::{translate_request, translate_response};
+use crate::config::Config;
+use crate::model::Model;
+use serde_json::{json, Value};
+
+/// Responses requests and upstream replies with the translations they must
+/// produce. Expected values list only the fields that matter, so generated
+/// ids and timestamps don't count.
+const FIXTURES: &str = include_str!("self_test.json");
+
+/// Runs every fixture through `translate_request` (and `translate_response`
+/// when it has a `chat_response`), returning a description of each mismatch.
+pub fn run(config: &Config) -> Result<usize, Vec<String>> {
+    let fixtures: Vec<Value> = serde_json::from_str(FIXTURES).expect("self_test.json is valid");
+    let target: Model = serde_json::from_value(json!({
+        "id": "vendor/test-model:free",
+        "name": "Self-test model",
+        "supported_parameters": ["tools", "tool_choice", "temperature", "max_tokens"]
+    }))
+    .expect("self-test model is valid");
+
+    let mut mismatches = Vec::new();
+    for fixture in &fixtures {
+        let name = fixture["name"].as_str().unwrap_or("unnamed");
+        let passthrough = &config.responses_passthrough;
+        let req = match translate_request(&fixture["request"], &target, passthrough) {
+            Ok(req) => req,
+            Err(e) => {
+                mismatches.push(format!("{name}: request rejected: {e}"));
+                continue;
+            }
+        };
+        if let Some(expected) = fixture.get("chat_request") {
+            if let Err(e) = contains(&req.cc_body, expected, "chat_request") {
+                mismatches.push(format!("{name}: {e}"));
+            }
+        }
+        if let Some(reply) = fixture.get("chat_response") {
+            let translated = translate_response(reply, &req);
+            if let Err(e) = contains(&translated, &fixture["response"], "response") {
+                mismatches.push(format!("{name}: {e}"));
+            }
+        }
+    }
+    if mismatches.is_empty() {
+        Ok(fixtures.len())
+    } else {
+        Err(mismatches)
+    }
+}
+
+/// Checks that every field of `expected` appears in `actual` with the same
+/// value. Objects may have extra fields; arrays must match element by element.
+fn contains(actual: &Value, expected: &Value, path: &str) -> Result<(), String> {
+    match (actual, expected) {
+        (Value::Object(have), Value::Object(want)) => {
+            for (key, value) in want {
+                let path = format!("{path}.{key}");
+                match have.get(key) {
+                    Some(found) => contains(found, value, &path)?,
+                    None => return Err(format!("{path} is missing")),
+                }
+            }
+            Ok(())
+        }
+        (Value::Array(have), Value::Array(want)) => {
+            if have.len() != want.len() {
+                return Err(format!("{path} has {} items, expected {}", have.len(), want.len()));
+            }
+            for (i, (found, value)) in have.iter().zip(want).enumerate() {
+                contains(found, value, &format!("{path}[{i}]"))?;
+            }
+            Ok(())
+        }
+        _ if actual == expected => Ok(()),
+        _ => Err(format!("{path} is {actual}, expected {expected}")),
+    }
+}
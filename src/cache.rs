@@ -0,0 +1,448 @@
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A complete upstream response, already filtered for relaying.
+#[derive(Clone)]
+pub struct Cached {
+    pub status: u16,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: Bytes,
+}
+
+struct Entry {
+    stored: Instant,
+    last_used: u64,
+    response: Cached,
+}
+
+/// Everything that makes two requests interchangeable: the path, the caller's
+/// credentials and accepted encodings (bodies are kept as upstream encoded
+/// them), and the normalized body (`serde_json` maps serialize with sorted
+/// keys). Maps keyed by it compare the whole key, so a hash collision can't
+/// hand one caller's response to another.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct RequestKey {
+    path: String,
+    auth: Option<HeaderValue>,
+    encoding: Option<HeaderValue>,
+    body: String,
+}
+
+impl RequestKey {
+    pub fn new(path: &str, headers: &HeaderMap, json: &Value) -> Self {
+        Self {
+            path: path.to_owned(),
+            auth: headers.get("authorization").cloned(),
+            encoding: headers.get("accept-encoding").cloned(),
+            body: json.to_string(),
+        }
+    }
+}
+
+/// Small LRU of non-streaming responses to deterministic requests.
+pub struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<(HashMap<RequestKey, Entry>, u64)>,
+}
+
+impl ResponseCache {
+    /// A `capacity` of 0 disables caching.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            inner: Mutex::new((HashMap::new(), 0)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Only non-streaming requests whose output shouldn't vary between calls:
+    /// temperature 0 or an explicit `seed`.
+    pub fn is_cacheable(json: &Value) -> bool {
+        let streaming = json.get("stream").and_then(Value::as_bool).unwrap_or(false);
+        let greedy = json.get("temperature").and_then(Value::as_f64) == Some(0.0);
+        let seeded = json.get("seed").is_some_and(|v| !v.is_null());
+        !streaming && (greedy || seeded)
+    }
+
+    pub fn get(&self, key: &RequestKey) -> Option<Cached> {
+        let mut guard = self.inner.lock().unwrap();
+        let (entries, clock) = &mut *guard;
+        let entry = entries.get_mut(key)?;
+        if entry.stored.elapsed() >= self.ttl {
+            entries.remove(key);
+            return None;
+        }
+        *clock += 1;
+        entry.last_used = *clock;
+        Some(entry.response.clone())
+    }
+
+    pub fn insert(&self, key: RequestKey, response: Cached) {
+        let mut guard = self.inner.lock().unwrap();
+        let (entries, clock) = &mut *guard;
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, e| e.stored.elapsed() < self.ttl);
+            if entries.len() >= self.capacity {
+                let oldest = entries.iter().min_by_key(|(_, e)| e.last_used);
+                if let Some(k) = oldest.map(|(k, _)| k.clone()) {
+                    entries.remove(&k);
+                }
+            }
+        }
+        *clock += 1;
+        entries.insert(
+            key,
+            Entry {
+                stored: Instant::now(),
+                last_used: *clock,
+                response,
+            },
+        );
+    }
+}
+
+/// One `Idempotency-Key`'s outcome. The first request holds `result` locked
+/// while it runs, so concurrent repeats wait for it instead of calling
+/// upstream again.
+pub struct IdempotencySlot {
+    created: Instant,
+    body: String,
+    pub result: tokio::sync::Mutex<Option<Cached>>,
+}
+
+/// An `Idempotency-Key` scoped to the request path and the caller's own
+/// credentials, and to accepted encodings since bodies are stored as upstream
+/// encoded them. Compared in full, like `RequestKey`.
+#[derive(PartialEq, Eq, Hash)]
+pub struct IdempotencyKey {
+    path: String,
+    auth: HeaderValue,
+    encoding: Option<HeaderValue>,
+    key: String,
+}
+
+/// Non-streaming responses remembered per `Idempotency-Key` for a TTL.
+pub struct IdempotencyStore {
+    ttl: Duration,
+    slots: Mutex<HashMap<IdempotencyKey, Arc<IdempotencySlot>>>,
+}
+
+impl IdempotencyStore {
+    /// A `ttl` of zero disables the store.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// `auth` is the caller's own `Authorization`, not one injected for them.
+    pub fn key(
+        path: &str,
+        auth: HeaderValue,
+        headers: &HeaderMap,
+        idempotency_key: &str,
+    ) -> IdempotencyKey {
+        IdempotencyKey {
+            path: path.to_owned(),
+            auth,
+            encoding: headers.get("accept-encoding").cloned(),
+            key: idempotency_key.to_owned(),
+        }
+    }
+
+    /// The slot for `key`, created if missing or expired. `None` when the key
+    /// was already used for a different request body.
+    pub fn slot(&self, key: IdempotencyKey, json: &Value) -> Option<Arc<IdempotencySlot>> {
+        let body = json.to_string();
+        let mut slots = self.slots.lock().unwrap();
+        slots.retain(|_, s| s.created.elapsed() < self.ttl);
+        let slot = slots.entry(key).or_insert_with(|| {
+            Arc::new(IdempotencySlot {
+                created: Instant::now(),
+                body: body.clone(),
+                result: tokio::sync::Mutex::new(None),
+            })
+        });
+        (slot.body == body).then(|| slot.clone())
+    }
+}
+
+/// Identifies a caller by their `Authorization` header, for state only they
+/// may see or change.
+pub fn caller_key(headers: &HeaderMap) -> u64 {
+    let mut h = DefaultHasher::new();
+    headers.get("authorization").map(HeaderValue::as_bytes).hash(&mut h);
+    h.finish()
+}
+
+struct StoredResponse {
+    stored: Instant,
+    owner: u64,
+    response: Value,
+}
+
+/// Finished Responses API responses, kept for `GET /v1/responses/{id}` and
+/// visible only to the credentials that created them. Cheap to clone, so
+/// stream tasks can store the response once it completes.
+#[derive(Clone)]
+pub struct ResponseStore {
+    capacity: usize,
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, StoredResponse>>>,
+}
+
+impl ResponseStore {
+    /// A `capacity` of 0 disables the store.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Stores `response` under its `id`, evicting the oldest entry when full.
+    pub fn insert(&self, owner: u64, response: Value) {
+        let Some(id) = response["id"].as_str().map(str::to_string) else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, e| e.stored.elapsed() < self.ttl);
+        if entries.len() >= self.capacity && !entries.contains_key(&id) {
+            let oldest = entries.iter().min_by_key(|(_, e)| e.stored).map(|(k, _)| k.clone());
+            if let Some(k) = oldest {
+                entries.remove(&k);
+            }
+        }
+        let entry = StoredResponse {
+            stored: Instant::now(),
+            owner,
+            response,
+        };
+        entries.insert(id, entry);
+    }
+
+    pub fn get(&self, owner: u64, id: &str) -> Option<Value> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(id)?;
+        (entry.owner == owner && entry.stored.elapsed() < self.ttl).then(|| entry.response.clone())
+    }
+
+    /// Whether a live response was removed.
+    pub fn remove(&self, owner: u64, id: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(id).map(|e| e.owner) != Some(owner) {
+            return false;
+        }
+        entries.remove(id).is_some_and(|e| e.stored.elapsed() < self.ttl)
+    }
+}
+
+type Flights = Arc<Mutex<HashMap<RequestKey, Arc<Flight>>>>;
+
+/// Output a flight keeps for requests that haven't joined yet. Past it, later
+/// identical requests go upstream on their own and nothing more is kept
+/// unless a follower is still replaying.
+const FLIGHT_BUFFER_BYTES: usize = 1 << 20;
+
+/// Streaming requests in flight, by `RequestKey`, so identical
+/// ones made meanwhile replay the first one's output (`COALESCE_INFLIGHT`).
+pub struct InflightStreams {
+    enabled: bool,
+    flights: Flights,
+}
+
+/// Whether a request runs upstream itself or replays an identical one.
+pub enum Joined {
+    Leader(FlightLeader),
+    Follower(FlightFollower),
+}
+
+/// The output of one streaming request so far, so followers replay from the
+/// start. Chunks are kept while the flight takes new followers (its first
+/// `FLIGHT_BUFFER_BYTES`), then only while a follower is still replaying.
+#[derive(Default)]
+pub struct Flight {
+    progress: Mutex<FlightProgress>,
+    changed: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct FlightProgress {
+    head: Option<(StatusCode, HeaderMap)>,
+    chunks: Vec<Bytes>,
+    bytes: usize,
+    followers: usize,
+    /// Past `FLIGHT_BUFFER_BYTES`: no new followers.
+    closed: bool,
+    done: bool,
+}
+
+/// Held by the request that calls upstream. Dropping it (when its stream ends
+/// or it fails) closes the flight to new followers and ends existing ones.
+pub struct FlightLeader {
+    key: RequestKey,
+    flight: Arc<Flight>,
+    flights: Flights,
+}
+
+impl FlightLeader {
+    /// Stops offering this flight to new requests, unless another has already
+    /// taken its key.
+    fn close(&self) {
+        let mut flights = self.flights.lock().unwrap();
+        if flights.get(&self.key).is_some_and(|f| Arc::ptr_eq(f, &self.flight)) {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+/// A request replaying a flight. Dropping it lets the flight free its chunks
+/// once no follower is left and no new ones can join.
+pub struct FlightFollower(Arc<Flight>);
+
+impl std::ops::Deref for FlightFollower {
+    type Target = Flight;
+
+    fn deref(&self) -> &Flight {
+        &self.0
+    }
+}
+
+impl Drop for FlightFollower {
+    fn drop(&mut self) {
+        let mut progress = self.0.progress.lock().unwrap();
+        progress.followers -= 1;
+        if progress.followers == 0 && progress.closed {
+            progress.chunks = Vec::new();
+        }
+    }
+}
+
+impl InflightStreams {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            flights: Arc::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Follows the flight already running for `key`, or starts one.
+    pub fn join(&self, key: RequestKey) -> Joined {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(&key) {
+            let mut progress = flight.progress.lock().unwrap();
+            // Checked under the lock `push` closes with, so chunks a new
+            // follower needs can't be dropped meanwhile.
+            if !progress.closed {
+                progress.followers += 1;
+                drop(progress);
+                return Joined::Follower(FlightFollower(flight.clone()));
+            }
+        }
+        let flight = Arc::new(Flight::default());
+        flights.insert(key.clone(), flight.clone());
+        Joined::Leader(FlightLeader {
+            key,
+            flight,
+            flights: self.flights.clone(),
+        })
+    }
+}
+
+impl Flight {
+    /// Waits for the leader's response status and headers; `None` if it
+    /// failed before getting a response.
+    pub async fn head(&self) -> Option<(StatusCode, HeaderMap)> {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let progress = self.progress.lock().unwrap();
+                if let Some(ref head) = progress.head {
+                    return Some(head.clone());
+                }
+                if progress.done {
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Waits for chunk `index`; `None` once the stream ended before it.
+    pub async fn chunk(&self, index: usize) -> Option<Bytes> {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let progress = self.progress.lock().unwrap();
+                if let Some(chunk) = progress.chunks.get(index) {
+                    return Some(chunk.clone());
+                }
+                if progress.done {
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+impl FlightLeader {
+    pub fn start(&self, status: StatusCode, headers: HeaderMap) {
+        self.flight.progress.lock().unwrap().head = Some((status, headers));
+        self.flight.changed.notify_waiters();
+    }
+
+    pub fn push(&self, chunk: &Bytes) {
+        let mut progress = self.flight.progress.lock().unwrap();
+        if progress.closed && progress.followers == 0 {
+            return;
+        }
+        progress.chunks.push(chunk.clone());
+        progress.bytes += chunk.len();
+        let closing = !progress.closed && progress.bytes > FLIGHT_BUFFER_BYTES;
+        if closing {
+            progress.closed = true;
+            if progress.followers == 0 {
+                progress.chunks = Vec::new();
+            }
+        }
+        drop(progress);
+        if closing {
+            self.close();
+        }
+        self.flight.changed.notify_waiters();
+    }
+}
+
+impl Drop for FlightLeader {
+    fn drop(&mut self) {
+        self.close();
+        self.flight.progress.lock().unwrap().done = true;
+        self.flight.changed.notify_waiters();
+    }
+}
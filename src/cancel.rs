@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+struct Entry {
+    /// `None` for streams started without the caller's own credentials,
+    /// which nobody can tell apart and so nobody may cancel.
+    owner: Option<u64>,
+    cancel: Arc<Notify>,
+}
+
+/// Streams in flight that `POST /cancel/{id}` can stop, by stream id.
+#[derive(Default)]
+pub struct StreamRegistry {
+    streams: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+/// A registered stream. Dropping it (when the stream ends) unregisters it.
+pub struct StreamTicket {
+    pub id: String,
+    pub cancel: Arc<Notify>,
+    streams: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Drop for StreamTicket {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl StreamRegistry {
+    /// Registers a stream under a random id, so ids can't be guessed.
+    pub fn register(&self, owner: Option<u64>) -> StreamTicket {
+        let mut streams = self.streams.lock().unwrap();
+        let id = loop {
+            let mut bytes = [0u8; 16];
+            getrandom::fill(&mut bytes).expect("OS random number generator is available");
+            let id: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            let id = format!("stream_{id}");
+            if !streams.contains_key(&id) {
+                break id;
+            }
+        };
+        let cancel = Arc::new(Notify::new());
+        let entry = Entry {
+            owner,
+            cancel: cancel.clone(),
+        };
+        streams.insert(id.clone(), entry);
+        StreamTicket {
+            id,
+            cancel,
+            streams: self.streams.clone(),
+        }
+    }
+
+    /// Stops the stream `id` if it's still running and belongs to `owner`.
+    pub fn cancel(&self, id: &str, owner: u64) -> bool {
+        match self.streams.lock().unwrap().get(id) {
+            Some(entry) if entry.owner == Some(owner) => {
+                // A stored permit, so a stream not yet waiting still stops.
+                entry.cancel.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+}
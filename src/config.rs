@@ -1,31 +1,1013 @@
+use crate::model::{IdDisplayMode, Model};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+/// Sampling parameters injected into forwarded requests that omit them.
+#[derive(Clone, Default)]
+pub struct SamplingDefaults {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+}
+
+impl SamplingDefaults {
+    fn from_env(prefix: &str, invalid: &mut Vec<String>) -> Self {
+        Self {
+            temperature: env_parse(&format!("{prefix}_DEFAULT_TEMPERATURE"), invalid),
+            top_p: env_parse(&format!("{prefix}_DEFAULT_TOP_P"), invalid),
+        }
+    }
+
+    /// Fills in missing fields of a request body; client-supplied values win.
+    /// Returns whether the body changed.
+    pub fn apply(&self, body: &mut Value) -> bool {
+        let Some(obj) = body.as_object_mut() else {
+            return false;
+        };
+        let mut changed = false;
+        for (key, default) in [("temperature", self.temperature), ("top_p", self.top_p)] {
+            if let Some(v) = default {
+                if !obj.contains_key(key) {
+                    obj.insert(key.into(), v.into());
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// `service_tier` values accepted from clients and `DEFAULT_SERVICE_TIER`.
+pub const SERVICE_TIERS: &[&str] = &["auto", "default", "flex", "priority", "scale"];
+
+/// Values OpenRouter accepts for `route`, its choice of how to use a `models`
+/// fallback list.
+pub const ROUTES: &[&str] = &["fallback"];
+
+/// Sampling fields checked by `VALIDATE_SAMPLING_PARAMS`, with their inclusive
+/// range.
+pub const SAMPLING_RANGES: &[(&str, f64, f64)] = &[("temperature", 0.0, 2.0), ("top_p", 0.0, 1.0)];
+
+/// Declarative request rewrite for models matching `pattern` (a model id where
+/// `*` matches any run of characters), loaded from `MODEL_TRANSFORMS`.
+#[derive(Clone, Deserialize)]
+pub struct Transform {
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Top-level request fields to remove.
+    #[serde(default)]
+    pub drop: Vec<String>,
+    /// Message roles to rewrite, e.g. `{"system": "user"}`.
+    #[serde(default)]
+    pub rename_roles: HashMap<String, String>,
+    /// Numeric fields to clamp into `[min, max]`.
+    #[serde(default)]
+    pub clamp: HashMap<String, (f64, f64)>,
+}
+
+impl Transform {
+    pub fn matches(&self, model_id: &str) -> bool {
+        glob_match(&self.pattern, model_id)
+    }
+
+    /// Rewrites a request body in place. Returns whether the body changed.
+    pub fn apply(&self, body: &mut Value) -> bool {
+        let Some(obj) = body.as_object_mut() else {
+            return false;
+        };
+        let mut changed = false;
+        for key in &self.drop {
+            changed |= obj.remove(key).is_some();
+        }
+        for (key, &(min, max)) in &self.clamp {
+            if let Some(v) = obj.get(key).and_then(Value::as_f64) {
+                let clamped = v.clamp(min, max);
+                if clamped != v {
+                    obj.insert(key.clone(), clamped.into());
+                    changed = true;
+                }
+            }
+        }
+        if let Some(Value::Array(messages)) = obj.get_mut("messages") {
+            for msg in messages {
+                let role = msg.get("role").and_then(Value::as_str);
+                if let Some(to) = role.and_then(|r| self.rename_roles.get(r)) {
+                    msg["role"] = Value::String(to.clone());
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// A tier defined in `CUSTOM_TIERS`, served at `/{prefix}/v1` next to the
+/// built-in ones. A model joins every custom tier whose rules it meets.
+#[derive(Clone, Deserialize)]
+pub struct CustomTier {
+    pub name: String,
+    /// Route prefix; defaults to `name`.
+    #[serde(default)]
+    prefix: Option<String>,
+    /// Health-check members with the shared key, like the free tier.
+    #[serde(default)]
+    pub health_check: bool,
+    /// Model used for requests that don't name one.
+    #[serde(default)]
+    pub default_model: Option<String>,
+    #[serde(rename = "match", default)]
+    pub rules: TierRules,
+}
+
+/// Conditions a model must all meet to join a custom tier; unset ones pass.
+#[derive(Clone, Default, Deserialize)]
+pub struct TierRules {
+    #[serde(default)]
+    id_prefix: Option<String>,
+    #[serde(default)]
+    id_suffix: Option<String>,
+    /// Highest prompt and completion price per token, in USD.
+    #[serde(default)]
+    max_price: Option<f64>,
+    /// Any of these in the id, name or description (case-insensitive).
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// Input modalities the model must all accept, e.g. `["image"]`.
+    #[serde(default)]
+    input_modalities: Vec<String>,
+}
+
+/// Top-level paths custom tier prefixes can't take.
+const RESERVED_PREFIXES: &[&str] =
+    &["free", "stealth", "routers", "health", "status", "metrics", "openapi.json", "admin"];
+
+impl CustomTier {
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(&self.name)
+    }
+}
+
+impl TierRules {
+    pub fn matches(&self, model: &Model) -> bool {
+        if self.id_prefix.as_ref().is_some_and(|p| !model.id.starts_with(p.as_str())) {
+            return false;
+        }
+        if self.id_suffix.as_ref().is_some_and(|s| !model.id.ends_with(s.as_str())) {
+            return false;
+        }
+        if let Some(max) = self.max_price {
+            // Unknown prices never pass a price limit.
+            let Some(pricing) = model.pricing.as_ref() else {
+                return false;
+            };
+            let within = |p: &Option<String>| {
+                p.as_deref()
+                    .and_then(|p| p.parse::<f64>().ok())
+                    .is_some_and(|p| (0.0..=max).contains(&p))
+            };
+            if !within(&pricing.prompt) || !within(&pricing.completion) {
+                return false;
+            }
+        }
+        if !self.keywords.is_empty() {
+            let text = format!(
+                "{} {} {}",
+                model.id,
+                model.name,
+                model.description.as_deref().unwrap_or("")
+            )
+            .to_lowercase();
+            if !self.keywords.iter().any(|k| text.contains(&k.to_lowercase())) {
+                return false;
+            }
+        }
+        self.input_modalities.iter().all(|m| model.accepts_input(m))
+    }
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// A numeric env var, `None` when unset or empty. A value that doesn't parse
+/// is recorded in `invalid` for `Config::validate` and treated as unset.
+fn env_parse<T: std::str::FromStr>(name: &str, invalid: &mut Vec<String>) -> Option<T> {
+    let raw = env::var(name).ok().filter(|v| !v.trim().is_empty())?;
+    let parsed = raw.trim().parse().ok();
+    if parsed.is_none() {
+        invalid.push(format!("{name} must be a number (got '{raw}')"));
+    }
+    parsed
+}
+
+/// A boolean env var: `true`/`1` or `false`/`0`, else `default`. Anything
+/// else is recorded in `invalid` like `env_parse` does.
+fn env_flag(name: &str, default: bool, invalid: &mut Vec<String>) -> bool {
+    let Ok(raw) = env::var(name) else {
+        return default;
+    };
+    match raw.trim() {
+        "" => default,
+        "true" | "1" => true,
+        "false" | "0" => false,
+        _ => {
+            invalid.push(format!("{name} must be true or false (got '{raw}')"));
+            default
+        }
+    }
+}
+
+/// A JSON env var, the default when unset or empty. JSON that doesn't
+/// deserialize is recorded in `invalid` (as `what` it should have been) like
+/// `env_parse` does.
+fn env_json<T: DeserializeOwned + Default>(name: &str, what: &str, invalid: &mut Vec<String>) -> T {
+    let Some(raw) = env::var(name).ok().filter(|v| !v.trim().is_empty()) else {
+        return T::default();
+    };
+    serde_json::from_str(&raw).unwrap_or_else(|e| {
+        invalid.push(format!("{name} must be {what} ({e})"));
+        T::default()
+    })
+}
+
+/// `PATH_PREFIX` as `/a/b`: one leading slash, no trailing one. `None` when
+/// it names the root.
+fn path_prefix(raw: &str) -> Option<String> {
+    let trimmed = raw.trim().trim_matches('/');
+    (!trimmed.is_empty()).then(|| format!("/{trimmed}"))
+}
+
+/// Request fields the Responses translator copies to Chat Completions as-is
+/// unless `RESPONSES_PASSTHROUGH` replaces the list: OpenRouter routing options
+/// and plain chat parameters that need no translation.
+pub const RESPONSES_PASSTHROUGH: &[&str] = &[
+    "models",
+    "provider",
+    "transforms",
+    "plugins",
+    "user",
+    "seed",
+    "frequency_penalty",
+    "presence_penalty",
+    "logit_bias",
+];
+
+/// Inserts `key` with `default`, if any, when `body` doesn't set it. Returns
+/// whether the body changed.
+fn fill_default(body: &mut Value, key: &str, default: Option<Value>) -> bool {
+    let (Some(value), Some(obj)) = (default, body.as_object_mut()) else {
+        return false;
+    };
+    if obj.contains_key(key) {
+        return false;
+    }
+    obj.insert(key.into(), value);
+    true
+}
+
+/// Which upstream models may be served at all, from `MODEL_ALLOWLIST` and
+/// `MODEL_DENYLIST`: comma-separated full ids or `glob_match` patterns.
+#[derive(Clone, Default)]
+pub struct ModelAccess {
+    /// Empty allows every model.
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl ModelAccess {
+    fn from_env() -> Self {
+        let list = |name: &str| -> Vec<String> {
+            env::var(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(|p| p.trim().to_owned())
+                .filter(|p| !p.is_empty())
+                .collect()
+        };
+        Self {
+            allow: list("MODEL_ALLOWLIST"),
+            deny: list("MODEL_DENYLIST"),
+        }
+    }
+
+    /// The deny list wins over the allow list.
+    pub fn permits(&self, id: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, id)))
+            && !self.deny.iter().any(|p| glob_match(p, id))
+    }
+}
+
+/// Upstream response headers always passed through in allow-list mode.
+const ALWAYS_ALLOWED_HEADERS: &[&str] = &[
+    "content-type",
+    "content-encoding",
+    "content-length",
+    "cache-control",
+    "retry-after",
+    "x-request-id",
+    "x-ratelimit-*",
+];
+
+/// Decides which upstream response headers reach clients. Patterns are
+/// lowercase names, optionally ending in `*` to match a prefix.
+#[derive(Clone, Default)]
+pub struct HeaderFilter {
+    pub allow: Option<Vec<String>>,
+    pub strip: Vec<String>,
+}
+
+impl HeaderFilter {
+    fn from_env() -> Self {
+        let list = |v: String| -> Vec<String> {
+            v.split(',')
+                .map(|h| h.trim().to_lowercase())
+                .filter(|h| !h.is_empty())
+                .collect()
+        };
+        Self {
+            allow: env::var("RESPONSE_HEADER_ALLOWLIST").ok().map(list),
+            strip: env::var("STRIP_RESPONSE_HEADERS").map(list).unwrap_or_default(),
+        }
+    }
+
+    pub fn permits(&self, name: &str) -> bool {
+        let matches = |pattern: &str| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        };
+        if self.strip.iter().any(|p| matches(p)) {
+            return false;
+        }
+        match self.allow {
+            Some(ref allow) => {
+                ALWAYS_ALLOWED_HEADERS.iter().any(|p| matches(p)) || allow.iter().any(|p| matches(p))
+            }
+            None => true,
+        }
+    }
+}
+
+const USAGE: &str = "\
+Usage: openrouter-api [OPTIONS]
+
+Options (override the matching environment variables):
+  --host <HOST>          Address to listen on (HOST)
+  --port <PORT>          Port to listen on (PORT)
+  --upstream <URL>       OpenRouter API base URL (UPSTREAM_URL)
+  --no-health-check      Skip model health checks (HEALTH_CHECK_ENABLED=false)
+  -h, --help             Print this help";
 
 #[derive(Clone)]
 pub struct Config {
     pub host: String,
     pub port: u16,
+    pub upstream_url: String,
+    /// Extra path prefix the routes are also served under, e.g. `/llm` when an
+    /// ingress forwards `/llm/free/v1/...` unchanged. Stripped before forwarding.
+    pub path_prefix: Option<String>,
+    /// Shared OpenRouter key; per-tier keys below take precedence.
     pub health_check_key: Option<String>,
+    pub free_api_key: Option<String>,
+    pub stealth_api_key: Option<String>,
+    pub inject_api_key: bool,
+    pub health_check_enabled: bool,
     pub health_check_concurrency: usize,
+    /// Per-tier overrides of `health_check_concurrency`.
+    pub free_health_check_concurrency: Option<usize>,
+    pub stealth_health_check_concurrency: Option<usize>,
+    pub health_check_timeout_secs: u64,
+    pub health_check_delay_ms: u64,
+    /// Failed health checks in a row before a served model is dropped.
+    pub health_fail_threshold: u32,
+    pub model_fetch_timeout_secs: u64,
     pub refresh_interval_secs: u64,
+    pub refresh_jitter_secs: u64,
+    pub admin_token: Option<String>,
+    pub stream_idle_timeout_secs: u64,
+    pub free_defaults: SamplingDefaults,
+    pub stealth_defaults: SamplingDefaults,
+    pub free_default_model: Option<String>,
+    pub stealth_default_model: Option<String>,
+    pub model_aliases: HashMap<String, Vec<String>>,
+    pub model_access: ModelAccess,
+    pub model_transforms: Vec<Transform>,
+    pub custom_tiers: Vec<CustomTier>,
+    pub repair_tool_args: bool,
+    pub rate_limit_rpm: u32,
+    pub rate_limit_user_rpm: u32,
+    pub response_headers: HeaderFilter,
+    pub precheck_context: bool,
+    pub max_concurrent_upstream: usize,
+    pub upstream_queue_timeout_ms: u64,
+    pub response_cache_size: usize,
+    pub response_cache_ttl_secs: u64,
+    pub response_store_size: usize,
+    pub response_store_ttl_secs: u64,
+    pub upstream_ca_bundle: Option<String>,
+    pub upstream_min_tls: String,
+    pub max_tool_calls: Option<usize>,
+    pub hide_reasoning: bool,
+    /// Prepended as a system message to every chat and Responses request.
+    pub inject_system_prompt: Option<String>,
+    pub id_display_mode: String,
+    pub batch_concurrency: usize,
+    pub debug_errors: bool,
+    pub self_test: bool,
+    pub self_test_strict: bool,
+    pub default_service_tier: Option<String>,
+    pub default_route: Option<String>,
+    pub webhook_url: Option<String>,
+    pub coalesce_arg_deltas_ms: u64,
+    pub allow_meta_routers: bool,
+    pub idempotency_ttl_secs: u64,
+    pub large_request_warn_bytes: usize,
+    pub sticky_aliases: bool,
+    pub validate_stream: bool,
+    /// Replay identical streaming requests made while one is running from it.
+    pub coalesce_inflight: bool,
+    pub request_usage_accounting: bool,
+    /// `reject` or `clamp` out-of-range `SAMPLING_RANGES` fields; unset passes
+    /// them through.
+    pub validate_sampling_params: Option<String>,
+    /// Vendors (the part of a full id before `/`) to prefer, in order, when a
+    /// display id matches several models.
+    pub provider_preference: Vec<String>,
+    /// Answer `GET /models/{id}` with a 409 instead of picking when a display
+    /// id is still ambiguous after `provider_preference`.
+    pub ambiguous_id_conflict: bool,
+    pub responses_passthrough: Vec<String>,
+    pub slow_client_timeout_secs: u64,
+    pub retry_empty_stream: bool,
+    pub warm_models: Vec<String>,
+    pub warm_interval_secs: u64,
+    pub status_history_size: usize,
+    /// OTLP/HTTP collector base URL; spans are only exported when it's set.
+    pub otel_endpoint: Option<String>,
+    pub otel_service_name: String,
+    /// Numeric and boolean env vars that didn't parse, reported by `validate`.
+    invalid_env: Vec<String>,
 }
 
 impl Config {
     pub fn from_env() -> Self {
+        let mut invalid = Vec::new();
         Self {
             host: env::var("HOST").unwrap_or_else(|_| "0.0.0.0".into()),
-            port: env::var("PORT")
-                .unwrap_or_else(|_| "3000".into())
-                .parse()
-                .expect("PORT must be a valid number"),
+            port: env_parse("PORT", &mut invalid).unwrap_or(3000),
+            upstream_url: env::var("UPSTREAM_URL")
+                .unwrap_or_else(|_| "https://openrouter.ai/api/v1".into())
+                .trim_end_matches('/')
+                .to_owned(),
+            path_prefix: env::var("PATH_PREFIX").ok().and_then(|p| path_prefix(&p)),
             health_check_key: env::var("OPENROUTER_API_KEY").ok().filter(|k| !k.is_empty()),
-            health_check_concurrency: env::var("HEALTH_CHECK_CONCURRENCY")
-                .unwrap_or_else(|_| "5".into())
-                .parse()
+            free_api_key: env::var("FREE_OPENROUTER_API_KEY").ok().filter(|k| !k.is_empty()),
+            stealth_api_key: env::var("STEALTH_OPENROUTER_API_KEY").ok().filter(|k| !k.is_empty()),
+            inject_api_key: env_flag("INJECT_API_KEY", false, &mut invalid),
+            health_check_enabled: env_flag("HEALTH_CHECK_ENABLED", true, &mut invalid),
+            health_check_concurrency: env_parse("HEALTH_CHECK_CONCURRENCY", &mut invalid)
                 .unwrap_or(5),
-            refresh_interval_secs: env::var("REFRESH_INTERVAL_SECS")
-                .unwrap_or_else(|_| "3600".into())
-                .parse()
+            free_health_check_concurrency: env_parse("HEALTH_CHECK_CONCURRENCY_FREE", &mut invalid),
+            stealth_health_check_concurrency: env_parse(
+                "HEALTH_CHECK_CONCURRENCY_STEALTH",
+                &mut invalid,
+            ),
+            health_check_timeout_secs: env_parse("HEALTH_CHECK_TIMEOUT_SECS", &mut invalid)
+                .unwrap_or(30),
+            health_check_delay_ms: env_parse("HEALTH_CHECK_DELAY_MS", &mut invalid).unwrap_or(0),
+            health_fail_threshold: env_parse("HEALTH_FAIL_THRESHOLD", &mut invalid).unwrap_or(1),
+            model_fetch_timeout_secs: env_parse("MODEL_FETCH_TIMEOUT_SECS", &mut invalid)
+                .unwrap_or(30),
+            refresh_interval_secs: env_parse("REFRESH_INTERVAL_SECS", &mut invalid).unwrap_or(3600),
+            refresh_jitter_secs: env_parse("REFRESH_JITTER_SECS", &mut invalid).unwrap_or(0),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            stream_idle_timeout_secs: env_parse("STREAM_IDLE_TIMEOUT_SECS", &mut invalid)
+                .unwrap_or(300),
+            free_defaults: SamplingDefaults::from_env("FREE", &mut invalid),
+            stealth_defaults: SamplingDefaults::from_env("STEALTH", &mut invalid),
+            free_default_model: env::var("FREE_DEFAULT_MODEL").ok().filter(|v| !v.is_empty()),
+            stealth_default_model: env::var("STEALTH_DEFAULT_MODEL")
+                .ok()
+                .filter(|v| !v.is_empty()),
+            model_aliases: env_json(
+                "MODEL_ALIASES",
+                "a JSON object of alias -> [model ids]",
+                &mut invalid,
+            ),
+            model_access: ModelAccess::from_env(),
+            model_transforms: env_json(
+                "MODEL_TRANSFORMS",
+                "a JSON array of transform rules",
+                &mut invalid,
+            ),
+            custom_tiers: env_json(
+                "CUSTOM_TIERS",
+                "a JSON array of tier definitions",
+                &mut invalid,
+            ),
+            repair_tool_args: env_flag("REPAIR_TOOL_ARGS", false, &mut invalid),
+            rate_limit_rpm: env_parse("RATE_LIMIT_RPM", &mut invalid).unwrap_or(0),
+            rate_limit_user_rpm: env_parse("RATE_LIMIT_USER_RPM", &mut invalid).unwrap_or(0),
+            response_headers: HeaderFilter::from_env(),
+            precheck_context: env_flag("PRECHECK_CONTEXT", false, &mut invalid),
+            max_concurrent_upstream: env_parse("MAX_CONCURRENT_UPSTREAM", &mut invalid)
+                .unwrap_or(0),
+            upstream_queue_timeout_ms: env_parse("UPSTREAM_QUEUE_TIMEOUT_MS", &mut invalid)
+                .unwrap_or(10000),
+            response_cache_size: env_parse("RESPONSE_CACHE_SIZE", &mut invalid).unwrap_or(0),
+            response_cache_ttl_secs: env_parse("RESPONSE_CACHE_TTL", &mut invalid).unwrap_or(300),
+            response_store_size: env_parse("RESPONSE_STORE_SIZE", &mut invalid).unwrap_or(0),
+            response_store_ttl_secs: env_parse("RESPONSE_STORE_TTL_SECS", &mut invalid)
                 .unwrap_or(3600),
+            upstream_ca_bundle: env::var("UPSTREAM_CA_BUNDLE").ok().filter(|p| !p.is_empty()),
+            upstream_min_tls: env::var("UPSTREAM_MIN_TLS").unwrap_or_else(|_| "1.2".into()),
+            max_tool_calls: env_parse("MAX_TOOL_CALLS", &mut invalid).filter(|&n: &usize| n > 0),
+            hide_reasoning: env_flag("HIDE_REASONING", false, &mut invalid),
+            inject_system_prompt: env::var("INJECT_SYSTEM_PROMPT").ok().filter(|v| !v.is_empty()),
+            id_display_mode: env::var("ID_DISPLAY_MODE").unwrap_or_else(|_| "short".into()),
+            batch_concurrency: env_parse("BATCH_CONCURRENCY", &mut invalid).unwrap_or(4),
+            debug_errors: env_flag("DEBUG_ERRORS", false, &mut invalid),
+            self_test: env_flag("SELF_TEST", false, &mut invalid),
+            self_test_strict: env_flag("SELF_TEST_STRICT", false, &mut invalid),
+            default_service_tier: env::var("DEFAULT_SERVICE_TIER").ok().filter(|v| !v.is_empty()),
+            default_route: env::var("DEFAULT_ROUTE").ok().filter(|v| !v.is_empty()),
+            webhook_url: env::var("WEBHOOK_URL").ok().filter(|v| !v.is_empty()),
+            coalesce_arg_deltas_ms: env_parse("COALESCE_ARG_DELTAS_MS", &mut invalid).unwrap_or(0),
+            allow_meta_routers: env_flag("ALLOW_META_ROUTERS", false, &mut invalid),
+            idempotency_ttl_secs: env_parse("IDEMPOTENCY_TTL_SECS", &mut invalid).unwrap_or(600),
+            large_request_warn_bytes: env_parse("LARGE_REQUEST_WARN_BYTES", &mut invalid)
+                .unwrap_or(1_048_576),
+            sticky_aliases: env_flag("STICKY_ALIASES", false, &mut invalid),
+            validate_stream: env_flag("VALIDATE_STREAM", false, &mut invalid),
+            coalesce_inflight: env_flag("COALESCE_INFLIGHT", false, &mut invalid),
+            request_usage_accounting: env_flag("REQUEST_USAGE_ACCOUNTING", false, &mut invalid),
+            validate_sampling_params: env::var("VALIDATE_SAMPLING_PARAMS")
+                .ok()
+                .filter(|v| !v.is_empty()),
+            provider_preference: env::var("PROVIDER_PREFERENCE")
+                .unwrap_or_default()
+                .split(',')
+                .map(|p| p.trim().to_owned())
+                .filter(|p| !p.is_empty())
+                .collect(),
+            ambiguous_id_conflict: env_flag("AMBIGUOUS_ID_CONFLICT", false, &mut invalid),
+            responses_passthrough: env::var("RESPONSES_PASSTHROUGH").map_or_else(
+                |_| RESPONSES_PASSTHROUGH.iter().map(|&f| f.to_owned()).collect(),
+                |v| {
+                    v.split(',')
+                        .map(|f| f.trim().to_owned())
+                        .filter(|f| !f.is_empty())
+                        .collect()
+                },
+            ),
+            slow_client_timeout_secs: env_parse("SLOW_CLIENT_TIMEOUT_SECS", &mut invalid)
+                .unwrap_or(300),
+            retry_empty_stream: env_flag("RETRY_EMPTY_STREAM", false, &mut invalid),
+            warm_models: env::var("WARM_MODELS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|m| m.trim().to_owned())
+                .filter(|m| !m.is_empty())
+                .collect(),
+            warm_interval_secs: env_parse("WARM_INTERVAL_SECS", &mut invalid).unwrap_or(300),
+            status_history_size: env_parse("STATUS_HISTORY_SIZE", &mut invalid).unwrap_or(50),
+            otel_endpoint: env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|v| !v.is_empty()),
+            otel_service_name: env::var("OTEL_SERVICE_NAME")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| env!("CARGO_PKG_NAME").into()),
+            invalid_env: invalid,
         }
     }
+
+    /// Overrides settings from command-line flags, which take precedence over
+    /// the environment. `--help` prints usage and exits; bad flags are errors.
+    pub fn apply_args(&mut self, args: impl IntoIterator<Item = String>) -> Result<(), String> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) => (f.to_owned(), Some(v.to_owned())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{flag} needs a value\n\n{USAGE}"))
+            };
+            match flag.as_str() {
+                "--host" => self.host = value()?,
+                "--port" => {
+                    let v = value()?;
+                    self.port = v.parse().map_err(|_| format!("invalid --port '{v}'"))?;
+                }
+                "--upstream" => self.upstream_url = value()?.trim_end_matches('/').to_owned(),
+                "--no-health-check" => self.health_check_enabled = false,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                other => return Err(format!("unknown argument '{other}'\n\n{USAGE}")),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn id_display_mode(&self) -> IdDisplayMode {
+        IdDisplayMode::parse(&self.id_display_mode).unwrap_or_default()
+    }
+
+    /// Checks settings that would otherwise fail late or obscurely, reporting
+    /// every problem at once.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = self.invalid_env.clone();
+        if self.host.trim().is_empty() {
+            problems.push("HOST must not be empty".to_owned());
+        } else if (self.host.as_str(), self.port).to_socket_addrs().is_err() {
+            problems.push(format!("HOST '{}' is not a valid address", self.host));
+        }
+        if !self.upstream_url.starts_with("https://") && !self.upstream_url.starts_with("http://") {
+            problems.push(format!(
+                "UPSTREAM_URL must be an http(s) URL (got '{}')",
+                self.upstream_url
+            ));
+        }
+        if let Some(ref url) = self.webhook_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                problems.push(format!("WEBHOOK_URL must be an http(s) URL (got '{url}')"));
+            } else if url.starts_with("http://") && self.upstream_url.starts_with("https://") {
+                // The shared client refuses plain HTTP once the upstream is HTTPS.
+                problems.push("WEBHOOK_URL must use https:// when UPSTREAM_URL does".into());
+            }
+        }
+        if self.port == 0 {
+            problems.push("PORT must be a number between 1 and 65535".into());
+        }
+        if self.health_check_concurrency == 0 {
+            problems.push("HEALTH_CHECK_CONCURRENCY must be a positive number".into());
+        }
+        for (name, value) in [
+            ("FREE", self.free_health_check_concurrency),
+            ("STEALTH", self.stealth_health_check_concurrency),
+        ] {
+            if value == Some(0) {
+                problems.push(format!("HEALTH_CHECK_CONCURRENCY_{name} must be a positive number"));
+            }
+        }
+        if self.batch_concurrency == 0 {
+            problems.push("BATCH_CONCURRENCY must be a positive number".into());
+        }
+        if self.health_check_timeout_secs == 0 {
+            problems.push("HEALTH_CHECK_TIMEOUT_SECS must be a positive number".into());
+        }
+        if self.health_fail_threshold == 0 {
+            problems.push("HEALTH_FAIL_THRESHOLD must be a positive number".into());
+        }
+        if self.model_fetch_timeout_secs == 0 {
+            problems.push("MODEL_FETCH_TIMEOUT_SECS must be a positive number".into());
+        }
+        if !self.warm_models.is_empty() && self.warm_interval_secs == 0 {
+            problems.push("WARM_INTERVAL_SECS must be a positive number".into());
+        }
+        if self.refresh_interval_secs == 0 {
+            problems.push("REFRESH_INTERVAL_SECS must be a positive number".into());
+        }
+        if !matches!(self.upstream_min_tls.as_str(), "1.2" | "1.3") {
+            problems.push(format!(
+                "UPSTREAM_MIN_TLS must be 1.2 or 1.3 (got '{}')",
+                self.upstream_min_tls
+            ));
+        }
+        if IdDisplayMode::parse(&self.id_display_mode).is_none() {
+            problems.push(format!(
+                "ID_DISPLAY_MODE must be full, no_suffix or short (got '{}')",
+                self.id_display_mode
+            ));
+        }
+        if let Some(ref tier) = self.default_service_tier {
+            if !SERVICE_TIERS.contains(&tier.as_str()) {
+                problems.push(format!(
+                    "DEFAULT_SERVICE_TIER must be one of {} (got '{tier}')",
+                    SERVICE_TIERS.join(", ")
+                ));
+            }
+        }
+        if let Some(ref mode) = self.validate_sampling_params {
+            if !matches!(mode.as_str(), "reject" | "clamp") {
+                problems.push(format!(
+                    "VALIDATE_SAMPLING_PARAMS must be reject or clamp (got '{mode}')"
+                ));
+            }
+        }
+        if let Some(ref route) = self.default_route {
+            if !ROUTES.contains(&route.as_str()) {
+                problems.push(format!(
+                    "DEFAULT_ROUTE must be one of {} (got '{route}')",
+                    ROUTES.join(", ")
+                ));
+            }
+        }
+        for t in &self.model_transforms {
+            for (key, (min, max)) in &t.clamp {
+                if min > max {
+                    problems.push(format!(
+                        "MODEL_TRANSFORMS clamp for '{key}' on '{}' needs min <= max",
+                        t.pattern
+                    ));
+                }
+            }
+        }
+        if let Some(ref prefix) = self.path_prefix {
+            let safe = prefix[1..].split('/').all(|seg| {
+                !seg.is_empty()
+                    && seg != "."
+                    && seg != ".."
+                    && seg.chars().all(|c| c.is_ascii_alphanumeric() || "-_.~".contains(c))
+            });
+            if !safe {
+                problems.push(format!(
+                    "PATH_PREFIX must be path segments of letters, digits, '-', '_', '.' and '~' \
+                     (got '{prefix}')"
+                ));
+            }
+        }
+        let (mut prefixes, mut names) = (HashSet::new(), HashSet::new());
+        for t in &self.custom_tiers {
+            // Status, history and webhook payloads key custom tiers by name.
+            if ["free", "stealth"].contains(&t.name.as_str()) || !names.insert(t.name.as_str()) {
+                problems.push(format!("CUSTOM_TIERS name '{}' is already taken", t.name));
+            }
+            let prefix = t.prefix();
+            let valid = prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if prefix.is_empty() || !valid {
+                problems.push(format!(
+                    "CUSTOM_TIERS prefix '{prefix}' may only use letters, digits, '-' and '_'"
+                ));
+            } else if RESERVED_PREFIXES.contains(&prefix) || !prefixes.insert(prefix) {
+                problems.push(format!("CUSTOM_TIERS prefix '{prefix}' is already taken"));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+
+    /// Fills in `DEFAULT_SERVICE_TIER` when the request has no `service_tier`.
+    /// Returns whether the body changed.
+    pub fn apply_service_tier(&self, body: &mut Value) -> bool {
+        fill_default(body, "service_tier", self.default_service_tier.as_deref().map(Value::from))
+    }
+
+    /// Likewise fills in `DEFAULT_ROUTE` when the request has no `route`.
+    pub fn apply_route(&self, body: &mut Value) -> bool {
+        fill_default(body, "route", self.default_route.as_deref().map(Value::from))
+    }
+
+    /// Asks OpenRouter for cost accounting (`usage: {include: true}`) when
+    /// `REQUEST_USAGE_ACCOUNTING` is on and the request has no `usage` field.
+    pub fn apply_usage_accounting(&self, body: &mut Value) -> bool {
+        let usage = self.request_usage_accounting.then(|| serde_json::json!({"include": true}));
+        fill_default(body, "usage", usage)
+    }
+
+    /// With `VALIDATE_SAMPLING_PARAMS=clamp`, pulls numeric sampling fields
+    /// into their `SAMPLING_RANGES` bounds.
+    pub fn clamp_sampling(&self, body: &mut Value) -> bool {
+        if self.validate_sampling_params.as_deref() != Some("clamp") {
+            return false;
+        }
+        let mut changed = false;
+        for &(field, min, max) in SAMPLING_RANGES {
+            let Some(value) = body.get(field).and_then(Value::as_f64) else {
+                continue;
+            };
+            let clamped = value.clamp(min, max);
+            if clamped != value {
+                body[field] = clamped.into();
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Window for batching streamed tool-argument deltas; `None` sends each one.
+    pub fn coalesce_arg_deltas(&self) -> Option<Duration> {
+        let ms = self.coalesce_arg_deltas_ms;
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+
+    pub fn health_check_timeout(&self) -> Duration {
+        Duration::from_secs(self.health_check_timeout_secs)
+    }
+
+    pub fn health_check_delay(&self) -> Duration {
+        Duration::from_millis(self.health_check_delay_ms)
+    }
+
+    pub fn model_fetch_timeout(&self) -> Duration {
+        Duration::from_secs(self.model_fetch_timeout_secs)
+    }
+
+    pub fn upstream_queue_timeout(&self) -> Duration {
+        Duration::from_millis(self.upstream_queue_timeout_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MODELS: &[&str] = &[
+        "qwen/qwen3-coder:free",
+        "qwen/qwen3-32b",
+        "meta-llama/llama-3.3-70b-instruct:free",
+        "google/gemma-3-27b-it:free",
+        "openrouter/auto",
+        "qwenlike/model:free",
+    ];
+
+    fn matching(pattern: &str) -> Vec<&'static str> {
+        MODELS.iter().copied().filter(|id| glob_match(pattern, id)).collect()
+    }
+
+    #[test]
+    fn glob_matches_free_models() {
+        assert_eq!(matching("*/*:free"), [
+            "qwen/qwen3-coder:free",
+            "meta-llama/llama-3.3-70b-instruct:free",
+            "google/gemma-3-27b-it:free",
+            "qwenlike/model:free",
+        ]);
+    }
+
+    #[test]
+    fn glob_matches_a_vendor() {
+        assert_eq!(matching("qwen/*"), ["qwen/qwen3-coder:free", "qwen/qwen3-32b"]);
+        assert_eq!(matching("*gemma*"), ["google/gemma-3-27b-it:free"]);
+    }
+
+    #[test]
+    fn glob_without_wildcards_is_exact() {
+        assert_eq!(matching("qwen/qwen3-32b"), ["qwen/qwen3-32b"]);
+        assert!(matching("qwen/qwen3").is_empty());
+        assert!(matching("qwen3-32b").is_empty());
+    }
+
+    #[test]
+    fn glob_rejects_non_matches() {
+        assert!(matching("*:paid").is_empty());
+        assert!(matching("anthropic/*").is_empty());
+        assert!(matching("*/*:free:*").is_empty());
+        // Pieces must appear in order and may not overlap.
+        assert!(!glob_match("*free*qwen*", "qwen/qwen3-coder:free"));
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn model_access_deny_wins() {
+        let access = ModelAccess {
+            allow: vec!["*/*:free".into(), "qwen/*".into()],
+            deny: vec!["*gemma*".into()],
+        };
+        let permitted: Vec<&str> = MODELS.iter().copied().filter(|id| access.permits(id)).collect();
+        assert_eq!(permitted, [
+            "qwen/qwen3-coder:free",
+            "qwen/qwen3-32b",
+            "meta-llama/llama-3.3-70b-instruct:free",
+            "qwenlike/model:free",
+        ]);
+
+        let open = ModelAccess {
+            allow: Vec::new(),
+            deny: vec!["openrouter/*".into()],
+        };
+        assert!(open.permits("qwen/qwen3-32b"));
+        assert!(!open.permits("openrouter/auto"));
+    }
+
+    #[test]
+    fn path_prefix_is_normalized() {
+        assert_eq!(path_prefix("llm").as_deref(), Some("/llm"));
+        assert_eq!(path_prefix("/llm/").as_deref(), Some("/llm"));
+        assert_eq!(path_prefix("/team-a/llm").as_deref(), Some("/team-a/llm"));
+        assert_eq!(path_prefix("/"), None);
+        assert_eq!(path_prefix(""), None);
+    }
+
+    #[test]
+    fn path_prefix_must_be_plain_segments() {
+        let validate = |prefix: &str| {
+            let mut config = Config::from_env();
+            config.path_prefix = path_prefix(prefix);
+            config.validate().map_err(|e| e.contains("PATH_PREFIX"))
+        };
+        for ok in ["/llm", "team-a/llm.v1", "~user/api_"] {
+            assert_eq!(validate(ok), Ok(()), "{ok}");
+        }
+        for bad in ["/../admin", "/llm/./x", "/a//b", "/a b", "/a%2fb", "/a?b"] {
+            assert_eq!(validate(bad), Err(true), "{bad}");
+        }
+    }
+
+    #[test]
+    fn custom_tier_names_must_be_unique() {
+        let validate = |tiers: Value| {
+            let mut config = Config::from_env();
+            config.custom_tiers = serde_json::from_value(tiers).unwrap();
+            config.validate().map_err(|e| e.contains("CUSTOM_TIERS name"))
+        };
+        assert_eq!(validate(json!([{"name": "a"}, {"name": "b"}])), Ok(()));
+        let same = json!([{"name": "a"}, {"name": "a", "prefix": "other"}]);
+        assert_eq!(validate(same), Err(true));
+        let builtin = json!([{"name": "free", "prefix": "my-free"}]);
+        assert_eq!(validate(builtin), Err(true));
+    }
+
+    #[test]
+    fn clamp_sampling_pulls_values_to_the_nearest_bound() {
+        let mut config = Config::from_env();
+        config.validate_sampling_params = Some("clamp".into());
+        let clamped = |mut body: Value| (config.clamp_sampling(&mut body), body);
+        assert_eq!(
+            clamped(serde_json::json!({"temperature": 3, "top_p": -0.5})),
+            (true, serde_json::json!({"temperature": 2.0, "top_p": 0.0}))
+        );
+        for edge in [
+            serde_json::json!({"temperature": 0, "top_p": 1}),
+            serde_json::json!({"temperature": 2, "top_p": 0}),
+            serde_json::json!({"temperature": "hot"}),
+        ] {
+            assert_eq!(clamped(edge.clone()), (false, edge));
+        }
+
+        config.validate_sampling_params = Some("reject".into());
+        let mut body = serde_json::json!({"temperature": 3});
+        assert!(!config.clamp_sampling(&mut body));
+        assert_eq!(body["temperature"], 3);
+    }
+
+    #[test]
+    fn unparseable_env_values_are_recorded() {
+        // Names `Config::from_env` doesn't read, so other tests can't see them.
+        env::set_var("CONFIG_TEST_NUMBER", "12");
+        env::set_var("CONFIG_TEST_BAD_NUMBER", "12s");
+        env::set_var("CONFIG_TEST_FLAG", "0");
+        env::set_var("CONFIG_TEST_BAD_FLAG", "yes");
+        env::set_var("CONFIG_TEST_EMPTY", " ");
+        let mut invalid = Vec::new();
+        assert_eq!(env_parse::<u64>("CONFIG_TEST_NUMBER", &mut invalid), Some(12));
+        assert_eq!(env_parse::<u64>("CONFIG_TEST_BAD_NUMBER", &mut invalid), None);
+        assert_eq!(env_parse::<u64>("CONFIG_TEST_EMPTY", &mut invalid), None);
+        assert_eq!(env_parse::<u64>("CONFIG_TEST_UNSET", &mut invalid), None);
+        assert!(!env_flag("CONFIG_TEST_FLAG", true, &mut invalid));
+        assert!(env_flag("CONFIG_TEST_BAD_FLAG", true, &mut invalid));
+        assert!(env_flag("CONFIG_TEST_EMPTY", true, &mut invalid));
+        assert!(!env_flag("CONFIG_TEST_UNSET", false, &mut invalid));
+        assert_eq!(invalid, [
+            "CONFIG_TEST_BAD_NUMBER must be a number (got '12s')",
+            "CONFIG_TEST_BAD_FLAG must be true or false (got 'yes')",
+        ]);
+
+        let mut config = Config::from_env();
+        config.invalid_env = invalid;
+        let problems = config.validate().unwrap_err();
+        assert!(problems.starts_with("CONFIG_TEST_BAD_NUMBER must be a number"), "{problems}");
+    }
+
+    #[test]
+    fn unparseable_json_env_values_are_recorded() {
+        env::set_var("CONFIG_TEST_JSON", r#"{"fast": ["a/b"]}"#);
+        env::set_var("CONFIG_TEST_BAD_JSON", r#"{"fast": "a/b""#);
+        let mut invalid = Vec::new();
+        let aliases: HashMap<String, Vec<String>> =
+            env_json("CONFIG_TEST_JSON", "an object", &mut invalid);
+        assert_eq!(aliases["fast"], ["a/b"]);
+        let aliases: HashMap<String, Vec<String>> =
+            env_json("CONFIG_TEST_BAD_JSON", "an object", &mut invalid);
+        assert!(aliases.is_empty());
+        assert_eq!(invalid.len(), 1);
+        assert!(invalid[0].starts_with("CONFIG_TEST_BAD_JSON must be an object ("), "{invalid:?}");
+    }
 }
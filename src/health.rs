@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Consecutive live failures after which a model is considered unavailable.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long an unavailable model stays hidden before it gets another chance.
+const COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Tracks how real traffic to each model is going, between health checks.
+#[derive(Default)]
+pub struct LiveHealth {
+    failures: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl LiveHealth {
+    /// Records an upstream outcome: `None` for a transport error, otherwise the
+    /// HTTP status. Successes reset the model; client errors say nothing about it.
+    pub fn observe(&self, model_id: &str, status: Option<u16>) {
+        let failed = match status {
+            Some(s) if (200..300).contains(&s) => false,
+            Some(s) if s >= 500 => true,
+            Some(_) => return,
+            None => true,
+        };
+        let mut failures = self.failures.lock().unwrap();
+        if failed {
+            let entry = failures.entry(model_id.to_owned()).or_insert((0, Instant::now()));
+            entry.0 += 1;
+            entry.1 = Instant::now();
+        } else {
+            failures.remove(model_id);
+        }
+    }
+
+    pub fn is_available(&self, model_id: &str) -> bool {
+        match self.failures.lock().unwrap().get(model_id) {
+            Some(&(count, last)) => count < FAILURE_THRESHOLD || last.elapsed() >= COOLDOWN,
+            None => true,
+        }
+    }
+}
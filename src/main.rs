@@ -1,43 +1,172 @@
 mod api;
+mod cache;
+mod cancel;
 mod config;
+mod health;
+mod metrics;
 mod model;
+mod ratelimit;
 mod state;
+mod telemetry;
+mod tokens;
 
-use api::{health, not_found, status, tier_router, Tier};
-use axum::{extract::DefaultBodyLimit, routing::get, Router};
+use api::{
+    cancel, check_default_models, classify, health, maintenance, metrics, not_found, openapi_spec,
+    status, tier_router, Tier,
+};
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{get, post},
+    Router,
+};
 use state::AppState;
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
 use tower_http::cors::CorsLayer;
-use tracing::info;
+use tracing::{error, info};
+use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
+    let mut config = config::Config::from_env();
+    if let Err(usage) = config.apply_args(std::env::args().skip(1)) {
+        eprintln!("{usage}");
+        std::process::exit(2);
+    }
+
+    let (otel, tracer_provider) = match telemetry::layer(&config) {
+        Ok(otel) => otel.unzip(),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let exporting = otel.is_some();
+    tracing_subscriber::registry()
+        .with(otel)
+        .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "openrouter_api=info,tower_http=info".into()),
         )
+        .with(tracing_subscriber::fmt::layer())
         .init();
+    if exporting {
+        info!("Exporting request spans over OTLP");
+    }
 
-    let config = config::Config::from_env();
+    if let Err(e) = config.validate() {
+        error!("Invalid configuration: {e}");
+        std::process::exit(1);
+    }
+    if config.self_test {
+        match api::self_test::run(&config) {
+            Ok(n) => info!("Translation self-test passed ({n} fixtures)"),
+            Err(mismatches) => {
+                for m in &mismatches {
+                    error!("Translation self-test mismatch: {m}");
+                }
+                if config.self_test_strict {
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
     let addr = format!("{}:{}", config.host, config.port);
-    let state = AppState::new(config);
+    let state = match AppState::new(config) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize: {e}");
+            std::process::exit(1);
+        }
+    };
 
     state.full_refresh().await;
+    // Tiers are empty when the first refresh failed, so only check real lists.
+    if state.cache.read().await.last_refresh_error.is_none() {
+        if let Err(e) = check_default_models(&state).await {
+            error!("Invalid configuration: {e}");
+            std::process::exit(1);
+        }
+    }
     state.spawn_scheduler();
+    state.spawn_warmer();
 
-    let app = Router::new()
+    let mut tiers = Router::new()
         .nest("/free/v1", tier_router(Tier::Free))
         .nest("/stealth/v1", tier_router(Tier::Stealth))
+        .nest("/routers/v1", tier_router(Tier::Router));
+    for (i, tier) in state.config.custom_tiers.iter().enumerate() {
+        info!("Serving custom tier '{}' at /{}/v1", tier.name, tier.prefix());
+        tiers = tiers.nest(&format!("/{}/v1", tier.prefix()), tier_router(Tier::Custom(i)));
+    }
+
+    let mut app = tiers
         .route("/health", get(health))
         .route("/status", get(status))
-        .fallback(not_found)
+        .route("/metrics", get(metrics))
+        .route("/openapi.json", get(openapi_spec))
+        .route("/admin/classify/*id", get(classify))
+        .route("/admin/maintenance", post(maintenance))
+        .route("/cancel/:id", post(cancel))
+        .fallback(not_found);
+    // Nesting strips the prefix, so handlers and upstream paths are unchanged.
+    if let Some(ref prefix) = state.config.path_prefix {
+        info!("Also serving every route under {prefix}");
+        app = Router::new().nest(prefix, app.clone()).merge(app);
+    }
+
+    let app = app
+        .layer(
+            // SSE must reach the client unbuffered, so never compress event streams
+            CompressionLayer::new().compress_when(
+                SizeAbove::default().and(NotForContentType::const_new("text/event-stream")),
+            ),
+        )
         .layer(CorsLayer::permissive())
         .layer(DefaultBodyLimit::max(10 * 1024 * 1024))
         .with_state(state);
 
     info!("Listening on {addr}");
     let listener = tokio::net::TcpListener::bind(&addr).await.expect("failed to bind");
-    axum::serve(listener, app).await.expect("server crashed");
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    );
+    tokio::select! {
+        result = async { server.await } => result.expect("server crashed"),
+        () = shutdown_signal() => info!("Shutting down"),
+    }
+
+    // Export the spans still waiting in the batch before exiting.
+    if let Some(provider) = tracer_provider {
+        match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Failed to flush spans: {e}"),
+            Err(e) => eprintln!("Failed to flush spans: {e}"),
+        }
+    }
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
 }
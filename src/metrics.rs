@@ -0,0 +1,96 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Upper bounds (bytes) of the size histogram buckets, 1 KiB to 16 MiB.
+const SIZE_BUCKETS: [u64; 8] = [
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+];
+
+/// Cumulative byte-size histogram in the Prometheus layout.
+#[derive(Default)]
+pub struct SizeHistogram {
+    buckets: [AtomicU64; SIZE_BUCKETS.len()],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl SizeHistogram {
+    pub fn observe(&self, bytes: u64) {
+        for (bound, bucket) in SIZE_BUCKETS.iter().zip(&self.buckets) {
+            if bytes <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in SIZE_BUCKETS.iter().zip(&self.buckets) {
+            let n = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {n}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Process-wide counters served at `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    pub request_bytes: SizeHistogram,
+    pub response_bytes: SizeHistogram,
+}
+
+impl Metrics {
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.request_bytes.render(
+            &mut out,
+            "proxy_request_body_bytes",
+            "Size of request bodies forwarded upstream.",
+        );
+        self.response_bytes.render(
+            &mut out,
+            "proxy_response_body_bytes",
+            "Size of upstream response bodies relayed to clients.",
+        );
+        out
+    }
+}
+
+/// Tallies a streamed response body and records its size once the stream is
+/// dropped, whether it finished or the client went away.
+pub struct StreamedBytes {
+    metrics: Arc<Metrics>,
+    bytes: u64,
+}
+
+impl StreamedBytes {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics, bytes: 0 }
+    }
+
+    pub fn add(&mut self, n: usize) {
+        self.bytes += n as u64;
+    }
+}
+
+impl Drop for StreamedBytes {
+    fn drop(&mut self) {
+        self.metrics.response_bytes.observe(self.bytes);
+    }
+}
@@ -1,12 +1,12 @@
+use crate::config::CustomTier;
+use chrono::{DateTime, Utc};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::Semaphore;
 use tracing::{info, warn};
 
-const API_BASE: &str = "https://openrouter.ai/api/v1";
-
 const META_ROUTER_IDS: &[&str] = &[
     "openrouter/auto",
     "openrouter/free",
@@ -14,6 +14,10 @@ const META_ROUTER_IDS: &[&str] = &[
     "switchpoint/router",
 ];
 
+/// Request fields that only tune output: sent to models that list them in
+/// `supported_parameters` and dropped, not rejected, for the rest.
+pub const SOFT_PARAMS: &[&str] = &["prediction", "top_k", "min_p", "top_a", "repetition_penalty"];
+
 #[derive(Debug, Deserialize, Clone)]
 struct ApiResponse {
     data: Vec<Model>,
@@ -28,7 +32,6 @@ pub struct Model {
     #[serde(default)]
     pub description: Option<String>,
     #[serde(default)]
-    #[allow(dead_code)]
     pub context_length: Option<u64>,
     #[serde(default)]
     pub pricing: Option<Pricing>,
@@ -39,6 +42,11 @@ pub struct Model {
     pub top_provider: Option<TopProvider>,
     #[serde(default)]
     pub supported_parameters: Option<Vec<String>>,
+    /// When this proxy last health-checked the model, and when that last passed.
+    #[serde(skip)]
+    pub last_checked: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub last_success: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -77,10 +85,14 @@ pub struct TopProvider {
 }
 
 impl Model {
-    pub async fn fetch_all(client: &Client) -> anyhow::Result<Vec<Self>> {
+    pub async fn fetch_all(
+        client: &Client,
+        base: &str,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<Self>> {
         let resp = client
-            .get(format!("{API_BASE}/models"))
-            .timeout(Duration::from_secs(30))
+            .get(format!("{base}/models"))
+            .timeout(timeout)
             .send()
             .await?;
 
@@ -103,6 +115,42 @@ impl Model {
         (free, stealth)
     }
 
+    /// Meta-router models (e.g. `openrouter/auto`), kept out of both tiers.
+    pub fn classify_routers(all: &[Self]) -> Vec<Self> {
+        let routers: Vec<_> = all.iter().filter(|m| m.is_meta_router()).cloned().collect();
+        info!("Classified {} meta-router models", routers.len());
+        routers
+    }
+
+    /// Members of each `CUSTOM_TIERS` entry, in the same order. Meta-routers
+    /// are never included.
+    pub fn classify_custom(all: &[Self], tiers: &[CustomTier]) -> Vec<Vec<Self>> {
+        tiers
+            .iter()
+            .map(|t| {
+                let members: Vec<_> = all
+                    .iter()
+                    .filter(|m| !m.is_meta_router() && t.rules.matches(m))
+                    .cloned()
+                    .collect();
+                info!("Classified {} models into custom tier '{}'", members.len(), t.name);
+                members
+            })
+            .collect()
+    }
+
+    /// Free or stealth models that output images. These skip chat health checks.
+    pub fn classify_images(all: &[Self]) -> Vec<Self> {
+        let images: Vec<_> = all
+            .iter()
+            .filter(|m| m.generates_images() && (m.is_free() || m.is_stealth()))
+            .filter(|m| !m.is_meta_router())
+            .cloned()
+            .collect();
+        info!("Classified {} image-generation models", images.len());
+        images
+    }
+
     pub fn is_free(&self) -> bool {
         self.id.ends_with(":free")
             || self.pricing.as_ref().is_some_and(|p| {
@@ -111,13 +159,55 @@ impl Model {
     }
 
     pub fn is_stealth(&self) -> bool {
+        !self.stealth_signals().is_empty()
+    }
+
+    /// Which fields mark the model as stealth: a "cloaked"/"stealth" keyword in
+    /// the description or name, or a `stealth/` id prefix.
+    fn stealth_signals(&self) -> Vec<&'static str> {
         let has_keyword = |s: &str| {
             let l = s.to_lowercase();
             l.contains("cloaked") || l.contains("stealth")
         };
-        self.description.as_deref().is_some_and(has_keyword)
-            || has_keyword(&self.name)
-            || self.id.starts_with("stealth/")
+        let mut signals = Vec::new();
+        if self.description.as_deref().is_some_and(has_keyword) {
+            signals.push("description");
+        }
+        if has_keyword(&self.name) {
+            signals.push("name");
+        }
+        if self.id.starts_with("stealth/") {
+            signals.push("id_prefix");
+        }
+        signals
+    }
+
+    /// Explains how `classify`/`classify_images` treat this model.
+    pub fn classification(&self) -> Classification {
+        let pricing = self.pricing.as_ref();
+        let meta_router = self.is_meta_router();
+        let (free, stealth) = (self.is_free(), self.is_stealth());
+        let tier = if meta_router {
+            None
+        } else if stealth {
+            Some("stealth")
+        } else if free {
+            Some("free")
+        } else {
+            None
+        };
+        Classification {
+            id: self.id.clone(),
+            tier,
+            is_free: free,
+            is_stealth: stealth,
+            is_meta_router: meta_router,
+            generates_images: self.generates_images(),
+            free_suffix: self.id.ends_with(":free"),
+            prompt_price: pricing.and_then(|p| p.prompt.clone()),
+            completion_price: pricing.and_then(|p| p.completion.clone()),
+            stealth_signals: self.stealth_signals(),
+        }
     }
 
     pub fn is_meta_router(&self) -> bool {
@@ -133,6 +223,16 @@ impl Model {
             .is_some_and(|params| params.iter().any(|p| p == name))
     }
 
+    /// The output-length field to send: `max_completion_tokens` for models
+    /// that list it (some reject `max_tokens`), else `max_tokens`.
+    pub fn max_tokens_param(&self) -> &'static str {
+        if self.has_param("max_completion_tokens") {
+            "max_completion_tokens"
+        } else {
+            "max_tokens"
+        }
+    }
+
     pub fn supports_vision(&self) -> bool {
         self.architecture
             .as_ref()
@@ -140,23 +240,98 @@ impl Model {
             .is_some_and(|m| m.contains("image"))
     }
 
-    pub fn display_id(&self) -> String {
+    pub fn supports_audio(&self) -> bool {
+        self.accepts_input("audio")
+    }
+
+    /// Whether `modality` is on the input side of `architecture.modality`.
+    pub fn accepts_input(&self, modality: &str) -> bool {
+        self.architecture
+            .as_ref()
+            .and_then(|a| a.modality.as_deref())
+            .map(|m| m.split_once("->").map_or(m, |(input, _)| input))
+            .is_some_and(|input| input.contains(modality))
+    }
+
+    pub fn generates_images(&self) -> bool {
+        self.architecture
+            .as_ref()
+            .and_then(|a| a.modality.as_deref())
+            .and_then(|m| m.split_once("->"))
+            .is_some_and(|(_, output)| output.contains("image"))
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            tools: self.has_param("tools"),
+            tool_choice: self.has_param("tool_choice"),
+            json_mode: self.has_param("response_format"),
+            streaming: self.has_param("stream"),
+            parallel_tool_calls: self.has_param("parallel_tool_calls"),
+            logprobs: self.has_param("logprobs"),
+            vision: self.supports_vision(),
+            prediction: self.has_param("prediction"),
+            reasoning: ["reasoning", "reasoning_effort", "include_reasoning"]
+                .iter()
+                .any(|p| self.has_param(p)),
+        }
+    }
+
+    pub fn display_id(&self, mode: IdDisplayMode) -> String {
         let id = self.id.as_str();
+        if matches!(mode, IdDisplayMode::Full) {
+            return id.to_owned();
+        }
         let id = id.strip_suffix(":free").unwrap_or(id);
+        if matches!(mode, IdDisplayMode::NoSuffix) {
+            return id.to_owned();
+        }
         let id = id.split('/').last().unwrap_or(id);
         id.to_owned()
     }
 
-    pub fn matches_display_id(&self, id: &str) -> bool {
-        self.id == id || self.display_id() == id
+    pub fn matches_display_id(&self, id: &str, mode: IdDisplayMode) -> bool {
+        self.id == id || self.display_id(mode) == id
     }
 
-    pub fn to_openai(&self) -> OpenAIModel {
+    /// Models answering to `id`, best first: an exact full id, then by the
+    /// vendor's position in `preferred` (unlisted vendors last), then by full
+    /// id, so the pick never depends on list order. The flag is set when the
+    /// best match is only ahead of the next one by that last tie-break.
+    pub fn find_by_display_id<'a>(
+        models: &'a [Self],
+        id: &str,
+        mode: IdDisplayMode,
+        preferred: &[String],
+    ) -> (Vec<&'a Self>, bool) {
+        let rank = |m: &Self| {
+            let vendor = preferred.iter().position(|p| p == m.provider());
+            (m.id != id, vendor.unwrap_or(preferred.len()))
+        };
+        let mut found: Vec<&Self> =
+            models.iter().filter(|m| m.matches_display_id(id, mode)).collect();
+        found.sort_by(|a, b| rank(a).cmp(&rank(b)).then_with(|| a.id.cmp(&b.id)));
+        let tied = matches!(found.as_slice(), [a, b, ..] if rank(a) == rank(b));
+        (found, tied)
+    }
+
+    pub fn to_openai(&self, mode: IdDisplayMode) -> OpenAIModel {
         OpenAIModel {
-            id: self.display_id(),
+            id: self.display_id(mode),
             object: "model".into(),
             created: self.created,
             owned_by: self.provider().to_owned(),
+            reasoning: self.capabilities().reasoning,
+            aliased_to: None,
+        }
+    }
+
+    /// A listing entry for `alias`, currently resolving to this model.
+    pub fn to_openai_alias(&self, alias: &str, mode: IdDisplayMode) -> OpenAIModel {
+        OpenAIModel {
+            id: alias.to_owned(),
+            aliased_to: Some(self.display_id(mode)),
+            ..self.to_openai(mode)
         }
     }
 
@@ -166,73 +341,218 @@ impl Model {
 
     pub async fn health_check_batch(
         client: &Client,
+        base: &str,
         api_key: &str,
         models: Vec<Self>,
         concurrency: usize,
-    ) -> Vec<Self> {
+        timeout: Duration,
+        delay: Duration,
+    ) -> HealthCheckResult {
         if models.is_empty() {
-            return models;
+            return HealthCheckResult::default();
         }
         info!("Health-checking {} models (concurrency={concurrency})", models.len());
 
         let sem = Arc::new(Semaphore::new(concurrency));
         let mut handles = Vec::with_capacity(models.len());
 
-        for model in models {
+        for mut model in models {
+            // Spacing pings out keeps a big batch from tripping upstream 429s.
+            if !delay.is_zero() && !handles.is_empty() {
+                tokio::time::sleep(delay).await;
+            }
             let permit = sem.clone().acquire_owned().await.unwrap();
             let client = client.clone();
+            let base = base.to_owned();
             let key = api_key.to_owned();
             handles.push(tokio::spawn(async move {
-                let ok = model.ping(&client, &key).await;
+                let result = model.ping(&client, &base, &key, timeout).await;
                 drop(permit);
-                ok.then_some(model)
+                let now = Utc::now();
+                model.last_checked = Some(now);
+                if result.alive {
+                    model.last_success = Some(now);
+                    return Ok(model);
+                }
+                Err(HealthFailure {
+                    id: model.id,
+                    status: result.status,
+                    reason: result.error.unwrap_or_default(),
+                    checked_at: now.to_rfc3339(),
+                })
             }));
         }
 
-        let mut healthy = Vec::new();
+        let mut result = HealthCheckResult::default();
         for h in handles {
-            if let Ok(Some(m)) = h.await {
-                healthy.push(m);
+            match h.await {
+                Ok(Ok(m)) => result.healthy.push(m),
+                Ok(Err(failure)) => result.failed.push(failure),
+                Err(e) => warn!("Health check task failed: {e}"),
             }
         }
-        info!("{} models passed health check", healthy.len());
-        healthy
+        let (passed, failed) = (result.healthy.len(), result.failed.len());
+        info!("{passed} models passed health check, {failed} failed");
+        result
     }
 
-    async fn ping(&self, client: &Client, api_key: &str) -> bool {
+    pub async fn ping(
+        &self,
+        client: &Client,
+        base: &str,
+        api_key: &str,
+        timeout: Duration,
+    ) -> PingResult {
+        let started = Instant::now();
         let payload = serde_json::json!({
             "model": self.id,
             "messages": [{"role": "user", "content": "hi"}],
             "max_tokens": 1
         });
 
-        match client
-            .post(format!("{API_BASE}/chat/completions"))
+        let (alive, status, error) = match client
+            .post(format!("{base}/chat/completions"))
             .bearer_auth(api_key)
             .json(&payload)
-            .timeout(Duration::from_secs(30))
+            .timeout(timeout)
             .send()
             .await
         {
             Ok(r) if r.status().is_success() => {
                 info!("  + {}", self.id);
-                true
+                (true, Some(r.status().as_u16()), None)
             }
             Ok(r) if r.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
                 // 429 means the model exists but is rate-limited; treat as alive
                 info!("  ~ {} (rate-limited, assumed alive)", self.id);
-                true
+                (true, Some(r.status().as_u16()), None)
             }
             Ok(r) => {
                 let st = r.status();
                 let body = r.text().await.unwrap_or_default();
-                warn!("  - {} -> {st} {}", self.id, &body[..body.len().min(120)]);
-                false
+                let snippet: String = body.chars().take(120).collect();
+                warn!("  - {} -> {st} {snippet}", self.id);
+                (false, Some(st.as_u16()), Some(format!("{st} {snippet}").trim_end().to_string()))
             }
             Err(e) => {
                 warn!("  - {} -> {e}", self.id);
-                false
+                let reason = if e.is_timeout() { "timed out".to_string() } else { e.to_string() };
+                (false, None, Some(reason))
             }
+        };
+
+        PingResult {
+            alive,
+            status,
+            latency_ms: started.elapsed().as_millis() as u64,
+            error,
+        }
+    }
+}
+
+/// How model ids are shown to clients: `vendor/model:free`, `vendor/model`, or
+/// just `model`. Shorter ids are friendlier but may collide across vendors.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum IdDisplayMode {
+    Full,
+    NoSuffix,
+    #[default]
+    Short,
+}
+
+impl IdDisplayMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "full" => Some(Self::Full),
+            "no_suffix" => Some(Self::NoSuffix),
+            "short" => Some(Self::Short),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Classification {
+    pub id: String,
+    /// The chat tier the model is eligible for before health checks, if any.
+    pub tier: Option<&'static str>,
+    pub is_free: bool,
+    pub is_stealth: bool,
+    pub is_meta_router: bool,
+    pub generates_images: bool,
+    pub free_suffix: bool,
+    pub prompt_price: Option<String>,
+    pub completion_price: Option<String>,
+    pub stealth_signals: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PingResult {
+    pub alive: bool,
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    /// Why a dead model failed: the upstream status and body, or the transport error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A model dropped by a health check, and why.
+#[derive(Debug, Serialize, Clone)]
+pub struct HealthFailure {
+    pub id: String,
+    /// Upstream's HTTP status; `None` for timeouts and connection errors.
+    pub status: Option<u16>,
+    pub reason: String,
+    pub checked_at: String,
+}
+
+/// Outcome of `health_check_batch`: models that answered, and those that didn't.
+#[derive(Debug, Default)]
+pub struct HealthCheckResult {
+    pub healthy: Vec<Model>,
+    pub failed: Vec<HealthFailure>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct Capabilities {
+    pub tools: bool,
+    pub tool_choice: bool,
+    pub json_mode: bool,
+    pub streaming: bool,
+    pub parallel_tool_calls: bool,
+    pub logprobs: bool,
+    pub vision: bool,
+    pub prediction: bool,
+    /// A reasoning (thinking) model, from its reasoning parameters.
+    pub reasoning: bool,
+}
+
+impl Capabilities {
+    pub const NAMES: &'static [&'static str] = &[
+        "tools",
+        "tool_choice",
+        "json_mode",
+        "streaming",
+        "parallel_tool_calls",
+        "logprobs",
+        "vision",
+        "prediction",
+        "reasoning",
+    ];
+
+    /// Looks up a capability by its query-string name; `None` if unknown.
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "tools" => Some(self.tools),
+            "tool_choice" => Some(self.tool_choice),
+            "json_mode" => Some(self.json_mode),
+            "streaming" => Some(self.streaming),
+            "parallel_tool_calls" => Some(self.parallel_tool_calls),
+            "logprobs" => Some(self.logprobs),
+            "vision" => Some(self.vision),
+            "prediction" => Some(self.prediction),
+            "reasoning" => Some(self.reasoning),
+            _ => None,
         }
     }
 }
@@ -243,6 +563,11 @@ pub struct OpenAIModel {
     pub object: String,
     pub created: i64,
     pub owned_by: String,
+    /// Whether the model can return its reasoning (`supports=reasoning`).
+    pub reasoning: bool,
+    /// Set on `MODEL_ALIASES` entries: the model the alias currently resolves to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aliased_to: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -252,10 +577,121 @@ pub struct OpenAIModelList {
 }
 
 impl OpenAIModelList {
-    pub fn from_models(models: &[Model]) -> Self {
+    pub fn from_models(models: &[Model], mode: IdDisplayMode) -> Self {
         Self {
             object: "list".into(),
-            data: models.iter().map(Model::to_openai).collect(),
+            data: models.iter().map(|m| m.to_openai(mode)).collect(),
+        }
+    }
+
+    /// The same listing as a CSV table with a header row: id, name, context
+    /// length, prompt and completion prices, then one column per capability.
+    pub fn csv(models: &[Model], mode: IdDisplayMode) -> String {
+        let mut header = vec!["id", "name", "context_length", "prompt_price", "completion_price"];
+        header.extend(Capabilities::NAMES);
+        let mut out = header.join(",") + "\r\n";
+        for m in models {
+            let listed = m.to_openai(mode);
+            let pricing = m.pricing.as_ref();
+            let mut row = vec![
+                csv_field(&listed.id),
+                csv_field(&m.name),
+                m.context_length.map(|c| c.to_string()).unwrap_or_default(),
+                csv_field(pricing.and_then(|p| p.prompt.as_deref()).unwrap_or_default()),
+                csv_field(pricing.and_then(|p| p.completion.as_deref()).unwrap_or_default()),
+            ];
+            let caps = m.capabilities();
+            for name in Capabilities::NAMES {
+                row.push(caps.get(name).unwrap_or(false).to_string());
+            }
+            out += &row.join(",");
+            out += "\r\n";
+        }
+        out
+    }
+}
+
+/// Quotes a CSV field (RFC 4180) when it holds a comma, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models(ids: &[&str]) -> Vec<Model> {
+        ids.iter()
+            .map(|id| serde_json::from_value(serde_json::json!({"id": id, "name": id})).unwrap())
+            .collect()
+    }
+
+    fn found(models: &[Model], id: &str, preferred: &[&str]) -> (Vec<String>, bool) {
+        let preferred: Vec<String> = preferred.iter().map(|&p| p.to_owned()).collect();
+        let (found, tied) = Model::find_by_display_id(models, id, IdDisplayMode::Short, &preferred);
+        (found.into_iter().map(|m| m.id.clone()).collect(), tied)
+    }
+
+    const COLLIDING: &[&str] = &[
+        "nvidia/llama-3.3-70b-instruct:free",
+        "meta-llama/llama-3.3-70b-instruct:free",
+    ];
+
+    #[test]
+    fn colliding_short_ids_resolve_regardless_of_list_order() {
+        let forward = models(COLLIDING);
+        let mut reversed = models(COLLIDING);
+        reversed.reverse();
+        for tier in [forward, reversed] {
+            assert_eq!(found(&tier, "llama-3.3-70b-instruct", &[]), (
+                vec![
+                    "meta-llama/llama-3.3-70b-instruct:free".to_owned(),
+                    "nvidia/llama-3.3-70b-instruct:free".to_owned(),
+                ],
+                true
+            ));
         }
     }
+
+    #[test]
+    fn provider_preference_breaks_the_tie() {
+        let tier = models(COLLIDING);
+        let (ids, tied) = found(&tier, "llama-3.3-70b-instruct", &["nvidia", "meta-llama"]);
+        assert_eq!(ids[0], "nvidia/llama-3.3-70b-instruct:free");
+        assert!(!tied);
+        // Listing only one vendor still puts it ahead of the unlisted one.
+        let (ids, tied) = found(&tier, "llama-3.3-70b-instruct", &["nvidia"]);
+        assert_eq!(ids[0], "nvidia/llama-3.3-70b-instruct:free");
+        assert!(!tied);
+    }
+
+    #[test]
+    fn exact_full_id_wins_over_preference() {
+        let tier = models(COLLIDING);
+        let id = "meta-llama/llama-3.3-70b-instruct:free";
+        let (ids, tied) = found(&tier, id, &["nvidia"]);
+        assert_eq!(ids, [id]);
+        assert!(!tied);
+    }
+
+    #[test]
+    fn listings_flag_reasoning_models() {
+        let listed = |params: &[&str]| {
+            let model: Model = serde_json::from_value(serde_json::json!({
+                "id": "a/b",
+                "name": "b",
+                "supported_parameters": params
+            }))
+            .unwrap();
+            let entry = serde_json::to_value(model.to_openai(IdDisplayMode::Short)).unwrap();
+            entry["reasoning"].clone()
+        };
+        assert_eq!(listed(&["tools", "include_reasoning"]), true);
+        assert_eq!(listed(&["reasoning_effort"]), true);
+        assert_eq!(listed(&["tools"]), false);
+    }
 }
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+const WINDOW: Duration = Duration::from_secs(60);
+/// Stale windows are swept once the map grows past this many keys.
+const SWEEP_THRESHOLD: usize = 10_000;
+
+/// Fixed one-minute window limiter keyed by caller identity.
+pub struct RateLimiter {
+    per_minute: u32,
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.per_minute > 0
+    }
+
+    /// Counts a request for `key`; on rejection returns how long until the
+    /// window resets.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > SWEEP_THRESHOLD {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+        }
+
+        let (start, count) = windows.entry(key.to_owned()).or_insert((now, 0));
+        if now.duration_since(*start) >= WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.per_minute {
+            return Err(WINDOW - now.duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// Caps how many requests are in flight upstream at once. Excess requests
+/// wait up to `max_wait` for a slot before being turned away.
+pub struct ConcurrencyLimiter {
+    permits: Option<Arc<Semaphore>>,
+    max_wait: Duration,
+}
+
+/// Held for as long as a request occupies an upstream slot, including the
+/// full lifetime of a streamed body.
+pub struct UpstreamPermit(#[allow(dead_code)] Option<OwnedSemaphorePermit>);
+
+impl ConcurrencyLimiter {
+    /// `max_concurrent` of 0 disables the limit.
+    pub fn new(max_concurrent: usize, max_wait: Duration) -> Self {
+        Self {
+            permits: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            max_wait,
+        }
+    }
+
+    /// Waits for a free slot; `None` if none opened up within `max_wait`.
+    pub async fn acquire(&self) -> Option<UpstreamPermit> {
+        let Some(ref sem) = self.permits else {
+            return Some(UpstreamPermit(None));
+        };
+        let permit = tokio::time::timeout(self.max_wait, sem.clone().acquire_owned()).await;
+        Some(UpstreamPermit(Some(permit.ok()?.ok()?)))
+    }
+
+    /// A free slot right now, without waiting; `None` when all are taken.
+    pub fn try_acquire(&self) -> Option<UpstreamPermit> {
+        let Some(ref sem) = self.permits else {
+            return Some(UpstreamPermit(None));
+        };
+        Some(UpstreamPermit(Some(sem.clone().try_acquire_owned().ok()?)))
+    }
+}
@@ -1,101 +1,476 @@
-use crate::config::Config;
-use crate::model::Model;
+use crate::api::Tier;
+use crate::cache::{IdempotencyStore, InflightStreams, ResponseCache, ResponseStore};
+use crate::cancel::StreamRegistry;
+use crate::config::{glob_match, Config, CustomTier};
+use crate::health::LiveHealth;
+use crate::metrics::Metrics;
+use crate::model::{HealthFailure, Model};
+use crate::ratelimit::{ConcurrencyLimiter, RateLimiter};
 use chrono::{DateTime, Utc};
 use reqwest::Client;
-use std::collections::HashSet;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, RwLock};
 use tracing::{error, info, warn};
 
+const FETCH_ATTEMPTS: u32 = 3;
+const FETCH_RETRY_DELAY_SECS: u64 = 5;
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// Models that entered or left a tier (after health checks) in one refresh.
+#[derive(Serialize)]
+pub struct TierChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl TierChanges {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The last health check's failures, by tier name.
+pub type HealthFailures = BTreeMap<String, Vec<HealthFailure>>;
+
+/// Health checks each model has failed in a row, by tier name.
+pub type HealthStrikes = BTreeMap<String, BTreeMap<String, u32>>;
+
+/// One entry of the `/status?history=true` refresh log.
+#[derive(Serialize, Clone)]
+pub struct RefreshEvent {
+    /// RFC 3339 time the refresh finished.
+    pub at: String,
+    /// `full` (startup) or `diff` (scheduled).
+    pub kind: &'static str,
+    pub duration_ms: u64,
+    pub free_models: usize,
+    pub stealth_models: usize,
+    /// Model count per `CUSTOM_TIERS` name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom_models: BTreeMap<String, usize>,
+    /// Models that entered or left a tier, custom ones included.
+    pub added: usize,
+    pub removed: usize,
+    /// Set when the model list couldn't be fetched; the counts are then unchanged.
+    pub error: Option<String>,
+}
+
 pub struct ModelCache {
+    /// Everything from the last successful fetch, before classification.
+    pub all_models: Arc<Vec<Model>>,
     pub free_models: Arc<Vec<Model>>,
     pub stealth_models: Arc<Vec<Model>>,
+    pub image_models: Arc<Vec<Model>>,
+    /// Meta-routers, served by `/routers/v1` and never health-checked.
+    pub router_models: Arc<Vec<Model>>,
+    /// Members of each `CUSTOM_TIERS` entry, in config order.
+    pub custom_models: Vec<Arc<Vec<Model>>>,
     pub last_refreshed: DateTime<Utc>,
+    pub last_refresh_error: Option<(DateTime<Utc>, String)>,
+    pub refresh_in_progress: bool,
+    /// When the scheduler will next run a refresh; `None` before it starts.
+    pub next_refresh: Option<DateTime<Utc>>,
+    /// Refreshes that failed in a row since the last successful one.
+    pub consecutive_failures: u32,
+    /// The last `STATUS_HISTORY_SIZE` refreshes, oldest first.
+    pub history: VecDeque<RefreshEvent>,
+    /// Models the last successful refresh's health checks dropped.
+    pub health_failures: HealthFailures,
+    /// Bumped by every refresh that changes any tier's models.
+    pub models_version: u64,
+}
+
+impl ModelCache {
+    /// Appends to `history`, dropping the oldest entries past `limit`.
+    fn record(&mut self, event: RefreshEvent, limit: usize) {
+        self.history.push_back(event);
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// A history entry for a refresh that ended now without fetching models.
+    fn failed_refresh(
+        &self,
+        kind: &'static str,
+        started: Instant,
+        tiers: &[CustomTier],
+    ) -> RefreshEvent {
+        RefreshEvent {
+            at: Utc::now().to_rfc3339(),
+            kind,
+            duration_ms: started.elapsed().as_millis() as u64,
+            free_models: self.free_models.len(),
+            stealth_models: self.stealth_models.len(),
+            custom_models: custom_counts(tiers, &self.custom_models),
+            added: 0,
+            removed: 0,
+            error: self.last_refresh_error.as_ref().map(|(_, e)| e.clone()),
+        }
+    }
 }
 
 pub struct AppState {
     pub cache: RwLock<ModelCache>,
     pub client: Client,
+    pub rate_limiter: RateLimiter,
+    pub user_rate_limiter: RateLimiter,
+    pub upstream_limiter: ConcurrencyLimiter,
+    pub response_cache: ResponseCache,
+    pub idempotency: IdempotencyStore,
+    pub inflight: InflightStreams,
+    /// Finished Responses API responses, for `GET /v1/responses/{id}`.
+    pub stored_responses: ResponseStore,
+    pub live_health: LiveHealth,
+    /// Updated by every health check, so counts carry across refreshes.
+    pub health_strikes: Mutex<HealthStrikes>,
+    /// Forwarded streams in flight, for `POST /cancel/{id}`.
+    pub streams: StreamRegistry,
+    pub metrics: Arc<Metrics>,
+    /// Set through `POST /admin/maintenance`; rejects generation requests.
+    pub maintenance: AtomicBool,
+    /// Wakes `/models/watch` long-polls once `models_version` is bumped.
+    pub models_changed: Notify,
     pub config: Config,
 }
 
 pub type SharedState = Arc<AppState>;
 
 impl AppState {
-    pub fn new(config: Config) -> SharedState {
-        Arc::new(Self {
+    pub fn new(config: Config) -> anyhow::Result<SharedState> {
+        Ok(Arc::new(Self {
             cache: RwLock::new(ModelCache {
+                all_models: Arc::new(Vec::new()),
                 free_models: Arc::new(Vec::new()),
                 stealth_models: Arc::new(Vec::new()),
+                image_models: Arc::new(Vec::new()),
+                router_models: Arc::new(Vec::new()),
+                custom_models: Vec::new(),
                 last_refreshed: Utc::now(),
+                last_refresh_error: None,
+                refresh_in_progress: false,
+                next_refresh: None,
+                consecutive_failures: 0,
+                history: VecDeque::new(),
+                health_failures: BTreeMap::new(),
+                models_version: 0,
             }),
-            client: Client::new(),
+            client: build_client(&config)?,
+            rate_limiter: RateLimiter::new(config.rate_limit_rpm),
+            user_rate_limiter: RateLimiter::new(config.rate_limit_user_rpm),
+            upstream_limiter: ConcurrencyLimiter::new(
+                config.max_concurrent_upstream,
+                config.upstream_queue_timeout(),
+            ),
+            response_cache: ResponseCache::new(
+                config.response_cache_size,
+                Duration::from_secs(config.response_cache_ttl_secs),
+            ),
+            idempotency: IdempotencyStore::new(Duration::from_secs(config.idempotency_ttl_secs)),
+            inflight: InflightStreams::new(config.coalesce_inflight),
+            stored_responses: ResponseStore::new(
+                config.response_store_size,
+                Duration::from_secs(config.response_store_ttl_secs),
+            ),
+            live_health: LiveHealth::default(),
+            health_strikes: Mutex::default(),
+            streams: StreamRegistry::default(),
+            metrics: Arc::default(),
+            maintenance: AtomicBool::new(false),
+            models_changed: Notify::new(),
             config,
-        })
+        }))
+    }
+
+    /// Whether a model is believed to work right now: its last health check
+    /// passed and live traffic isn't failing. Without health checks
+    /// configured, every model counts as available.
+    pub fn is_available(&self, tier: Tier, model: &Model) -> bool {
+        if tier.health_check_key(&self.config).is_none() {
+            return true;
+        }
+        model.last_success.is_some()
+            && model.last_success == model.last_checked
+            && self.live_health.is_available(&model.id)
     }
 
     pub async fn full_refresh(self: &Arc<Self>) {
         info!("Full model refresh (startup)");
+        let started = Instant::now();
+        self.begin_refresh().await;
 
-        let all = match Model::fetch_all(&self.client).await {
-            Ok(m) => m,
-            Err(e) => {
-                error!("Failed to fetch models: {e}");
-                return;
-            }
+        let Some(all) = self.fetch_models().await else {
+            self.record_failure("full", started).await;
+            return;
         };
 
-        let (mut free, mut stealth) = Model::classify(&all);
+        let listed = self.permitted(&all);
+        let (free, stealth) = Model::classify(&listed);
+        let images = Model::classify_images(&listed);
+        let routers = Model::classify_routers(&listed);
 
-        if let Some(ref key) = self.config.health_check_key {
-            let c = self.config.health_check_concurrency;
-            free = Model::health_check_batch(&self.client, key, free, c).await;
-            stealth = Model::health_check_batch(&self.client, key, stealth, c).await;
-        } else {
-            info!("No OPENROUTER_API_KEY set, skipping health checks");
+        let cache = self.cache.read().await;
+        let old_free = cache.free_models.clone();
+        let old_stealth = cache.stealth_models.clone();
+        let old_custom = cache.custom_models.clone();
+        drop(cache);
+
+        let mut failures = HealthFailures::new();
+        let free = self.health_check(Tier::Free, free, &old_free, &mut failures).await;
+        let stealth = self.health_check(Tier::Stealth, stealth, &old_stealth, &mut failures).await;
+        let mut custom = Vec::new();
+        for (i, members) in Model::classify_custom(&listed, &self.config.custom_tiers)
+            .into_iter()
+            .enumerate()
+        {
+            let old = old_custom.get(i).map_or(&[][..], |old| old.as_slice());
+            let members = self.health_check(Tier::Custom(i), members, old, &mut failures).await;
+            custom.push(Arc::new(members));
         }
 
         let mut cache = self.cache.write().await;
+        let custom_churn = custom.iter().enumerate().map(|(i, new)| {
+            tier_churn(cache.custom_models.get(i).map_or(&[], |old| old.as_slice()), new)
+        });
+        let (added, removed) = [(&cache.free_models, &free), (&cache.stealth_models, &stealth)]
+            .into_iter()
+            .map(|(old, new)| tier_churn(old, new))
+            .chain(custom_churn)
+            .fold((0, 0), |(a, r), (da, dr)| (a + da, r + dr));
+        let event = RefreshEvent {
+            at: Utc::now().to_rfc3339(),
+            kind: "full",
+            duration_ms: started.elapsed().as_millis() as u64,
+            free_models: free.len(),
+            stealth_models: stealth.len(),
+            custom_models: custom_counts(&self.config.custom_tiers, &custom),
+            added,
+            removed,
+            error: None,
+        };
+        cache.record(event, self.config.status_history_size);
+        cache.all_models = Arc::new(all);
         cache.free_models = Arc::new(free);
         cache.stealth_models = Arc::new(stealth);
+        cache.image_models = Arc::new(images);
+        cache.router_models = Arc::new(routers);
+        cache.custom_models = custom;
+        cache.health_failures = failures;
         cache.last_refreshed = Utc::now();
-        info!("Model cache updated");
+        cache.last_refresh_error = None;
+        cache.refresh_in_progress = false;
+        cache.consecutive_failures = 0;
+        self.publish(cache, added + removed > 0);
     }
 
     pub async fn diff_refresh(self: &Arc<Self>) {
         info!("Diff model refresh");
+        let started = Instant::now();
+        self.begin_refresh().await;
 
-        let all = match Model::fetch_all(&self.client).await {
-            Ok(m) => m,
-            Err(e) => {
-                error!("Failed to fetch models: {e}");
-                return;
-            }
+        let Some(all) = self.fetch_models().await else {
+            self.record_failure("diff", started).await;
+            return;
         };
 
-        let (fresh_free, fresh_stealth) = Model::classify(&all);
+        let listed = self.permitted(&all);
+        let (fresh_free, fresh_stealth) = Model::classify(&listed);
+        let images = Model::classify_images(&listed);
+        let routers = Model::classify_routers(&listed);
 
         let cache = self.cache.read().await;
         let old_free = cache.free_models.clone();
         let old_stealth = cache.stealth_models.clone();
+        let old_custom = cache.custom_models.clone();
         drop(cache);
 
-        let new_free = self.diff_tier("free", &old_free, fresh_free).await;
-        let new_stealth = self.diff_tier("stealth", &old_stealth, fresh_stealth).await;
+        let mut failures = HealthFailures::new();
+        let (new_free, free_changes) =
+            self.diff_tier(Tier::Free, &old_free, fresh_free, &mut failures).await;
+        let (new_stealth, stealth_changes) =
+            self.diff_tier(Tier::Stealth, &old_stealth, fresh_stealth, &mut failures).await;
+        let (mut custom, mut custom_changes) = (Vec::new(), BTreeMap::new());
+        for (i, fresh) in Model::classify_custom(&listed, &self.config.custom_tiers)
+            .into_iter()
+            .enumerate()
+        {
+            let old = old_custom.get(i).map_or(&[][..], |old| old.as_slice());
+            let tier = Tier::Custom(i);
+            let (members, changes) = self.diff_tier(tier, old, fresh, &mut failures).await;
+            custom.push(Arc::new(members));
+            custom_changes.insert(self.config.custom_tiers[i].name.clone(), changes);
+        }
 
         let mut cache = self.cache.write().await;
+        let all_changes =
+            || [&free_changes, &stealth_changes].into_iter().chain(custom_changes.values());
+        let added = all_changes().map(|c| c.added.len()).sum();
+        let removed = all_changes().map(|c| c.removed.len()).sum();
+        let event = RefreshEvent {
+            at: Utc::now().to_rfc3339(),
+            kind: "diff",
+            duration_ms: started.elapsed().as_millis() as u64,
+            free_models: new_free.len(),
+            stealth_models: new_stealth.len(),
+            custom_models: custom_counts(&self.config.custom_tiers, &custom),
+            added,
+            removed,
+            error: None,
+        };
+        cache.record(event, self.config.status_history_size);
+        cache.all_models = Arc::new(all);
         cache.free_models = Arc::new(new_free);
         cache.stealth_models = Arc::new(new_stealth);
+        cache.image_models = Arc::new(images);
+        cache.router_models = Arc::new(routers);
+        cache.custom_models = custom;
+        cache.health_failures = failures;
         cache.last_refreshed = Utc::now();
+        cache.last_refresh_error = None;
+        cache.refresh_in_progress = false;
+        cache.consecutive_failures = 0;
+        self.publish(cache, added + removed > 0);
+        self.notify_webhook(free_changes, stealth_changes, custom_changes);
+    }
+
+    /// Releases the updated cache and, if any tier's models `changed`, bumps
+    /// `models_version` and wakes the `/models/watch` long-polls.
+    fn publish(&self, mut cache: tokio::sync::RwLockWriteGuard<'_, ModelCache>, changed: bool) {
+        if changed {
+            cache.models_version += 1;
+        }
+        drop(cache);
         info!("Model cache updated");
+        if changed {
+            self.models_changed.notify_waiters();
+        }
+    }
+
+    /// Models that pass `MODEL_ALLOWLIST`/`MODEL_DENYLIST`, before any
+    /// classification; `all_models` keeps the full list for diagnostics.
+    fn permitted(&self, all: &[Model]) -> Vec<Model> {
+        let access = &self.config.model_access;
+        let listed: Vec<Model> = all.iter().filter(|m| access.permits(&m.id)).cloned().collect();
+        if listed.len() < all.len() {
+            info!("{} model(s) excluded by allow/deny lists", all.len() - listed.len());
+        }
+        listed
+    }
+
+    async fn record_failure(&self, kind: &'static str, started: Instant) {
+        let mut cache = self.cache.write().await;
+        let event = cache.failed_refresh(kind, started, &self.config.custom_tiers);
+        cache.record(event, self.config.status_history_size);
+    }
+
+    /// Marks a refresh as running for `/status`; the scheduler sets the next
+    /// ETA again once it's done.
+    async fn begin_refresh(&self) {
+        let mut cache = self.cache.write().await;
+        cache.refresh_in_progress = true;
+        cache.next_refresh = None;
+    }
+
+    /// Fetches the upstream model list, retrying briefly. On persistent failure
+    /// the cache is left untouched and the error is recorded for `/status`.
+    async fn fetch_models(&self) -> Option<Vec<Model>> {
+        let mut attempt = 1;
+        loop {
+            let timeout = self.config.model_fetch_timeout();
+            match Model::fetch_all(&self.client, &self.config.upstream_url, timeout).await {
+                Ok(m) => return Some(m),
+                Err(e) if attempt < FETCH_ATTEMPTS => {
+                    warn!("Failed to fetch models (attempt {attempt}/{FETCH_ATTEMPTS}): {e}");
+                    let delay = FETCH_RETRY_DELAY_SECS * u64::from(attempt);
+                    tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!("Failed to fetch models: {e}");
+                    let mut cache = self.cache.write().await;
+                    cache.last_refresh_error = Some((Utc::now(), e.to_string()));
+                    cache.refresh_in_progress = false;
+                    cache.consecutive_failures += 1;
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Health-checks a tier's `models` with its own key and returns those to
+    /// serve, in order. A model in `served` is kept until it fails
+    /// `HEALTH_FAIL_THRESHOLD` checks in a row; the ones dropped go into
+    /// `failures`. Passes everything through when health checks are off or no
+    /// key is configured.
+    async fn health_check(
+        &self,
+        tier: Tier,
+        models: Vec<Model>,
+        served: &[Model],
+        failures: &mut HealthFailures,
+    ) -> Vec<Model> {
+        let name = tier.name(&self.config);
+        let Some(key) = tier.health_check_key(&self.config) else {
+            info!("[{name}] Health checks disabled or no API key set, skipping them");
+            return models;
+        };
+        info!("[{name}] Health-checking {} models", models.len());
+        let order = models.clone();
+        let result = Model::health_check_batch(
+            &self.client,
+            &self.config.upstream_url,
+            key,
+            models,
+            tier.health_check_concurrency(&self.config),
+            self.config.health_check_timeout(),
+            self.config.health_check_delay(),
+        )
+        .await;
+
+        let threshold = self.config.health_fail_threshold;
+        let mut passed: HashMap<String, Model> =
+            result.healthy.into_iter().map(|m| (m.id.clone(), m)).collect();
+        let mut dropped = Vec::new();
+        let mut strikes = self.health_strikes.lock().unwrap();
+        let previous = strikes.remove(name).unwrap_or_default();
+        let mut counts = BTreeMap::new();
+        for failure in result.failed {
+            let count = previous.get(&failure.id).map_or(1, |n| n + 1);
+            counts.insert(failure.id.clone(), count);
+            let model = order.iter().find(|m| m.id == failure.id);
+            match model.filter(|m| count < threshold && served.iter().any(|s| s.id == m.id)) {
+                Some(model) => {
+                    let id = &model.id;
+                    warn!("[{name}] {id} failed {count}/{threshold} checks, still serving it");
+                    let mut model = model.clone();
+                    model.last_checked = Some(Utc::now());
+                    passed.insert(model.id.clone(), model);
+                }
+                None => dropped.push(failure),
+            }
+        }
+        if !counts.is_empty() {
+            strikes.insert(name.to_string(), counts);
+        }
+        drop(strikes);
+        if !dropped.is_empty() {
+            failures.insert(name.to_string(), dropped);
+        }
+        order.into_iter().filter_map(|m| passed.remove(&m.id)).collect()
     }
 
     async fn diff_tier(
         self: &Arc<Self>,
-        tier_name: &str,
+        tier: Tier,
         old: &[Model],
         fresh: Vec<Model>,
-    ) -> Vec<Model> {
+        failures: &mut HealthFailures,
+    ) -> (Vec<Model>, TierChanges) {
+        let tier_name = tier.name(&self.config);
         let old_ids: HashSet<&str> = old.iter().map(|m| m.id.as_str()).collect();
 
         let (added_count, removed_count, total) = {
@@ -114,41 +489,185 @@ impl AppState {
             (added, removed, fresh.len())
         };
 
-        let result = if let Some(ref key) = self.config.health_check_key {
-            info!("[{tier_name}] Health-checking {total} models");
-            Model::health_check_batch(
-                &self.client,
-                key,
-                fresh,
-                self.config.health_check_concurrency,
-            )
-            .await
-        } else {
-            fresh
-        };
+        let result = self.health_check(tier, fresh, old, failures).await;
 
         info!(
             "[{tier_name}] {}/{total} passed ({added_count} new, {removed_count} dropped upstream)",
             result.len()
         );
 
-        result
+        let new_ids: HashSet<&str> = result.iter().map(|m| m.id.as_str()).collect();
+        let mut changes = TierChanges {
+            added: new_ids.difference(&old_ids).map(|id| id.to_string()).collect(),
+            removed: old_ids.difference(&new_ids).map(|id| id.to_string()).collect(),
+        };
+        changes.added.sort();
+        changes.removed.sort();
+        (result, changes)
+    }
+
+    /// Best-effort POST of a refresh's tier changes to `WEBHOOK_URL`, off the
+    /// refresh path so a slow receiver can't delay the cache update. `custom`
+    /// is keyed by `CUSTOM_TIERS` name; tiers that didn't change are left out.
+    fn notify_webhook(
+        &self,
+        free: TierChanges,
+        stealth: TierChanges,
+        mut custom: BTreeMap<String, TierChanges>,
+    ) {
+        let Some(url) = self.config.webhook_url.clone() else {
+            return;
+        };
+        custom.retain(|_, changes| !changes.is_empty());
+        if free.is_empty() && stealth.is_empty() && custom.is_empty() {
+            return;
+        }
+        let mut payload = serde_json::json!({
+            "event": "models_changed",
+            "timestamp": Utc::now().to_rfc3339(),
+            "free": free,
+            "stealth": stealth,
+        });
+        if !custom.is_empty() {
+            payload["custom"] = serde_json::json!(custom);
+        }
+        let request = self
+            .client
+            .post(url)
+            .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+            .json(&payload);
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(resp) if resp.status().is_success() => {}
+                Ok(resp) => warn!("Webhook returned {}", resp.status()),
+                Err(e) => warn!("Webhook failed: {e}"),
+            }
+        });
     }
 
     pub fn spawn_scheduler(self: &Arc<Self>) {
         let state = self.clone();
         let interval = self.config.refresh_interval_secs;
+        let jitter = self.config.refresh_jitter_secs;
         tokio::spawn(async move {
+            let mut offset = 0;
+            if jitter > 0 {
+                offset = random_below(jitter + 1);
+                info!("Offsetting refresh schedule by {offset}s");
+            }
+
             loop {
+                let delay = std::mem::take(&mut offset) + interval + random_below(jitter + 1);
+                state.cache.write().await.next_refresh =
+                    Some(Utc::now() + chrono::Duration::seconds(delay as i64));
                 info!(
-                    "Next refresh in {}h {}m",
-                    interval / 3600,
-                    (interval % 3600) / 60
+                    "Next refresh in {}h {}m {}s",
+                    delay / 3600,
+                    (delay % 3600) / 60,
+                    delay % 60
                 );
-                tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+                tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
 
                 state.diff_refresh().await;
             }
         });
     }
+
+    /// Sends the `WARM_MODELS` a tiny request every `WARM_INTERVAL_SECS` to
+    /// keep provider connections and caches hot. Unlike health checks this
+    /// never moves models between tiers; outcomes only feed live health.
+    pub fn spawn_warmer(self: &Arc<Self>) {
+        if self.config.warm_models.is_empty() {
+            return;
+        }
+        let state = self.clone();
+        let interval = Duration::from_secs(self.config.warm_interval_secs);
+        tokio::spawn(async move {
+            loop {
+                state.warm().await;
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    async fn warm(&self) {
+        let tiers = {
+            let c = self.cache.read().await;
+            let custom = c.custom_models.iter().enumerate();
+            [(Tier::Free, c.free_models.clone()), (Tier::Stealth, c.stealth_models.clone())]
+                .into_iter()
+                .chain(custom.map(|(i, models)| (Tier::Custom(i), models.clone())))
+                .collect::<Vec<_>>()
+        };
+        let timeout = Duration::from_secs(self.config.health_check_timeout_secs);
+        // A model in several tiers that share a key is only warmed once.
+        let mut warmed = HashSet::new();
+        for (tier, models) in tiers {
+            let Some(key) = tier.api_key(&self.config) else {
+                continue;
+            };
+            let warm = models.iter().filter(|m| {
+                self.config.warm_models.iter().any(|p| glob_match(p, &m.id))
+                    && warmed.insert((key, m.id.clone()))
+            });
+            for model in warm {
+                let base = &self.config.upstream_url;
+                let result = model.ping(&self.client, base, key, timeout).await;
+                self.live_health.observe(&model.id, result.status);
+            }
+        }
+    }
+}
+
+/// Model count per `CUSTOM_TIERS` name, for `/status` and its history.
+pub fn custom_counts(tiers: &[CustomTier], members: &[Arc<Vec<Model>>]) -> BTreeMap<String, usize> {
+    tiers.iter().zip(members).map(|(t, models)| (t.name.clone(), models.len())).collect()
+}
+
+/// How many ids of `new` aren't in `old`, and of `old` aren't in `new`.
+fn tier_churn(old: &[Model], new: &[Model]) -> (usize, usize) {
+    let old_ids: HashSet<&str> = old.iter().map(|m| m.id.as_str()).collect();
+    let new_ids: HashSet<&str> = new.iter().map(|m| m.id.as_str()).collect();
+    (new_ids.difference(&old_ids).count(), old_ids.difference(&new_ids).count())
+}
+
+/// Upstream HTTP client. HTTPS upstreams may not be downgraded to plain HTTP,
+/// and TLS uses a minimum version plus optional extra trusted roots (e.g. for
+/// a TLS-inspecting egress proxy).
+/// reqwest never speaks HTTP/1.0, so there's nothing to disable there.
+fn build_client(config: &Config) -> anyhow::Result<Client> {
+    let min_tls = match config.upstream_min_tls.as_str() {
+        "1.3" => reqwest::tls::Version::TLS_1_3,
+        _ => reqwest::tls::Version::TLS_1_2,
+    };
+    let https = config.upstream_url.starts_with("https://");
+    let mut builder = Client::builder().https_only(https).min_tls_version(min_tls);
+    if let Some(ref path) = config.upstream_ca_bundle {
+        let pem = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("cannot read UPSTREAM_CA_BUNDLE {path}: {e}"))?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| anyhow::anyhow!("invalid UPSTREAM_CA_BUNDLE {path}: {e}"))?;
+        if certs.is_empty() {
+            anyhow::bail!("UPSTREAM_CA_BUNDLE {path} contains no certificates");
+        }
+        info!("Trusting {} extra root certificate(s) from {path}", certs.len());
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Cheap per-process randomness (`RandomState` is seeded randomly), good enough
+/// to de-synchronize refreshes across instances.
+fn random_below(bound: u64) -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+    h.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    h.finish() % bound.max(1)
 }
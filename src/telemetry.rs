@@ -0,0 +1,37 @@
+use crate::config::Config;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+pub type Layer<S> = OpenTelemetryLayer<S, Tracer>;
+
+/// A tracing layer exporting spans over OTLP/HTTP to `otel_endpoint`, with
+/// the provider to shut down on exit, or `None` when no endpoint is set. The
+/// exporter also honors the standard `OTEL_EXPORTER_OTLP_*` header and
+/// timeout variables.
+pub fn layer<S>(config: &Config) -> Result<Option<(Layer<S>, SdkTracerProvider)>, String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(ref endpoint) = config.otel_endpoint else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+        .map_err(|e| format!("failed to set up OTLP exporter: {e}"))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder().with_service_name(config.otel_service_name.clone()).build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    Ok(Some((tracing_opentelemetry::layer().with_tracer(tracer), provider)))
+}
@@ -0,0 +1,84 @@
+use crate::model::Model;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Chat-format overhead per message (role, separators) and for priming the reply,
+/// as documented for OpenAI chat models.
+const TOKENS_PER_MESSAGE: usize = 4;
+const TOKENS_REPLY_PRIMING: usize = 3;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    O200kBase,
+    Cl100kBase,
+    Heuristic,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Estimate {
+    pub tokens: usize,
+    pub method: Method,
+}
+
+impl Method {
+    /// GPT models get their own encoding; other BPE families are approximated
+    /// with cl100k, and anything unknown falls back to a character heuristic.
+    pub fn for_model(model: &Model) -> Self {
+        let tokenizer = model
+            .architecture
+            .as_ref()
+            .and_then(|a| a.tokenizer.as_deref())
+            .unwrap_or("Other");
+        match tokenizer {
+            "GPT" => Self::O200kBase,
+            "Other" | "Router" => Self::Heuristic,
+            _ => Self::Cl100kBase,
+        }
+    }
+
+    fn count(self, text: &str) -> usize {
+        match self {
+            Self::O200kBase => tiktoken_rs::o200k_base_singleton()
+                .lock()
+                .encode_with_special_tokens(text)
+                .len(),
+            Self::Cl100kBase => tiktoken_rs::cl100k_base_singleton()
+                .lock()
+                .encode_with_special_tokens(text)
+                .len(),
+            Self::Heuristic => text.chars().count().div_ceil(4),
+        }
+    }
+}
+
+pub fn count_text(model: &Model, text: &str) -> Estimate {
+    let method = Method::for_model(model);
+    Estimate {
+        tokens: method.count(text),
+        method,
+    }
+}
+
+/// Estimates the prompt size of a chat-completions `messages` array. Only text
+/// content is counted; images and audio are ignored.
+pub fn count_messages(model: &Model, messages: &[Value]) -> Estimate {
+    let method = Method::for_model(model);
+    let mut tokens = TOKENS_REPLY_PRIMING;
+    for msg in messages {
+        tokens += TOKENS_PER_MESSAGE;
+        if let Some(name) = msg.get("name").and_then(|v| v.as_str()) {
+            tokens += method.count(name);
+        }
+        match msg.get("content") {
+            Some(Value::String(s)) => tokens += method.count(s),
+            Some(Value::Array(parts)) => {
+                for text in parts.iter().filter_map(|p| p.get("text")?.as_str()) {
+                    tokens += method.count(text);
+                }
+            }
+            _ => {}
+        }
+    }
+    Estimate { tokens, method }
+}